@@ -0,0 +1,86 @@
+// Ingestion throughput on a synthetic stream of all firmware channels.
+//
+//     cargo bench --bench ingest
+
+use std::hint::black_box;
+use std::io::Write;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use visualizer::channels;
+use visualizer::framing::{self, Frame, Framer};
+use visualizer::parser::Parser;
+use visualizer::pipeline::{self, Pipeline};
+
+// Serial reads return at most this many bytes, as in the listener
+const READ_SIZE: usize = 256;
+
+/// `cycles` rounds of every channel, with values shaped like real telemetry
+fn synthetic_stream(cycles: usize) -> (Vec<u8>, u64) {
+    let mut out = Vec::new();
+    for i in 0..cycles {
+        let t = i as f64 * 1e-3;
+        for (k, channel) in channels::CHANNELS.iter().enumerate() {
+            let value = match channel.header {
+                "bus_voltage" => 24.0 - t * 0.01,
+                "bus_current" => 1.5 + (t * 3.0).sin(),
+                "enc_pos_0" | "enc_pos_1" => t * 2.0,
+                _ => (t * (k + 1) as f64).sin() * 10.0,
+            };
+            writeln!(out, "{}:{:.4}", channel.header, value).unwrap();
+        }
+    }
+    (out, (cycles * channels::CHANNELS.len()) as u64)
+}
+
+fn framing_and_parsing(c: &mut Criterion) {
+    let (stream, lines) = synthetic_stream(1000);
+    let mut group = c.benchmark_group("framing_and_parsing");
+    group.throughput(Throughput::Elements(lines));
+    group.bench_function("synthetic", |b| {
+        let mut framer = Framer::new(b"\n".to_vec(), framing::DEFAULT_MAX_LINE_LEN);
+        b.iter(|| {
+            let mut sum = 0.0;
+            for chunk in stream.chunks(READ_SIZE) {
+                framer.push(chunk);
+                while let Some(Frame::Line(line)) = framer.next_frame() {
+                    let (header, data) = Parser::split_line(line).unwrap();
+                    if channels::lookup(header).is_some() {
+                        sum += Parser::parse_float(data).unwrap();
+                    }
+                }
+            }
+            black_box(sum)
+        })
+    });
+    group.finish();
+}
+
+fn pipeline(c: &mut Criterion) {
+    let (stream, lines) = synthetic_stream(1000);
+    let (rec, storage) = pipeline::recording_builder().memory().unwrap();
+    let mut pipeline = Pipeline::new(rec, None);
+    let mut framer = Framer::new(b"\n".to_vec(), framing::DEFAULT_MAX_LINE_LEN);
+
+    let mut group = c.benchmark_group("pipeline");
+    group.throughput(Throughput::Elements(lines));
+    group.sample_size(20);
+    group.bench_function("synthetic", |b| {
+        let mut t = 0.0;
+        b.iter(|| {
+            for chunk in stream.chunks(READ_SIZE) {
+                t += 1e-3;
+                framer.push(chunk);
+                while let Some(Frame::Line(line)) = framer.next_frame() {
+                    pipeline.line(t, line);
+                }
+            }
+            // Include the batcher's work, and keep memory bounded
+            black_box(storage.take().len())
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, framing_and_parsing, pipeline);
+criterion_main!(benches);
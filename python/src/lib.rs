@@ -0,0 +1,203 @@
+//! Python bindings for scripting experiments against a live device.
+//!
+//! ```text
+//! import mission_control as mc
+//!
+//! with mc.Session.connect("/dev/ttyACM0", record="run.rrd") as s:
+//!     s.subscribe("bus_voltage")
+//!     s.subscribe("enc_vel_0", lambda t, v: print(t, v))
+//!     s.send("velo_ctrl")
+//!     s.setpoint(1.5)
+//!     time.sleep(2)
+//!     t, v = s.read("bus_voltage")  # numpy arrays of what arrived since the last read
+//! ```
+//!
+//! A session runs the same listener as the command window, see `visualizer::session`.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+
+use crossbeam_channel::{Receiver, Sender};
+use numpy::PyArray1;
+use pyo3::exceptions::{PyIOError, PyKeyError, PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+
+use visualizer::channels;
+use visualizer::commands::Command;
+use visualizer::framing;
+use visualizer::pipeline::{self, ListenerMsg, Pipeline};
+use visualizer::session::{self, SessionConfig};
+
+// Samples kept per subscription between reads; newer ones are dropped once it's full
+const SUBSCRIPTION_CAPACITY: usize = 100_000;
+const CHANNEL_CAPACITY: usize = 10;
+
+// Sample times and values
+type Samples<'py> = (Bound<'py, PyArray1<f64>>, Bound<'py, PyArray1<f64>>);
+
+fn open_recording(path: PathBuf) -> PyResult<rerun::RecordingStream> {
+    pipeline::recording_builder()
+        .save(path)
+        .map_err(|e| PyIOError::new_err(e.to_string()))
+}
+
+/// A connection to the device's serial port.
+#[pyclass(module = "mission_control")]
+struct Session {
+    cmds_s: Sender<String>,
+    listener_msgs_s: Sender<ListenerMsg>,
+    subscriptions: Mutex<HashMap<String, Receiver<(f64, f64)>>>,
+    listener: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Session {
+    fn control(&self, msg: ListenerMsg) -> PyResult<()> {
+        self.listener_msgs_s.send(msg).map_err(|_| PyRuntimeError::new_err("session is disconnected"))
+    }
+
+    fn dispatch(&self, command: Command) -> PyResult<()> {
+        self.cmds_s.send(command.encode()).map_err(|_| PyRuntimeError::new_err("session is disconnected"))
+    }
+}
+
+#[pymethods]
+impl Session {
+    /// Open `port`, or the first serial port found, and start listening. Everything
+    /// received is logged to the .rrd file `record` if given.
+    #[staticmethod]
+    #[pyo3(signature = (port=None, baud=115_200, record=None, delimiter="lf", max_line_len=framing::DEFAULT_MAX_LINE_LEN))]
+    fn connect(port: Option<&str>, baud: u32, record: Option<PathBuf>, delimiter: &str, max_line_len: usize) -> PyResult<Self> {
+        let delimiter = framing::parse_delimiter(delimiter).map_err(PyValueError::new_err)?;
+        let rec = match &record {
+            Some(path) => open_recording(path.clone())?,
+            None => rerun::RecordingStream::disabled(),
+        };
+        let port = session::open_port(port, baud).map_err(|e| PyIOError::new_err(e.to_string()))?;
+
+        let (cmds_s, cmds_r) = crossbeam_channel::bounded::<String>(CHANNEL_CAPACITY);
+        let (listener_msgs_s, listener_msgs_r) = crossbeam_channel::bounded::<ListenerMsg>(CHANNEL_CAPACITY);
+        let config = SessionConfig {
+            delimiter: delimiter.0,
+            max_line_len,
+            ..Default::default()
+        };
+        let listener = thread::spawn(move || {
+            let mut pipeline = Pipeline::new(rec, None);
+            pipeline.set_recording_path(record);
+            if let Err(e) = session::run(port, &config, pipeline, cmds_r, listener_msgs_r) {
+                println!("Serial listener stopped: {}", e);
+            }
+        });
+
+        Ok(Session {
+            cmds_s,
+            listener_msgs_s,
+            subscriptions: Mutex::new(HashMap::new()),
+            listener: Mutex::new(Some(listener)),
+        })
+    }
+
+    /// Flush the recording and close the port.
+    fn disconnect(&self, py: Python<'_>) {
+        let _ = self.listener_msgs_s.send(ListenerMsg::Shutdown);
+        if let Some(listener) = self.listener.lock().unwrap().take() {
+            py.detach(|| {
+                let _ = listener.join();
+            });
+        }
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    #[pyo3(signature = (*_args))]
+    fn __exit__(&self, py: Python<'_>, _args: &Bound<'_, pyo3::types::PyTuple>) {
+        self.disconnect(py);
+    }
+
+    /// Send a command as the command window would, e.g. `posn_ctrl` or `sp:1.5`.
+    fn send(&self, command: &str) -> PyResult<()> {
+        let command = Command::decode(command).ok_or_else(|| PyValueError::new_err(format!("unknown command '{}'", command)))?;
+        self.dispatch(command)
+    }
+
+    /// Setpoint of the active controller.
+    fn setpoint(&self, value: f32) -> PyResult<()> {
+        self.dispatch(Command::Setpoint(value))
+    }
+
+    /// Receive the `(t, value)` samples of a channel or derived channel. Without a callback
+    /// they are buffered until `read`; a callback is called from a background thread.
+    #[pyo3(signature = (channel, callback=None))]
+    fn subscribe(&self, channel: String, callback: Option<Py<PyAny>>) -> PyResult<()> {
+        let (samples_s, samples_r) = crossbeam_channel::bounded(SUBSCRIPTION_CAPACITY);
+        self.control(ListenerMsg::Subscribe(channel.clone(), samples_s))?;
+        match callback {
+            // Stops once unsubscribed or disconnected
+            Some(callback) => {
+                thread::spawn(move || {
+                    for (t, v) in samples_r {
+                        Python::attach(|py| {
+                            if let Err(e) = callback.call1(py, (t, v)) {
+                                e.print(py);
+                            }
+                        });
+                    }
+                });
+            }
+            None => {
+                self.subscriptions.lock().unwrap().insert(channel, samples_r);
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop receiving a channel's samples, including its callbacks.
+    fn unsubscribe(&self, channel: String) -> PyResult<()> {
+        self.subscriptions.lock().unwrap().remove(&channel);
+        self.control(ListenerMsg::Unsubscribe(channel))
+    }
+
+    /// Times and values received on a subscribed channel since the last read.
+    fn read<'py>(&self, py: Python<'py>, channel: &str) -> PyResult<Samples<'py>> {
+        let subscriptions = self.subscriptions.lock().unwrap();
+        let samples_r = subscriptions
+            .get(channel)
+            .ok_or_else(|| PyKeyError::new_err(format!("not subscribed to '{}'", channel)))?;
+        let (t, v): (Vec<f64>, Vec<f64>) = samples_r.try_iter().unzip();
+        Ok((PyArray1::from_vec(py, t), PyArray1::from_vec(py, v)))
+    }
+
+    /// Log everything received from now on to the .rrd file `path` too, alongside the
+    /// recording given to `connect`. Replaces any recording started this way.
+    fn start_recording(&self, path: PathBuf) -> PyResult<()> {
+        self.control(ListenerMsg::Record(Some((open_recording(path.clone())?, path))))
+    }
+
+    /// Flush and close the recording started with `start_recording`.
+    fn stop_recording(&self) -> PyResult<()> {
+        self.control(ListenerMsg::Record(None))
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let _ = self.listener_msgs_s.try_send(ListenerMsg::Shutdown);
+    }
+}
+
+/// Headers of the channels the firmware streams.
+#[pyfunction]
+fn channel_names() -> Vec<&'static str> {
+    channels::CHANNELS.iter().map(|c| c.header).collect()
+}
+
+#[pymodule]
+fn mission_control(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Session>()?;
+    m.add_function(wrap_pyfunction!(channel_names, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,359 @@
+//! HTTP control API for automation, e.g. from CI rigs. Everything goes through the same
+//! channels as the command window, which reflects commands and recordings started here.
+//!
+//! ```text
+//! GET  /status                 connection, recording, parse and queue statistics
+//! GET  /channels               latest sample of every channel
+//! GET  /channels/<name>        latest sample of a channel or derived channel
+//! GET  /commands               accepted commands
+//! POST /commands               send the command in the body, e.g. `posn_ctrl` or `sp:1.5`
+//! POST /recording/start        also record to the .rrd file named in the body
+//! POST /recording/stop
+//! GET  /metrics                latest channel values and pipeline counters, for Prometheus
+//! ```
+//!
+//! Responses are JSON, except for the Prometheus text format of `/metrics`; errors are
+//! `{"error": "..."}` with a 4xx/5xx status.
+
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::Sender;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::channels;
+use crate::commands::{Command, CommandSender};
+use crate::pipeline::{self, LatestValues, ListenerMsg, PipelineStatus};
+
+// How long the listener gets to answer a status request before it counts as disconnected
+const STATUS_TIMEOUT: Duration = Duration::from_secs(1);
+// Upper bound between updates of the latest values
+const POLL_PERIOD: Duration = Duration::from_millis(50);
+// The firmware's attitude, published by the pipeline alongside the registered channels
+const QUATERNION_CHANNELS: [&str; 4] = ["quaternion_w", "quaternion_x", "quaternion_y", "quaternion_z"];
+
+struct Api {
+    cmds_s: CommandSender,
+    listener_msgs_s: Sender<ListenerMsg>,
+    latest: LatestValues,
+    start_time: Instant,
+}
+
+type Reply = Result<Value, (u16, String)>;
+
+fn bad_request(msg: impl ToString) -> (u16, String) {
+    (400, msg.to_string())
+}
+
+fn unavailable(msg: impl ToString) -> (u16, String) {
+    (503, msg.to_string())
+}
+
+// Prometheus spells the non-finite values differently from Rust
+fn prometheus_value(v: f64) -> String {
+    match v {
+        v if v.is_nan() => "NaN".to_string(),
+        f64::INFINITY => "+Inf".to_string(),
+        f64::NEG_INFINITY => "-Inf".to_string(),
+        v => v.to_string(),
+    }
+}
+
+fn sample_json(name: &str, sample: Option<(f64, f64)>) -> Value {
+    match sample {
+        Some((t, value)) => json!({ "name": name, "t": t, "value": value }),
+        None => json!({ "name": name, "t": null, "value": null }),
+    }
+}
+
+impl Api {
+    fn handle(&mut self, method: &Method, path: &str, body: &str) -> Reply {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (method, segments.as_slice()) {
+            (Method::Get, ["status"]) => Ok(self.status()),
+            (Method::Get, ["channels"]) => {
+                let mut channels: Vec<Value> = self.latest.iter().map(|(name, sample)| sample_json(name, sample)).collect();
+                channels.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+                Ok(Value::Array(channels))
+            }
+            (Method::Get, ["channels", name]) => {
+                // Derived channels and binary frame fields start being tracked on their first request
+                if !self.latest.contains(name) {
+                    let status = self.pipeline_status().ok_or_else(|| unavailable("serial listener stopped"))?;
+                    let known = status.derived_channels.iter().any(|d| d == name)
+                        || QUATERNION_CHANNELS.contains(name)
+                        || status.parse_stats.channels.contains_key(*name);
+                    if !known {
+                        return Err((404, format!("no channel '{}'", name)));
+                    }
+                    self.latest.subscribe(name).map_err(unavailable)?;
+                }
+                Ok(sample_json(name, self.latest.get(name)))
+            }
+            (Method::Get, ["commands"]) => {
+                let mut commands: Vec<String> = Command::NAMED.iter().map(Command::encode).collect();
+                commands.push("sp:<value>".to_string());
+                Ok(json!(commands))
+            }
+            (Method::Post, ["commands"]) => {
+                let command = Command::decode(body).ok_or_else(|| bad_request(format!("unknown command '{}'", body.trim())))?;
+                self.cmds_s.send(command.encode()).map_err(|_| unavailable("serial listener stopped"))?;
+                Ok(json!({ "sent": command.encode() }))
+            }
+            (Method::Post, ["recording", "start"]) => {
+                let path = PathBuf::from(body.trim());
+                if body.trim().is_empty() {
+                    return Err(bad_request("expected the .rrd file to record to"));
+                }
+                let rec = pipeline::recording_builder().save(&path).map_err(|e| (500, e.to_string()))?;
+                self.control(ListenerMsg::Record(Some((rec, path.clone()))))?;
+                Ok(json!({ "recording": path }))
+            }
+            (Method::Post, ["recording", "stop"]) => {
+                self.control(ListenerMsg::Record(None))?;
+                Ok(json!({ "recording": null }))
+            }
+            _ => Err((404, format!("no endpoint {} {}", method, path))),
+        }
+    }
+
+    fn control(&self, msg: ListenerMsg) -> Result<(), (u16, String)> {
+        self.listener_msgs_s.send(msg).map_err(|_| unavailable("serial listener stopped"))
+    }
+
+    // None once the listener stopped or stalled
+    fn pipeline_status(&self) -> Option<PipelineStatus> {
+        let (reply_s, reply_r) = crossbeam_channel::bounded(1);
+        self.listener_msgs_s
+            .send_timeout(ListenerMsg::Status(reply_s), STATUS_TIMEOUT)
+            .ok()
+            .and_then(|_| reply_r.recv_timeout(STATUS_TIMEOUT).ok())
+    }
+
+    fn status(&self) -> Value {
+        let uptime = self.start_time.elapsed().as_secs_f64();
+        match self.pipeline_status() {
+            Some(status) => {
+                let channels: u64 = status.parse_stats.channels.values().map(|c| c.ok).sum();
+                json!({
+                    "connected": true,
+                    "uptime": uptime,
+                    "recording": status.recording,
+                    "lines": channels,
+                    "parse_errors": status.parse_stats.errors(),
+                    "read_queue": status.queue_stats.read_depth,
+                    "dropped_bytes": status.queue_stats.dropped_bytes,
+                })
+            }
+            None => json!({ "connected": false, "uptime": uptime }),
+        }
+    }
+
+    // Prometheus text exposition format
+    fn metrics(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, f64)]| {
+            let _ = writeln!(out, "# HELP mission_control_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE mission_control_{} {}", name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "mission_control_{}{} {}", name, labels, prometheus_value(*value));
+            }
+        };
+
+        let mut values: Vec<(String, f64)> = self
+            .latest
+            .iter()
+            .filter_map(|(name, sample)| sample.map(|(_, v)| (format!("{{channel=\"{}\"}}", name), v)))
+            .collect();
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        metric("channel_value", "gauge", "Latest value of a channel.", &values);
+        metric("uptime_seconds", "gauge", "Seconds since the API started.", &[(String::new(), self.start_time.elapsed().as_secs_f64())]);
+
+        let status = self.pipeline_status();
+        metric("connected", "gauge", "Whether the serial listener is running.", &[(String::new(), if status.is_some() { 1.0 } else { 0.0 })]);
+        let counter = |v: u64| [(String::new(), v as f64)];
+        metric("command_queue_drops_total", "counter", "Commands dropped because the command queue was full.", &counter(self.cmds_s.dropped()));
+        // Counters are left out while there's nothing to read them from
+        let Some(status) = status else {
+            return out;
+        };
+        let (parse, queue) = (&status.parse_stats, &status.queue_stats);
+        metric("read_bytes_total", "counter", "Bytes read from the serial port.", &counter(queue.read_bytes));
+        metric("dropped_bytes_total", "counter", "Bytes read but dropped by the read queue.", &counter(queue.dropped_bytes));
+        metric("parsed_lines_total", "counter", "Lines parsed into a channel value.", &counter(parse.channels.values().map(|c| c.ok).sum()));
+        metric("parse_errors_total", "counter", "Lines that failed to parse, for any reason.", &counter(parse.errors()));
+        metric("unknown_headers_total", "counter", "Lines with a header that isn't a known channel.", &counter(parse.unknown_header));
+        metric("dropped_commands_total", "counter", "Commands that failed to be written to the port.", &counter(queue.dropped_commands));
+        metric("reconnects_total", "counter", "Times the serial port was reopened after failing.", &counter(queue.reconnects));
+        metric("read_queue_depth", "gauge", "Chunks waiting to be processed.", &[(String::new(), queue.read_depth as f64)]);
+        metric("command_queue_depth", "gauge", "Commands waiting to be written.", &[(String::new(), queue.command_depth as f64)]);
+        out
+    }
+
+    fn respond(&mut self, mut request: Request) {
+        let mut body = String::new();
+        let path = request.url().split('?').next().unwrap_or("").to_string();
+        if request.method() == &Method::Get && path.trim_matches('/') == "metrics" {
+            let content_type = Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap();
+            let _ = request.respond(Response::from_string(self.metrics()).with_header(content_type));
+            return;
+        }
+        let reply = match request.as_reader().read_to_string(&mut body) {
+            Ok(_) => self.handle(request.method(), &path, &body),
+            Err(_) => Err(bad_request("body is not UTF-8")),
+        };
+        let (status, value) = match reply {
+            Ok(value) => (200, value),
+            Err((status, error)) => (status, json!({ "error": error })),
+        };
+        let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+        let response = Response::from_string(value.to_string()).with_status_code(status).with_header(content_type);
+        let _ = request.respond(response);
+    }
+}
+
+/// Serve the API on `addr`. Commands go to `cmds_s` and everything else through
+/// `listener_msgs_s`, as from the command window.
+pub fn serve(addr: SocketAddr, cmds_s: CommandSender, listener_msgs_s: Sender<ListenerMsg>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let server = Server::http(addr)?;
+    println!("HTTP API listening on http://{}", addr);
+
+    let mut api = Api {
+        cmds_s,
+        latest: LatestValues::new(listener_msgs_s.clone()),
+        listener_msgs_s,
+        start_time: Instant::now(),
+    };
+    // Keeps answering once the listener stopped, with the status saying so
+    for channel in channels::CHANNELS {
+        let _ = api.latest.subscribe(channel.header);
+    }
+
+    loop {
+        let request = server.recv_timeout(POLL_PERIOD)?;
+        let _ = api.latest.update();
+        if let Some(request) = request {
+            api.respond(request);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crossbeam_channel::Receiver;
+
+    use super::*;
+    use crate::parser::ParseStats;
+    use crate::pipeline::Subscription;
+    use crate::serial::QueueStats;
+
+    // Stands in for the pipeline: answers status requests and hands subscriptions to the
+    // test, until a shutdown
+    fn listener(derived_channels: Vec<String>) -> (Sender<ListenerMsg>, Receiver<(String, Subscription)>, thread::JoinHandle<()>) {
+        let (listener_msgs_s, listener_msgs_r) = crossbeam_channel::unbounded();
+        let (subscriptions_s, subscriptions_r) = crossbeam_channel::unbounded();
+        let listener = thread::spawn(move || {
+            for msg in listener_msgs_r {
+                match msg {
+                    ListenerMsg::Subscribe(name, samples_s) => {
+                        let _ = subscriptions_s.send((name, samples_s));
+                    }
+                    ListenerMsg::Status(reply_s) => {
+                        let _ = reply_s.send(PipelineStatus {
+                            parse_stats: ParseStats::default(),
+                            queue_stats: QueueStats::default(),
+                            recording: None,
+                            derived_channels: derived_channels.clone(),
+                        });
+                    }
+                    ListenerMsg::Shutdown => return,
+                    _ => {}
+                }
+            }
+        });
+        (listener_msgs_s, subscriptions_r, listener)
+    }
+
+    fn api(listener_msgs_s: Sender<ListenerMsg>) -> (Api, Receiver<String>) {
+        let (cmds_s, cmds_r) = crossbeam_channel::unbounded();
+        let mut api = Api {
+            cmds_s: CommandSender::new(cmds_s),
+            latest: LatestValues::new(listener_msgs_s.clone()),
+            listener_msgs_s,
+            start_time: Instant::now(),
+        };
+        api.latest.subscribe("bus_voltage").unwrap();
+        (api, cmds_r)
+    }
+
+    #[test]
+    fn routes_and_status_codes() {
+        let (listener_msgs_s, subscriptions_r, listener) = listener(vec!["power".to_string()]);
+        let (mut api, cmds_r) = api(listener_msgs_s.clone());
+        let status = |reply: Reply| reply.err().map_or(200, |(status, _)| status);
+
+        assert_eq!(api.handle(&Method::Get, "/channels/bus_voltage", "").unwrap(), json!({ "name": "bus_voltage", "t": null, "value": null }));
+        assert_eq!(status(api.handle(&Method::Get, "/channels/power", "")), 200);
+        assert_eq!(status(api.handle(&Method::Get, "/channels/quaternion_w", "")), 200);
+        // Typos aren't subscribed to
+        assert_eq!(status(api.handle(&Method::Get, "/channels/bus_votlage", "")), 404);
+        let subscribed: Vec<String> = subscriptions_r.iter().take(3).map(|(name, _)| name).collect();
+        assert_eq!(subscribed, ["bus_voltage", "power", "quaternion_w"]);
+        assert_eq!(api.handle(&Method::Get, "/channels", "").unwrap().as_array().unwrap().len(), 3);
+
+        assert_eq!(status(api.handle(&Method::Get, "/nowhere", "")), 404);
+        assert_eq!(status(api.handle(&Method::Post, "/channels", "")), 404);
+        assert_eq!(status(api.handle(&Method::Post, "/commands", "bogus")), 400);
+        assert_eq!(api.handle(&Method::Post, "/commands", "velo_ctrl").unwrap(), json!({ "sent": "velo_ctrl" }));
+        assert_eq!(cmds_r.try_recv().as_deref(), Ok("velo_ctrl"));
+        assert_eq!(status(api.handle(&Method::Post, "/recording/start", " ")), 400);
+        assert_eq!(api.handle(&Method::Get, "/status", "").unwrap()["connected"], true);
+
+        listener_msgs_s.send(ListenerMsg::Shutdown).unwrap();
+        listener.join().unwrap();
+        assert_eq!(status(api.handle(&Method::Post, "/recording/stop", "")), 503);
+        assert_eq!(status(api.handle(&Method::Get, "/channels/power_w", "")), 503);
+        assert_eq!(api.handle(&Method::Get, "/status", "").unwrap()["connected"], false);
+    }
+
+    #[test]
+    fn metrics_text_format() {
+        let (listener_msgs_s, subscriptions_r, listener) = listener(Vec::new());
+        let (mut api, _cmds_r) = api(listener_msgs_s.clone());
+        api.latest.subscribe("theta").unwrap();
+        // Kept, a dropped subscription is a stopped listener
+        let subscriptions: Vec<(String, Subscription)> = subscriptions_r.iter().take(2).collect();
+        for ((_, samples_s), value) in subscriptions.iter().zip([24.1, f64::NAN]) {
+            samples_s.send((1.0, value)).unwrap();
+        }
+        api.latest.update().unwrap();
+
+        let metrics = api.metrics();
+        let lines: Vec<&str> = metrics.lines().collect();
+        assert_eq!(
+            lines[..4],
+            [
+                "# HELP mission_control_channel_value Latest value of a channel.",
+                "# TYPE mission_control_channel_value gauge",
+                "mission_control_channel_value{channel=\"bus_voltage\"} 24.1",
+                "mission_control_channel_value{channel=\"theta\"} NaN",
+            ]
+        );
+        assert!(lines.contains(&"mission_control_connected 1"));
+        assert!(lines.contains(&"# TYPE mission_control_parse_errors_total counter"));
+        assert!(lines.contains(&"mission_control_parse_errors_total 0"));
+        assert_eq!(prometheus_value(f64::NEG_INFINITY), "-Inf");
+
+        // Pipeline counters are left out once the listener stopped
+        listener_msgs_s.send(ListenerMsg::Shutdown).unwrap();
+        listener.join().unwrap();
+        let metrics = api.metrics();
+        assert!(metrics.contains("mission_control_connected 0\n"));
+        assert!(!metrics.contains("parse_errors_total"));
+    }
+}
@@ -0,0 +1,639 @@
+//! Binary telemetry: packed C structs sent straight from the firmware, described by a
+//! descriptor file that both the listener and the firmware's C header are generated from.
+//!
+//! A descriptor lists frames and their fields in wire order, one per line:
+//!
+//! ```text
+//! frame imu 1                       # name, id 0-255
+//! acc_x   i16  scale=0.001 units=g
+//! gyr_x   i16  scale=0.01  units=deg/s
+//! temp    u16  endian=big  scale=0.01 offset=-40 units=degC
+//!
+//! frame power 2 endian=big          # default endianness of its fields
+//! bus_voltage f32 units=V
+//! ```
+//!
+//! Types are u8, i8, u16, i16, u32, i32, u64, i64, f32 and f64, little-endian unless
+//! given, and values are `raw * scale + offset`. Fields named after a registered channel,
+//! such as `acc_x`, are processed like the firmware's text lines; others are logged under
+//! `<frame>/<field>`. On the wire, every frame is
+//!
+//! ```text
+//! 0xA5 0x5A  id  len  payload (len bytes)  CRC-16/CCITT-FALSE of id..payload, little-endian
+//! ```
+//!
+//! `visualizer c-header` writes the structs and a `mc_frame()` helper building this.
+
+use std::collections::HashSet;
+use std::fmt::{self, Write as _};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crossbeam_channel::Receiver;
+
+use crate::capture::CaptureWriter;
+use crate::channels;
+use crate::pipeline::{ListenerMsg, Pipeline};
+use crate::serial::{OverflowPolicy, SerialLink};
+
+pub const SYNC: [u8; 2] = [0xA5, 0x5A];
+// Sync, id, len and checksum
+const OVERHEAD: usize = 6;
+// Seconds between queue depth updates
+const QUEUE_STATS_PERIOD: f64 = 0.5;
+
+#[derive(Debug)]
+pub struct DescriptorError(pub String);
+
+impl fmt::Display for DescriptorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for DescriptorError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    F32,
+    F64,
+}
+
+impl FieldType {
+    fn parse(s: &str) -> Option<Self> {
+        let ty = match s {
+            "u8" => FieldType::U8,
+            "i8" => FieldType::I8,
+            "u16" => FieldType::U16,
+            "i16" => FieldType::I16,
+            "u32" => FieldType::U32,
+            "i32" => FieldType::I32,
+            "u64" => FieldType::U64,
+            "i64" => FieldType::I64,
+            "f32" => FieldType::F32,
+            "f64" => FieldType::F64,
+            _ => return None,
+        };
+        Some(ty)
+    }
+
+    pub fn size(self) -> usize {
+        match self {
+            FieldType::U8 | FieldType::I8 => 1,
+            FieldType::U16 | FieldType::I16 => 2,
+            FieldType::U32 | FieldType::I32 | FieldType::F32 => 4,
+            FieldType::U64 | FieldType::I64 | FieldType::F64 => 8,
+        }
+    }
+
+    fn c_type(self) -> &'static str {
+        match self {
+            FieldType::U8 => "uint8_t",
+            FieldType::I8 => "int8_t",
+            FieldType::U16 => "uint16_t",
+            FieldType::I16 => "int16_t",
+            FieldType::U32 => "uint32_t",
+            FieldType::I32 => "int32_t",
+            FieldType::U64 => "uint64_t",
+            FieldType::I64 => "int64_t",
+            FieldType::F32 => "float",
+            FieldType::F64 => "double",
+        }
+    }
+
+    // `bytes` holds exactly size() bytes, in little-endian order
+    fn read(self, bytes: &[u8]) -> f64 {
+        let mut le = [0; 8];
+        le[..bytes.len()].copy_from_slice(bytes);
+        let [b0, b1, b2, b3, ..] = le;
+        match self {
+            FieldType::U8 => b0 as f64,
+            FieldType::I8 => b0 as i8 as f64,
+            FieldType::U16 => u16::from_le_bytes([b0, b1]) as f64,
+            FieldType::I16 => i16::from_le_bytes([b0, b1]) as f64,
+            FieldType::U32 => u32::from_le_bytes([b0, b1, b2, b3]) as f64,
+            FieldType::I32 => i32::from_le_bytes([b0, b1, b2, b3]) as f64,
+            FieldType::U64 => u64::from_le_bytes(le) as f64,
+            FieldType::I64 => i64::from_le_bytes(le) as f64,
+            FieldType::F32 => f32::from_le_bytes([b0, b1, b2, b3]) as f64,
+            FieldType::F64 => f64::from_le_bytes(le),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "little" => Some(Endian::Little),
+            "big" => Some(Endian::Big),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub ty: FieldType,
+    pub endian: Endian,
+    pub scale: f64,
+    pub offset: f64,
+    pub units: Option<String>,
+    /// Where it is logged if it isn't a registered channel
+    pub entity_path: String,
+}
+
+impl Field {
+    fn value(&self, bytes: &[u8]) -> f64 {
+        let raw = match self.endian {
+            Endian::Little => self.ty.read(bytes),
+            Endian::Big => self.ty.read(&bytes.iter().rev().copied().collect::<Vec<_>>()),
+        };
+        raw * self.scale + self.offset
+    }
+
+    fn label(&self) -> String {
+        match &self.units {
+            Some(units) => format!("{} [{}]", self.name, units),
+            None => self.name.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameDef {
+    pub name: String,
+    pub id: u8,
+    pub fields: Vec<Field>,
+}
+
+impl FrameDef {
+    /// Payload size in bytes, the packed struct's size
+    pub fn size(&self) -> usize {
+        self.fields.iter().map(|field| field.ty.size()).sum()
+    }
+}
+
+// Up to C23, including the ones that used to be macros
+const C_KEYWORDS: &[&str] = &[
+    "alignas", "alignof", "auto", "bool", "break", "case", "char", "const", "constexpr", "continue", "default", "do",
+    "double", "else", "enum", "extern", "false", "float", "for", "goto", "if", "inline", "int", "long", "nullptr",
+    "register", "restrict", "return", "short", "signed", "sizeof", "static", "static_assert", "struct", "switch",
+    "thread_local", "true", "typedef", "typeof", "typeof_unqual", "union", "unsigned", "void", "volatile", "while",
+];
+
+// Names end up as C identifiers
+fn is_identifier(s: &str) -> bool {
+    s.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !C_KEYWORDS.contains(&s)
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Descriptor {
+    pub frames: Vec<FrameDef>,
+}
+
+impl Descriptor {
+    /// Parse a descriptor, see the module docs. `#` starts a comment.
+    pub fn parse(src: &str) -> Result<Self, DescriptorError> {
+        let mut frames: Vec<FrameDef> = Vec::new();
+        let mut names = HashSet::new();
+        let mut frame_endian = Endian::Little;
+        for (line_no, line) in src.lines().enumerate() {
+            let mut words = line.split('#').next().unwrap_or("").split_whitespace();
+            let Some(first) = words.next() else {
+                continue;
+            };
+            let err = |msg: String| DescriptorError(format!("line {}: {}", line_no + 1, msg));
+
+            let mut positional = Vec::new();
+            let mut options = Vec::new();
+            for word in words {
+                match word.split_once('=') {
+                    Some(option) => options.push(option),
+                    None => positional.push(word),
+                }
+            }
+            let endian = |default: Endian| match options.iter().find(|(key, _)| *key == "endian") {
+                Some((_, value)) => Endian::parse(value).ok_or_else(|| err(format!("endian must be little or big, not '{}'", value))),
+                None => Ok(default),
+            };
+
+            if first == "frame" {
+                let [name, id] = positional[..] else {
+                    return Err(err("expected 'frame <name> <id>'".into()));
+                };
+                // Also unique ignoring case, as the ids are upper-case macros
+                if !is_identifier(name) || frames.iter().any(|f| f.name.eq_ignore_ascii_case(name)) {
+                    return Err(err(format!("invalid or repeated frame name '{}'", name)));
+                }
+                let id: u8 = id.parse().map_err(|_| err(format!("frame id must be 0-255, not '{}'", id)))?;
+                if frames.iter().any(|f| f.id == id) {
+                    return Err(err(format!("frame id {} is already used", id)));
+                }
+                if let Some((key, _)) = options.iter().find(|(key, _)| *key != "endian") {
+                    return Err(err(format!("unknown frame option '{}'", key)));
+                }
+                frame_endian = endian(Endian::Little)?;
+                frames.push(FrameDef { name: name.to_string(), id, fields: Vec::new() });
+                continue;
+            }
+
+            let name = first;
+            let Some(frame) = frames.last_mut() else {
+                return Err(err("field before the first 'frame' line".into()));
+            };
+            let [ty] = positional[..] else {
+                return Err(err("expected '<name> <type> [option=value]...'".into()));
+            };
+            let ty = FieldType::parse(ty).ok_or_else(|| err(format!("unknown type '{}'", ty)))?;
+            if !is_identifier(name) || !names.insert(name.to_string()) {
+                return Err(err(format!("invalid or repeated field name '{}'", name)));
+            }
+            let mut field = Field {
+                name: name.to_string(),
+                ty,
+                endian: endian(frame_endian)?,
+                scale: 1.0,
+                offset: 0.0,
+                units: None,
+                entity_path: format!("{}/{}", frame.name, name),
+            };
+            for (key, value) in &options {
+                let number = || value.parse::<f64>().map_err(|_| err(format!("bad number '{}'", value)));
+                match *key {
+                    "scale" => field.scale = number()?,
+                    "offset" => field.offset = number()?,
+                    "units" => field.units = Some(value.to_string()),
+                    "endian" => {}
+                    _ => return Err(err(format!("unknown field option '{}'", key))),
+                }
+            }
+            frame.fields.push(field);
+            if frame.size() > u8::MAX as usize {
+                return Err(err(format!("frame '{}' is larger than 255 bytes", frame.name)));
+            }
+        }
+        if let Some(frame) = frames.iter().find(|frame| frame.fields.is_empty()) {
+            return Err(DescriptorError(format!("frame '{}' has no fields", frame.name)));
+        }
+        Ok(Descriptor { frames })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let src = std::fs::read_to_string(path)?;
+        Ok(Descriptor::parse(&src).map_err(|e| format!("{}: {}", path.display(), e))?)
+    }
+
+    /// A C header with a packed struct and id per frame, and `mc_frame()` to build frames.
+    /// `name` is used for the include guard, e.g. the descriptor's file name.
+    pub fn c_header(&self, name: &str) -> String {
+        let stem = Path::new(name).file_stem().map_or(name.into(), |stem| stem.to_string_lossy());
+        let guard: String = stem.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' }).collect();
+        let mut h = String::new();
+        let _ = writeln!(h, "/* Generated from {} by `visualizer c-header`, do not edit. */", name);
+        let _ = writeln!(h, "#ifndef MC_{}_H", guard);
+        let _ = writeln!(h, "#define MC_{}_H\n", guard);
+        h.push_str("#include <stddef.h>\n#include <stdint.h>\n#include <string.h>\n\n");
+        h.push_str("/* Frame: sync, id, payload length, payload, CRC-16/CCITT-FALSE of id..payload (little-endian) */\n");
+        let _ = writeln!(h, "#define MC_SYNC0 0x{:02X}", SYNC[0]);
+        let _ = writeln!(h, "#define MC_SYNC1 0x{:02X}", SYNC[1]);
+        let _ = writeln!(h, "#define MC_FRAME_OVERHEAD {}\n", OVERHEAD);
+        h.push_str("/* Fields are in native order, which must be little-endian unless marked big-endian. */\n\n");
+        for frame in &self.frames {
+            let (ty, upper) = (format!("mc_{}_t", frame.name), frame.name.to_ascii_uppercase());
+            let _ = writeln!(h, "#define MC_{}_ID {}", upper, frame.id);
+            h.push_str("typedef struct __attribute__((packed)) {\n");
+            for field in &frame.fields {
+                let mut notes = Vec::new();
+                if field.endian == Endian::Big {
+                    notes.push("big-endian".to_string());
+                }
+                if let Some(units) = &field.units {
+                    notes.push(units.clone());
+                }
+                if field.scale != 1.0 || field.offset != 0.0 {
+                    notes.push(format!("value = raw * {} + {}", field.scale, field.offset));
+                }
+                let comment = if notes.is_empty() { String::new() } else { format!(" /* {} */", notes.join(", ")) };
+                let _ = writeln!(h, "    {} {};{}", field.ty.c_type(), field.name, comment);
+            }
+            let _ = writeln!(h, "}} {};", ty);
+            let _ = writeln!(h, "_Static_assert(sizeof({}) == {}, \"{} does not match the descriptor\");\n", ty, frame.size(), ty);
+        }
+        h.push_str(
+            "static inline uint16_t mc_crc16(const uint8_t *data, size_t len) {
+    uint16_t crc = 0xFFFF;
+    for (size_t i = 0; i < len; i++) {
+        crc ^= (uint16_t)data[i] << 8;
+        for (int bit = 0; bit < 8; bit++) {
+            crc = (crc & 0x8000) ? (uint16_t)(crc << 1) ^ 0x1021 : (uint16_t)(crc << 1);
+        }
+    }
+    return crc;
+}
+
+/* Writes the frame of a payload, e.g. an mc_imu_t, to out, which holds at least
+ * len + MC_FRAME_OVERHEAD bytes. Returns the frame length. */
+static inline size_t mc_frame(uint8_t id, const void *payload, uint8_t len, uint8_t *out) {
+    out[0] = MC_SYNC0;
+    out[1] = MC_SYNC1;
+    out[2] = id;
+    out[3] = len;
+    memcpy(out + 4, payload, len);
+    uint16_t crc = mc_crc16(out + 2, (size_t)len + 2);
+    out[4 + len] = (uint8_t)(crc & 0xFF);
+    out[5 + len] = (uint8_t)(crc >> 8);
+    return (size_t)len + MC_FRAME_OVERHEAD;
+}
+
+",
+        );
+        let _ = writeln!(h, "#endif /* MC_{}_H */", guard);
+        h
+    }
+}
+
+// CRC-16/CCITT-FALSE
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0xFFFF, |crc: u16, &b| {
+        (0..8).fold(crc ^ (b as u16) << 8, |crc, _| if crc & 0x8000 != 0 { crc << 1 ^ 0x1021 } else { crc << 1 })
+    })
+}
+
+/// Frame `payload` as the firmware would, see `mc_frame()` in the C header.
+pub fn encode_frame(id: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![SYNC[0], SYNC[1], id, payload.len() as u8];
+    frame.extend_from_slice(payload);
+    let crc = crc16(&frame[2..]);
+    frame.extend_from_slice(&crc.to_le_bytes());
+    frame
+}
+
+/// A frame that didn't match the descriptor.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameError {
+    UnknownId(u8),
+    // Frame id, expected and received payload length
+    WrongLength(u8, usize, usize),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::UnknownId(id) => write!(f, "frame id {} is not in the descriptor", id),
+            FrameError::WrongLength(id, expected, len) => write!(f, "frame {} has {} bytes, the descriptor {}", id, len, expected),
+        }
+    }
+}
+
+/// Splits a byte stream into frames and their fields, skipping anything that isn't a
+/// whole frame with a valid checksum.
+pub struct Decoder {
+    descriptor: Descriptor,
+    buf: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn new(descriptor: Descriptor) -> Self {
+        Decoder { descriptor, buf: Vec::new() }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Forget the partial frame held, after bytes following it were lost.
+    pub fn resync(&mut self) {
+        self.buf.clear();
+    }
+
+    /// The fields of the next frame with their values, or `None` once more bytes are needed.
+    pub fn next_frame(&mut self) -> Option<Result<Vec<(&Field, f64)>, FrameError>> {
+        let (id, payload) = loop {
+            let Some(start) = self.buf.windows(2).position(|w| w == SYNC) else {
+                // Keep a trailing first sync byte
+                let keep = usize::from(self.buf.last() == Some(&SYNC[0]));
+                self.buf.drain(..self.buf.len() - keep);
+                return None;
+            };
+            self.buf.drain(..start);
+            if self.buf.len() < 4 {
+                return None;
+            }
+            let len = self.buf[3] as usize;
+            if self.buf.len() < len + OVERHEAD {
+                return None;
+            }
+            let crc = u16::from_le_bytes([self.buf[4 + len], self.buf[5 + len]]);
+            // Sync bytes inside a payload look like a frame too, so resync right after
+            if crc16(&self.buf[2..4 + len]) != crc {
+                self.buf.drain(..1);
+                continue;
+            }
+            let frame: Vec<u8> = self.buf.drain(..len + OVERHEAD).collect();
+            break (frame[2], frame[4..4 + len].to_vec());
+        };
+        let Some(def) = self.descriptor.frames.iter().find(|frame| frame.id == id) else {
+            return Some(Err(FrameError::UnknownId(id)));
+        };
+        if payload.len() != def.size() {
+            return Some(Err(FrameError::WrongLength(id, def.size(), payload.len())));
+        }
+        let mut offset = 0;
+        let mut values = Vec::with_capacity(def.fields.len());
+        for field in &def.fields {
+            let size = field.ty.size();
+            values.push((field, field.value(&payload[offset..offset + size])));
+            offset += size;
+        }
+        Some(Ok(values))
+    }
+}
+
+/// Run a session on `port` decoding the binary frames of `descriptor`, until a shutdown is
+/// received on `listener_msgs_r` or the port fails for good. Commands are still written as text.
+/// Fields are recorded to `capture` as `name:value` lines.
+pub fn run(
+    port: Box<dyn serialport::SerialPort>,
+    descriptor: Descriptor,
+    overflow: OverflowPolicy,
+    capture: Option<PathBuf>,
+    mut pipeline: Pipeline,
+    cmds_r: Receiver<String>,
+    listener_msgs_r: Receiver<ListenerMsg>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut capture = match &capture {
+        Some(path) => Some(CaptureWriter::create(path)?),
+        None => None,
+    };
+    let frames: Vec<String> = descriptor.frames.iter().map(|frame| format!("{} ({} bytes)", frame.name, frame.size())).collect();
+    println!("Decoding binary frames {}", frames.join(", "));
+    let mut decoder = Decoder::new(descriptor);
+    // Each kind of error is reported once, a mismatched firmware would repeat it for every frame
+    let mut reported = HashSet::new();
+    let mut link = SerialLink::start(port, overflow, cmds_r)?;
+    let mut stats_t = 0.0;
+    let mut next_seq = 0;
+    loop {
+        crossbeam_channel::select! {
+            recv(link.chunks_r) -> chunk => {
+                let Ok(chunk) = chunk else {
+                    break;
+                };
+                let t = chunk.t;
+                if chunk.after_gap(&mut next_seq) {
+                    decoder.resync();
+                }
+                decoder.push(&chunk.bytes);
+                while let Some(frame) = decoder.next_frame() {
+                    let fields = match frame {
+                        Ok(fields) => fields,
+                        Err(e) => {
+                            if reported.insert(e.to_string()) {
+                                println!("Dropped binary frame: {}", e);
+                            }
+                            continue;
+                        }
+                    };
+                    for (field, value) in fields {
+                        if let Some(capture) = &mut capture {
+                            let _ = capture.line(t, &format!("{}:{}", field.name, value));
+                        }
+                        if channels::lookup(&field.name).is_some() {
+                            pipeline.line(t, format!("{}:{}", field.name, value).as_bytes());
+                        } else {
+                            pipeline.sample(t, &field.name, &field.entity_path, &field.label(), value);
+                        }
+                    }
+                }
+            },
+            recv(link.sent_r) -> sent => {
+                if let Ok((t, command)) = sent {
+                    if let Some(capture) = &mut capture {
+                        let _ = capture.command(t, &command);
+                    }
+                    pipeline.command(t, &command);
+                }
+            },
+            recv(listener_msgs_r) -> msg => match msg {
+                Ok(ListenerMsg::Shutdown) | Err(_) => break,
+                Ok(msg) => pipeline.handle(msg),
+            },
+            default(Duration::from_millis(100)) => {},
+        }
+        let t = link.elapsed();
+        if t - stats_t >= QUEUE_STATS_PERIOD {
+            stats_t = t;
+            pipeline.queue_stats(t, link.stats());
+        }
+    }
+    link.stop();
+    if let Some(capture) = &mut capture {
+        capture.flush()?;
+    }
+    pipeline.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESCRIPTOR: &str = "
+        frame imu 1
+        acc_x   i16  scale=0.001 units=g   # a registered channel
+        temp    u16  endian=big scale=0.01 offset=-40 units=degC
+
+        frame power 2 endian=big
+        bus_voltage f32 units=V
+    ";
+
+    #[test]
+    fn parses_frames_and_fields() {
+        let descriptor = Descriptor::parse(DESCRIPTOR).unwrap();
+        assert_eq!(descriptor.frames.len(), 2);
+        let imu = &descriptor.frames[0];
+        assert_eq!((imu.name.as_str(), imu.id, imu.size()), ("imu", 1, 4));
+        assert_eq!(imu.fields[1].endian, Endian::Big);
+        assert_eq!(imu.fields[1].entity_path, "imu/temp");
+        assert_eq!(imu.fields[1].label(), "temp [degC]");
+        assert_eq!(descriptor.frames[1].fields[0].endian, Endian::Big);
+    }
+
+    #[test]
+    fn rejects_bad_descriptors() {
+        for (src, msg) in [
+            ("acc_x i16", "line 1: field before the first 'frame' line"),
+            ("frame a 1\nx i24", "line 2: unknown type 'i24'"),
+            ("frame a 1\nx u8\nframe b 1\ny u8", "line 3: frame id 1 is already used"),
+            ("frame a 1\nx u8\nx u8", "line 3: invalid or repeated field name 'x'"),
+            ("frame a 1\nint u8", "line 2: invalid or repeated field name 'int'"),
+            ("frame float 1\nx u8", "line 1: invalid or repeated frame name 'float'"),
+            ("frame imu 1\nx u8\nframe IMU 2\ny u8", "line 3: invalid or repeated frame name 'IMU'"),
+            ("frame a 1\nx u8 scale=abc", "line 2: bad number 'abc'"),
+            ("frame a 1", "frame 'a' has no fields"),
+        ] {
+            assert_eq!(Descriptor::parse(src).unwrap_err().to_string(), msg);
+        }
+    }
+
+    #[test]
+    fn checksum_matches_reference() {
+        // CRC-16/CCITT-FALSE check value
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn decodes_frames_through_noise() {
+        let mut decoder = Decoder::new(Descriptor::parse(DESCRIPTOR).unwrap());
+        let payload = [(-1000i16).to_le_bytes(), 6500u16.to_be_bytes()].concat();
+        let mut bytes = vec![0x00, 0xA5, 0x5A, 0x01, 0x02];
+        bytes.extend(encode_frame(1, &payload));
+        bytes.extend(encode_frame(2, &24.5f32.to_be_bytes()));
+
+        let (a, b) = bytes.split_at(7);
+        decoder.push(a);
+        assert!(decoder.next_frame().is_none());
+        decoder.push(b);
+        let values = |frame: Option<Result<Vec<(&Field, f64)>, FrameError>>| -> Vec<(String, f64)> {
+            frame.unwrap().unwrap().into_iter().map(|(field, v)| (field.name.clone(), v)).collect()
+        };
+        assert_eq!(values(decoder.next_frame()), [("acc_x".to_string(), -1.0), ("temp".to_string(), 25.0)]);
+        assert_eq!(values(decoder.next_frame()), [("bus_voltage".to_string(), 24.5)]);
+        assert!(decoder.next_frame().is_none());
+    }
+
+    #[test]
+    fn mismatched_frames_are_errors() {
+        let mut decoder = Decoder::new(Descriptor::parse(DESCRIPTOR).unwrap());
+        decoder.push(&encode_frame(9, &[1, 2]));
+        decoder.push(&encode_frame(1, &[1, 2]));
+        assert_eq!(decoder.next_frame(), Some(Err(FrameError::UnknownId(9))));
+        assert_eq!(decoder.next_frame(), Some(Err(FrameError::WrongLength(1, 4, 2))));
+    }
+
+    #[test]
+    fn c_header_matches_the_descriptor() {
+        let header = Descriptor::parse(DESCRIPTOR).unwrap().c_header("telemetry.desc");
+        assert!(header.contains("#ifndef MC_TELEMETRY_H"));
+        assert!(header.contains("#define MC_IMU_ID 1"));
+        assert!(header.contains("    int16_t acc_x; /* g, value = raw * 0.001 + 0 */"));
+        assert!(header.contains("    uint16_t temp; /* big-endian, degC, value = raw * 0.01 + -40 */"));
+        assert!(header.contains("_Static_assert(sizeof(mc_power_t) == 4,"));
+    }
+}
@@ -0,0 +1,323 @@
+//! IMU calibration: gyro bias from a stationary capture and accelerometer offset/scale
+//! from an axis-aligned ellipsoid fit over captures in several orientations.
+//!
+//! The calibration is stored as a small text file and applied to the incoming
+//! `acc_*` and `gyr_*` channels before they are logged:
+//!
+//! ```text
+//! gyro_bias  = 0.012, -0.004, 0.001
+//! acc_offset = 0.05, -0.02, 0.11
+//! acc_scale  = 1.002, 0.998, 1.004
+//! ```
+
+use std::fmt;
+
+use crate::fusion::ImuSample;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImuCalibration {
+    pub gyro_bias: [f64; 3],
+    pub acc_offset: [f64; 3],
+    pub acc_scale: [f64; 3],
+}
+
+impl Default for ImuCalibration {
+    fn default() -> Self {
+        ImuCalibration {
+            gyro_bias: [0.0; 3],
+            acc_offset: [0.0; 3],
+            acc_scale: [1.0; 3],
+        }
+    }
+}
+
+impl ImuCalibration {
+    /// Corrected value of a raw channel sample. Other channels pass through unchanged.
+    pub fn apply(&self, header: &str, value: f64) -> f64 {
+        match header {
+            "acc_x" => (value - self.acc_offset[0]) * self.acc_scale[0],
+            "acc_y" => (value - self.acc_offset[1]) * self.acc_scale[1],
+            "acc_z" => (value - self.acc_offset[2]) * self.acc_scale[2],
+            "gyr_x" => value - self.gyro_bias[0],
+            "gyr_y" => value - self.gyro_bias[1],
+            "gyr_z" => value - self.gyro_bias[2],
+            _ => value,
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut cal = ImuCalibration::default();
+        for line in std::fs::read_to_string(path)?.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let Some((key, values)) = line.split_once('=') else {
+                continue;
+            };
+            let values = values
+                .split(',')
+                .map(|v| v.trim().parse::<f64>())
+                .collect::<Result<Vec<f64>, _>>()?;
+            let values: [f64; 3] = values
+                .try_into()
+                .map_err(|_| format!("'{}' needs three comma separated values", key.trim()))?;
+            match key.trim() {
+                "gyro_bias" => cal.gyro_bias = values,
+                "acc_offset" => cal.acc_offset = values,
+                "acc_scale" => cal.acc_scale = values,
+                other => return Err(format!("unknown calibration key '{}'", other).into()),
+            }
+        }
+        Ok(cal)
+    }
+
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        std::fs::write(path, self.to_string())
+    }
+}
+
+impl fmt::Display for ImuCalibration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let row = |v: [f64; 3]| format!("{}, {}, {}", v[0], v[1], v[2]);
+        writeln!(f, "gyro_bias  = {}", row(self.gyro_bias))?;
+        writeln!(f, "acc_offset = {}", row(self.acc_offset))?;
+        writeln!(f, "acc_scale  = {}", row(self.acc_scale))
+    }
+}
+
+// Sent from the GUI to the listener thread
+pub enum CalibrationMsg {
+    // Start/stop forwarding raw IMU samples to the GUI
+    Capture(bool),
+    Apply(ImuCalibration),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AccelFit {
+    pub offset: [f64; 3],
+    pub scale: [f64; 3],
+    // RMS of |calibrated acc| - gravity, in the firmware's acceleration units. Only known with
+    // more orientations than the 6 parameters, which any 6 orientations fit exactly.
+    pub rms_error: Option<f64>,
+}
+
+/// Fit `A x² + B y² + C z² + D x + E y + F z = 1` to the mean accelerometer reading of each
+/// orientation. Needs at least six well spread orientations, and more to tell how well the
+/// fit matches; nine or more are best.
+pub fn fit_accelerometer(orientations: &[[f64; 3]], gravity: f64) -> Result<AccelFit, String> {
+    if orientations.len() < 6 {
+        return Err(format!("need at least 6 orientations, have {}", orientations.len()));
+    }
+
+    // Least squares via the normal equations
+    let mut ata = [[0.0; 6]; 6];
+    let mut atb = [0.0; 6];
+    for &[x, y, z] in orientations {
+        let row = [x * x, y * y, z * z, x, y, z];
+        for i in 0..6 {
+            for j in 0..6 {
+                ata[i][j] += row[i] * row[j];
+            }
+            atb[i] += row[i];
+        }
+    }
+    let p = solve6(ata, atb).ok_or("orientations are degenerate, capture more varied poses")?;
+    let (quad, lin) = ([p[0], p[1], p[2]], [p[3], p[4], p[5]]);
+    if quad.iter().any(|&q| q <= 0.0) {
+        return Err("fit is not an ellipsoid, capture more varied poses".into());
+    }
+
+    let offset = [0, 1, 2].map(|i| -lin[i] / (2.0 * quad[i]));
+    let g = 1.0 + (0..3).map(|i| quad[i] * offset[i] * offset[i]).sum::<f64>();
+    let radius = [0, 1, 2].map(|i| (g / quad[i]).sqrt());
+    let scale = radius.map(|r| gravity / r);
+
+    let sq_err: f64 = orientations
+        .iter()
+        .map(|m| {
+            let norm = (0..3).map(|i| ((m[i] - offset[i]) * scale[i]).powi(2)).sum::<f64>().sqrt();
+            (norm - gravity).powi(2)
+        })
+        .sum();
+    let rms_error = (orientations.len() > 6).then(|| (sq_err / orientations.len() as f64).sqrt());
+    Ok(AccelFit { offset, scale, rms_error })
+}
+
+// Gaussian elimination with partial pivoting
+fn solve6(mut a: [[f64; 6]; 6], mut b: [f64; 6]) -> Option<[f64; 6]> {
+    for col in 0..6 {
+        let pivot = (col..6).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        for row in col + 1..6 {
+            let k = a[row][col] / a[col][col];
+            let pivot_row = a[col];
+            for (v, p) in a[row].iter_mut().zip(pivot_row).skip(col) {
+                *v -= k * p;
+            }
+            b[row] -= k * b[col];
+        }
+    }
+    let mut x = [0.0; 6];
+    for row in (0..6).rev() {
+        let s: f64 = (row + 1..6).map(|c| a[row][c] * x[c]).sum();
+        x[row] = (b[row] - s) / a[row][row];
+    }
+    Some(x)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WizardStep {
+    Idle,
+    GyroBias,
+    Orientations,
+    Done,
+}
+
+// GUI-side state of the calibration wizard
+pub struct CalibrationWizard {
+    pub step: WizardStep,
+    pub samples_per_capture: usize,
+    capturing: Vec<ImuSample>,
+    is_capturing: bool,
+    pub gyro_bias: [f64; 3],
+    pub orientations: Vec<[f64; 3]>,
+    pub result: Option<Result<ImuCalibration, String>>,
+    pub fit: Option<AccelFit>,
+}
+
+impl Default for CalibrationWizard {
+    fn default() -> Self {
+        CalibrationWizard {
+            step: WizardStep::Idle,
+            samples_per_capture: 200,
+            capturing: Vec::new(),
+            is_capturing: false,
+            gyro_bias: [0.0; 3],
+            orientations: Vec::new(),
+            result: None,
+            fit: None,
+        }
+    }
+}
+
+fn mean(samples: impl Iterator<Item = [f64; 3]>) -> [f64; 3] {
+    let (sum, n) = samples.fold(([0.0; 3], 0usize), |(s, n), v| ([s[0] + v[0], s[1] + v[1], s[2] + v[2]], n + 1));
+    sum.map(|s| s / n.max(1) as f64)
+}
+
+impl CalibrationWizard {
+    pub fn start(&mut self) {
+        *self = CalibrationWizard { samples_per_capture: self.samples_per_capture, ..Default::default() };
+        self.step = WizardStep::GyroBias;
+    }
+
+    pub fn begin_capture(&mut self) {
+        self.capturing.clear();
+        self.is_capturing = true;
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        self.is_capturing
+    }
+
+    pub fn capture_progress(&self) -> f32 {
+        self.capturing.len() as f32 / self.samples_per_capture.max(1) as f32
+    }
+
+    /// Feed a raw sample. Returns true when the current capture has just completed.
+    pub fn add_sample(&mut self, sample: ImuSample) -> bool {
+        if !self.is_capturing {
+            return false;
+        }
+        self.capturing.push(sample);
+        if self.capturing.len() < self.samples_per_capture {
+            return false;
+        }
+        self.is_capturing = false;
+
+        let acc_mean = mean(self.capturing.iter().map(|s| s.acc));
+        if self.step == WizardStep::GyroBias {
+            self.gyro_bias = mean(self.capturing.iter().map(|s| s.gyr));
+            self.step = WizardStep::Orientations;
+        }
+        // The stationary capture doubles as the first orientation
+        self.orientations.push(acc_mean);
+        true
+    }
+
+    pub fn compute(&mut self) {
+        // Readings near 1 mean the firmware reports in g, otherwise m/s²
+        let mean_norm = self.orientations.iter().map(|m| m.iter().map(|v| v * v).sum::<f64>().sqrt()).sum::<f64>()
+            / self.orientations.len().max(1) as f64;
+        let gravity = if mean_norm < 3.0 { 1.0 } else { 9.80665 };
+
+        self.result = Some(fit_accelerometer(&self.orientations, gravity).map(|fit| {
+            self.fit = Some(fit);
+            ImuCalibration {
+                gyro_bias: self.gyro_bias,
+                acc_offset: fit.offset,
+                acc_scale: fit.scale,
+            }
+        }));
+        self.step = WizardStep::Done;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn solve6_solves_a_system_needing_pivots() {
+        // Zero on the diagonal, so it only solves with row swaps
+        let mut a = [[0.0; 6]; 6];
+        for (i, row) in a.iter_mut().enumerate() {
+            row[(i + 1) % 6] = 2.0;
+            row[i] = if i % 2 == 0 { 0.0 } else { 1.0 };
+        }
+        let x = [1.0, -2.0, 3.0, 0.5, -0.25, 4.0];
+        let b = a.map(|row| (0..6).map(|j| row[j] * x[j]).sum::<f64>());
+        let solved = solve6(a, b).unwrap();
+        for i in 0..6 {
+            assert!((solved[i] - x[i]).abs() < 1e-12);
+        }
+        assert_eq!(solve6([[1.0; 6]; 6], [1.0; 6]), None);
+    }
+
+    // Mean readings of a sensor with `offset` and `scale` held still in each of `directions`
+    fn readings(directions: &[[f64; 3]], offset: [f64; 3], scale: [f64; 3], gravity: f64) -> Vec<[f64; 3]> {
+        directions
+            .iter()
+            .map(|d| {
+                let norm = d.iter().map(|v| v * v).sum::<f64>().sqrt();
+                [0, 1, 2].map(|i| d[i] / norm * gravity / scale[i] + offset[i])
+            })
+            .collect()
+    }
+
+    const FACES: [[f64; 3]; 6] = [[1.0, 0.0, 0.0], [-1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, -1.0, 0.0], [0.0, 0.0, 1.0], [0.0, 0.0, -1.0]];
+
+    #[test]
+    fn fit_recovers_offset_and_scale() {
+        let (offset, scale, gravity) = ([0.3, -0.2, 0.5], [1.02, 0.97, 1.05], 9.80665);
+        let directions = [&FACES[..], &[[1.0, 1.0, 0.0], [0.0, -1.0, 1.0], [-1.0, 0.0, -1.0]]].concat();
+        let fit = fit_accelerometer(&readings(&directions, offset, scale, gravity), gravity).unwrap();
+        for i in 0..3 {
+            assert!((fit.offset[i] - offset[i]).abs() < 1e-9, "{:?}", fit.offset);
+            assert!((fit.scale[i] - scale[i]).abs() < 1e-9, "{:?}", fit.scale);
+        }
+        assert!(fit.rms_error.unwrap() < 1e-9);
+    }
+
+    #[test]
+    fn fit_quality_needs_more_than_six_orientations() {
+        let fit = fit_accelerometer(&readings(&FACES, [0.1, 0.0, 0.0], [1.0; 3], 1.0), 1.0).unwrap();
+        assert!((fit.offset[0] - 0.1).abs() < 1e-9);
+        assert_eq!(fit.rms_error, None);
+
+        assert!(fit_accelerometer(&FACES[..5], 1.0).is_err());
+        assert!(fit_accelerometer(&[[0.0, 0.0, 1.0]; 7], 1.0).is_err());
+    }
+}
@@ -0,0 +1,306 @@
+//! A live session over SocketCAN, for ODrives on a CAN bus rather than the firmware's
+//! serial stream. Frames are decoded with [`crate::cansimple`] into the same `header:value`
+//! lines the firmware sends, so they go through the [`Pipeline`] unchanged, and dispatched
+//! commands are sent as CANSimple frames.
+
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, Sender};
+
+use crate::capture::CaptureWriter;
+use crate::cansimple::{CanFrame, Decoder, Encoder};
+use crate::commands::Command;
+use crate::pipeline::{ListenerMsg, Pipeline};
+use crate::serial::{OverflowPolicy, QueueStats, READ_QUEUE_CAPACITY};
+
+// Size of struct can_frame
+const CAN_MTU: usize = 16;
+// How often the reader wakes up to notice a stop
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+// Seconds between queue depth updates
+const QUEUE_STATS_PERIOD: f64 = 0.5;
+
+/// A raw CAN socket bound to one interface, e.g. `can0` or `vcan0`.
+pub struct CanSocket {
+    fd: OwnedFd,
+}
+
+impl CanSocket {
+    pub fn open(interface: &str) -> io::Result<Self> {
+        let name = CString::new(interface).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // SAFETY: plain libc calls; the fd is owned from here on
+        let fd = unsafe {
+            let ifindex = libc::if_nametoindex(name.as_ptr());
+            if ifindex == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let fd = libc::socket(libc::AF_CAN, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::CAN_RAW);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let fd = OwnedFd::from_raw_fd(fd);
+
+            let mut addr: libc::sockaddr_can = mem::zeroed();
+            addr.can_family = libc::AF_CAN as libc::sa_family_t;
+            addr.can_ifindex = ifindex as libc::c_int;
+            let addr_len = mem::size_of::<libc::sockaddr_can>() as libc::socklen_t;
+            if libc::bind(fd.as_raw_fd(), &addr as *const _ as *const libc::sockaddr, addr_len) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let timeout = libc::timeval { tv_sec: 0, tv_usec: READ_TIMEOUT.as_micros() as libc::suseconds_t };
+            let timeout_len = mem::size_of::<libc::timeval>() as libc::socklen_t;
+            if libc::setsockopt(fd.as_raw_fd(), libc::SOL_SOCKET, libc::SO_RCVTIMEO, &timeout as *const _ as *const libc::c_void, timeout_len) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            fd
+        };
+        Ok(CanSocket { fd })
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(CanSocket { fd: self.fd.try_clone()? })
+    }
+
+    /// The next standard data frame; extended, remote and error frames are skipped.
+    /// Times out with `WouldBlock` after a while without one.
+    pub fn read(&self) -> io::Result<CanFrame> {
+        loop {
+            let mut buf = [0u8; CAN_MTU];
+            // SAFETY: buf outlives the call and holds CAN_MTU bytes
+            let n = unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, CAN_MTU) };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if n as usize != CAN_MTU {
+                continue;
+            }
+            let id = u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]);
+            if id & (libc::CAN_EFF_FLAG | libc::CAN_RTR_FLAG | libc::CAN_ERR_FLAG) != 0 {
+                continue;
+            }
+            let mut data = [0; 8];
+            data.copy_from_slice(&buf[8..]);
+            return Ok(CanFrame { id: id & libc::CAN_SFF_MASK, len: buf[4].min(8), data });
+        }
+    }
+
+    pub fn write(&self, frame: &CanFrame) -> io::Result<()> {
+        let mut buf = [0u8; CAN_MTU];
+        buf[..4].copy_from_slice(&frame.id.to_ne_bytes());
+        buf[4] = frame.len;
+        buf[8..].copy_from_slice(&frame.data);
+        // SAFETY: buf outlives the call and holds CAN_MTU bytes
+        let n = unsafe { libc::write(self.fd.as_raw_fd(), buf.as_ptr() as *const libc::c_void, CAN_MTU) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+// Shared with the bus threads, in frames and data bytes
+#[derive(Default)]
+struct Counters {
+    read_bytes: AtomicU64,
+    dropped_frames: AtomicU64,
+    dropped_bytes: AtomicU64,
+    dropped_commands: AtomicU64,
+}
+
+// Queues the frames returned by `read`, e.g. `CanSocket::read`, until it fails
+fn read_frames(
+    mut read: impl FnMut() -> io::Result<CanFrame>,
+    start_time: Instant,
+    policy: OverflowPolicy,
+    frames_s: Sender<(f64, CanFrame)>,
+    frames_r: Receiver<(f64, CanFrame)>,
+    counters: Arc<Counters>,
+    stop: Arc<AtomicBool>,
+) {
+    let count_drop = |(_, frame): &(f64, CanFrame)| {
+        counters.dropped_frames.fetch_add(1, Ordering::Relaxed);
+        counters.dropped_bytes.fetch_add(frame.len as u64, Ordering::Relaxed);
+    };
+    while !stop.load(Ordering::Relaxed) {
+        match read() {
+            Ok(frame) => {
+                counters.read_bytes.fetch_add(frame.len as u64, Ordering::Relaxed);
+                if !policy.enqueue((start_time.elapsed().as_secs_f64(), frame), &frames_s, &frames_r, &stop, count_drop) {
+                    return;
+                }
+            }
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted) => {}
+            Err(e) => {
+                println!("CAN read failed: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+fn write_commands(
+    socket: CanSocket,
+    node_ids: Vec<u8>,
+    start_time: Instant,
+    cmds_r: Receiver<String>,
+    sent_s: Sender<(f64, String)>,
+    counters: Arc<Counters>,
+    stop: Arc<AtomicBool>,
+) {
+    let mut encoder = Encoder::new(&node_ids);
+    while !stop.load(Ordering::Relaxed) {
+        let command = match cmds_r.recv_timeout(READ_TIMEOUT) {
+            Ok(command) => command,
+            Err(e) if e.is_timeout() => continue,
+            Err(_) => return,
+        };
+        let Some(decoded) = Command::decode(&command) else {
+            println!("Ignored unknown command '{}'", command);
+            continue;
+        };
+        let frames = match encoder.encode(decoded) {
+            Ok(frames) => frames,
+            Err(e) => {
+                println!("Ignored command '{}': {}", command, e);
+                continue;
+            }
+        };
+        if let Err(e) = frames.iter().try_for_each(|frame| socket.write(frame)) {
+            println!("Failed to write to CAN bus: {}", e);
+            counters.dropped_commands.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        let _ = sent_s.send((start_time.elapsed().as_secs_f64(), command));
+    }
+}
+
+/// Run a session on the CAN `interface` with the ODrive axes `node_ids`, until a shutdown
+/// is received on `listener_msgs_r` or the bus fails. Lines decoded from the bus are
+/// recorded to `capture` if given, as in a serial session, and `overflow` decides which
+/// frames are lost when processing falls behind.
+pub fn run(
+    interface: &str,
+    node_ids: &[u8],
+    overflow: OverflowPolicy,
+    capture: Option<PathBuf>,
+    mut pipeline: Pipeline,
+    cmds_r: Receiver<String>,
+    listener_msgs_r: Receiver<ListenerMsg>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let socket = CanSocket::open(interface).map_err(|e| format!("could not open CAN interface {}: {}", interface, e))?;
+    let mut capture = match &capture {
+        Some(path) => Some(CaptureWriter::create(path)?),
+        None => None,
+    };
+    let start_time = Instant::now();
+    let stop = Arc::new(AtomicBool::new(false));
+    let counters = Arc::new(Counters::default());
+    let (frames_s, frames_r) = crossbeam_channel::bounded(READ_QUEUE_CAPACITY);
+    let (sent_s, sent_r) = crossbeam_channel::unbounded();
+    let reader: JoinHandle<()> = {
+        let (socket, frames_r, counters, stop) = (socket.try_clone()?, frames_r.clone(), counters.clone(), stop.clone());
+        thread::spawn(move || read_frames(|| socket.read(), start_time, overflow, frames_s, frames_r, counters, stop))
+    };
+    let writer = {
+        let (node_ids, cmds_r, counters, stop) = (node_ids.to_vec(), cmds_r.clone(), counters.clone(), stop.clone());
+        thread::spawn(move || write_commands(socket, node_ids, start_time, cmds_r, sent_s, counters, stop))
+    };
+    println!("Listening to ODrive nodes {:?} on {}", node_ids, interface);
+
+    let mut decoder = Decoder::new(node_ids);
+    let (mut stats_t, mut read_peak) = (0.0, 0);
+    loop {
+        crossbeam_channel::select! {
+            recv(frames_r) -> frame => {
+                let Ok((t, frame)) = frame else {
+                    break;
+                };
+                for line in decoder.decode(&frame) {
+                    if let Some(capture) = &mut capture {
+                        let _ = capture.line(t, &line);
+                    }
+                    pipeline.line(t, line.as_bytes());
+                }
+            },
+            // Dispatched commands, once they were sent
+            recv(sent_r) -> sent => {
+                if let Ok((t, command)) = sent {
+                    if let Some(capture) = &mut capture {
+                        let _ = capture.command(t, &command);
+                    }
+                    pipeline.command(t, &command);
+                }
+            },
+            recv(listener_msgs_r) -> msg => match msg {
+                Ok(ListenerMsg::Shutdown) | Err(_) => break,
+                Ok(msg) => pipeline.handle(msg),
+            },
+            default(READ_TIMEOUT) => {},
+        }
+        let t = start_time.elapsed().as_secs_f64();
+        if t - stats_t >= QUEUE_STATS_PERIOD {
+            stats_t = t;
+            read_peak = read_peak.max(frames_r.len());
+            let stats = QueueStats {
+                read_depth: frames_r.len(),
+                read_peak,
+                read_capacity: READ_QUEUE_CAPACITY,
+                dropped_chunks: counters.dropped_frames.load(Ordering::Relaxed),
+                dropped_bytes: counters.dropped_bytes.load(Ordering::Relaxed),
+                command_depth: cmds_r.len(),
+                read_bytes: counters.read_bytes.load(Ordering::Relaxed),
+                dropped_commands: counters.dropped_commands.load(Ordering::Relaxed),
+                reconnects: 0,
+            };
+            pipeline.queue_stats(t, stats);
+        }
+    }
+    stop.store(true, Ordering::Relaxed);
+    let _ = reader.join();
+    let _ = writer.join();
+    if let Some(capture) = &mut capture {
+        capture.flush()?;
+    }
+    pipeline.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Queue `count` frames with ids 0, 1, ... on a queue of 2 that nothing reads
+    fn overflow(policy: OverflowPolicy, count: u32) -> (Vec<u32>, Counters) {
+        let (frames_s, frames_r) = crossbeam_channel::bounded(2);
+        let counters = Arc::new(Counters::default());
+        let mut ids = 0..count;
+        let read = || match ids.next() {
+            Some(id) => Ok(CanFrame { id, len: 8, data: [0; 8] }),
+            None => Err(io::Error::new(io::ErrorKind::BrokenPipe, "bus gone")),
+        };
+        read_frames(read, Instant::now(), policy, frames_s, frames_r.clone(), counters.clone(), Arc::new(AtomicBool::new(false)));
+        let ids = frames_r.try_iter().map(|(_, frame)| frame.id).collect();
+        (ids, Arc::into_inner(counters).unwrap())
+    }
+
+    #[test]
+    fn overflowing_frames_are_counted() {
+        let (ids, counters) = overflow(OverflowPolicy::DropOldest, 5);
+        assert_eq!(ids, [3, 4]);
+        assert_eq!(counters.dropped_frames.into_inner(), 3);
+        assert_eq!(counters.dropped_bytes.into_inner(), 24);
+        assert_eq!(counters.read_bytes.into_inner(), 40);
+
+        let (ids, counters) = overflow(OverflowPolicy::DropNewest, 5);
+        assert_eq!(ids, [0, 1]);
+        assert_eq!(counters.dropped_frames.into_inner(), 3);
+    }
+}
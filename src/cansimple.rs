@@ -0,0 +1,228 @@
+//! ODrive CANSimple protocol: the frames an ODrive exchanges on a CAN bus, and their
+//! mapping onto the channel registry and the firmware's commands. See [`crate::can`] for
+//! the SocketCAN transport.
+//!
+//! Arbitration IDs are `node_id << 5 | cmd_id` with standard 11-bit IDs, and payloads are
+//! little-endian. Each ODrive axis is one node; nodes map to axis channels in the order
+//! given, e.g. with nodes `[3, 4]` the encoder estimates of node 4 become `enc_pos_1`.
+
+use crate::commands::Command;
+use crate::odrive::{ControlMode, AXIS_STATE_CLOSED_LOOP_CONTROL, AXIS_STATE_FULL_CALIBRATION_SEQUENCE, AXIS_STATE_IDLE};
+
+const HEARTBEAT: u32 = 0x001;
+const GET_ERROR: u32 = 0x003;
+const SET_AXIS_STATE: u32 = 0x007;
+const GET_ENCODER_ESTIMATES: u32 = 0x009;
+const SET_CONTROLLER_MODE: u32 = 0x00B;
+const SET_INPUT_POS: u32 = 0x00C;
+const SET_INPUT_VEL: u32 = 0x00D;
+const SET_INPUT_TORQUE: u32 = 0x00E;
+const GET_BUS_VOLTAGE_CURRENT: u32 = 0x017;
+const CLEAR_ERRORS: u32 = 0x018;
+
+// Input mode passing setpoints straight to the controller
+const INPUT_MODE_PASSTHROUGH: u32 = 1;
+
+/// A classic CAN frame with a standard ID.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CanFrame {
+    pub id: u32,
+    pub len: u8,
+    pub data: [u8; 8],
+}
+
+impl CanFrame {
+    pub fn new(id: u32, payload: &[u8]) -> Self {
+        let mut data = [0; 8];
+        data[..payload.len()].copy_from_slice(payload);
+        CanFrame { id, len: payload.len() as u8, data }
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.data[..(self.len as usize).min(8)]
+    }
+}
+
+fn id(node_id: u8, cmd_id: u32) -> u32 {
+    (node_id as u32) << 5 | cmd_id
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn f32_at(data: &[u8], offset: usize) -> Option<f32> {
+    u32_at(data, offset).map(f32::from_bits)
+}
+
+/// Turns frames from the bus into channel samples.
+pub struct Decoder {
+    node_ids: Vec<u8>,
+    // Last reported error of each axis, to report changes
+    errors: Vec<Option<u32>>,
+}
+
+impl Decoder {
+    pub fn new(node_ids: &[u8]) -> Self {
+        Decoder { node_ids: node_ids.to_vec(), errors: vec![None; node_ids.len()] }
+    }
+
+    /// `header:value` lines for the pipeline, as the firmware would send them. Error
+    /// changes also come out as a `dbg_msg` line with the full bitmask, as channel values
+    /// are only f32. Frames from other nodes, and commands, give nothing.
+    pub fn decode(&mut self, frame: &CanFrame) -> Vec<String> {
+        let (node_id, cmd_id) = ((frame.id >> 5) as u8, frame.id & 0x1F);
+        let Some(axis) = self.node_ids.iter().position(|&n| n == node_id) else {
+            return Vec::new();
+        };
+        let data = frame.payload();
+        let mut lines = Vec::new();
+        match cmd_id {
+            HEARTBEAT => {
+                if let (Some(error), Some(&state)) = (u32_at(data, 0), data.get(4)) {
+                    lines.push(format!("axis_state_{}:{}", axis, state));
+                    self.error(axis, error, &mut lines);
+                }
+            }
+            GET_ERROR => {
+                if let Some(error) = u32_at(data, 0) {
+                    self.error(axis, error, &mut lines);
+                }
+            }
+            GET_ENCODER_ESTIMATES => {
+                if let (Some(pos), Some(vel)) = (f32_at(data, 0), f32_at(data, 4)) {
+                    lines.push(format!("enc_pos_{}:{}", axis, pos));
+                    lines.push(format!("enc_vel_{}:{}", axis, vel));
+                }
+            }
+            GET_BUS_VOLTAGE_CURRENT => {
+                if let (Some(voltage), Some(current)) = (f32_at(data, 0), f32_at(data, 4)) {
+                    lines.push(format!("bus_voltage:{}", voltage));
+                    lines.push(format!("bus_current:{}", current));
+                }
+            }
+            _ => {}
+        }
+        lines
+    }
+
+    fn error(&mut self, axis: usize, error: u32, lines: &mut Vec<String>) {
+        lines.push(format!("axis_error_{}:{}", axis, error));
+        if self.errors[axis] != Some(error) {
+            self.errors[axis] = Some(error);
+            lines.push(format!("dbg_msg:node {} axis error 0x{:08X}", self.node_ids[axis], error));
+        }
+    }
+}
+
+/// Turns the firmware's commands into frames for every node. Setpoints go to the input
+/// of the last control mode selected.
+pub struct Encoder {
+    node_ids: Vec<u8>,
+    mode: Option<ControlMode>,
+}
+
+impl Encoder {
+    pub fn new(node_ids: &[u8]) -> Self {
+        Encoder { node_ids: node_ids.to_vec(), mode: None }
+    }
+
+    /// The frames to send for `command`, or why it has no CANSimple equivalent.
+    pub fn encode(&mut self, command: Command) -> Result<Vec<CanFrame>, String> {
+        let mut frames = Vec::new();
+        let mut each = |cmd_id: u32, payload: &[u8]| {
+            for &node_id in &self.node_ids {
+                frames.push(CanFrame::new(id(node_id, cmd_id), payload));
+            }
+        };
+        let axis_state = |state: u32| state.to_le_bytes();
+        match command {
+            Command::CalibrationRoutine => each(SET_AXIS_STATE, &axis_state(AXIS_STATE_FULL_CALIBRATION_SEQUENCE)),
+            // Without identify, i.e. don't blink the LED
+            Command::ClearErrors => each(CLEAR_ERRORS, &[0]),
+            Command::Idle => each(SET_AXIS_STATE, &axis_state(AXIS_STATE_IDLE)),
+            Command::AutoControl => return Err("auto_ctrl is handled by the firmware, not the ODrive".to_string()),
+            Command::Setpoint(sp) => {
+                let input = match self.mode {
+                    Some(ControlMode::Position) => (SET_INPUT_POS, [&sp.to_le_bytes()[..], &[0; 4]].concat()),
+                    Some(ControlMode::Velocity) => (SET_INPUT_VEL, [&sp.to_le_bytes()[..], &[0; 4]].concat()),
+                    Some(ControlMode::Torque) => (SET_INPUT_TORQUE, sp.to_le_bytes().to_vec()),
+                    Some(ControlMode::Voltage) => return Err("voltage setpoints can't be sent over CANSimple".to_string()),
+                    None => return Err("select a control mode before sending a setpoint".to_string()),
+                };
+                each(input.0, &input.1);
+            }
+            // Control modes
+            _ => {}
+        }
+        if let Some(mode) = ControlMode::of(command) {
+            let payload = [(mode as u32).to_le_bytes(), INPUT_MODE_PASSTHROUGH.to_le_bytes()].concat();
+            each(SET_CONTROLLER_MODE, &payload);
+            each(SET_AXIS_STATE, &axis_state(AXIS_STATE_CLOSED_LOOP_CONTROL));
+            self.mode = Some(mode);
+        }
+        Ok(frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(node_id: u8, cmd_id: u32, payload: &[u8]) -> CanFrame {
+        CanFrame::new(id(node_id, cmd_id), payload)
+    }
+
+    #[test]
+    fn encoder_estimates_map_to_axis_channels() {
+        let mut decoder = Decoder::new(&[3, 4]);
+        let payload = [1.5f32.to_le_bytes(), (-0.25f32).to_le_bytes()].concat();
+        assert_eq!(decoder.decode(&frame(4, GET_ENCODER_ESTIMATES, &payload)), ["enc_pos_1:1.5", "enc_vel_1:-0.25"]);
+        assert!(decoder.decode(&frame(5, GET_ENCODER_ESTIMATES, &payload)).is_empty());
+    }
+
+    #[test]
+    fn bus_voltage_and_current() {
+        let mut decoder = Decoder::new(&[0]);
+        let payload = [24.5f32.to_le_bytes(), 1.25f32.to_le_bytes()].concat();
+        assert_eq!(decoder.decode(&frame(0, GET_BUS_VOLTAGE_CURRENT, &payload)), ["bus_voltage:24.5", "bus_current:1.25"]);
+    }
+
+    #[test]
+    fn heartbeat_reports_error_changes_once() {
+        let mut decoder = Decoder::new(&[0]);
+        let heartbeat = frame(0, HEARTBEAT, &[0x00, 0x08, 0x00, 0x00, 8, 0, 0, 0]);
+        assert_eq!(decoder.decode(&heartbeat), ["axis_state_0:8", "axis_error_0:2048", "dbg_msg:node 0 axis error 0x00000800"]);
+        assert_eq!(decoder.decode(&heartbeat), ["axis_state_0:8", "axis_error_0:2048"]);
+    }
+
+    #[test]
+    fn truncated_frames_are_ignored() {
+        let mut decoder = Decoder::new(&[0]);
+        assert!(decoder.decode(&frame(0, GET_ENCODER_ESTIMATES, &[0; 4])).is_empty());
+    }
+
+    #[test]
+    fn control_modes_enter_closed_loop() {
+        let mut encoder = Encoder::new(&[1]);
+        let frames = encoder.encode(Command::VelocityControl).unwrap();
+        assert_eq!(frames, [frame(1, SET_CONTROLLER_MODE, &[2, 0, 0, 0, 1, 0, 0, 0]), frame(1, SET_AXIS_STATE, &[8, 0, 0, 0])]);
+    }
+
+    #[test]
+    fn setpoints_follow_the_control_mode() {
+        let mut encoder = Encoder::new(&[0, 1]);
+        assert!(encoder.encode(Command::Setpoint(1.0)).is_err());
+
+        encoder.encode(Command::PositionControl).unwrap();
+        let frames = encoder.encode(Command::Setpoint(2.0)).unwrap();
+        let payload = [2.0f32.to_le_bytes(), [0; 4]].concat();
+        assert_eq!(frames, [frame(0, SET_INPUT_POS, &payload), frame(1, SET_INPUT_POS, &payload)]);
+
+        encoder.encode(Command::TorqueControl).unwrap();
+        assert_eq!(encoder.encode(Command::Setpoint(0.5)).unwrap()[0], frame(0, SET_INPUT_TORQUE, &0.5f32.to_le_bytes()));
+
+        encoder.encode(Command::VoltageControl).unwrap();
+        assert!(encoder.encode(Command::Setpoint(0.5)).is_err());
+    }
+}
@@ -0,0 +1,60 @@
+//! Raw capture of a session: every received line and dispatched command with its time
+//! offset in seconds, so the session can be exported or replayed later.
+//!
+//! One entry per line, the time and the entry separated by a tab (`\t` below); commands
+//! start with `>`:
+//!
+//! ```text
+//! 0.012345\tbus_voltage:24.10
+//! 0.500000\t>posn_ctrl
+//! ```
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+pub enum CaptureRecord {
+    Line(String),
+    Command(String),
+}
+
+pub struct CaptureWriter {
+    out: BufWriter<File>,
+}
+
+impl CaptureWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(CaptureWriter { out: BufWriter::new(File::create(path)?) })
+    }
+
+    pub fn line(&mut self, t: f64, line: &str) -> io::Result<()> {
+        writeln!(self.out, "{:.6}\t{}", t, line.trim_end())
+    }
+
+    pub fn command(&mut self, t: f64, command: &str) -> io::Result<()> {
+        writeln!(self.out, "{:.6}\t>{}", t, command.trim_end())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+pub fn read_capture(path: &Path) -> io::Result<Vec<(f64, CaptureRecord)>> {
+    let mut records = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let Some((t, rest)) = line.split_once('\t') else {
+            continue;
+        };
+        let Ok(t) = t.parse::<f64>() else {
+            continue;
+        };
+        let record = match rest.strip_prefix('>') {
+            Some(command) => CaptureRecord::Command(command.to_string()),
+            None => CaptureRecord::Line(rest.to_string()),
+        };
+        records.push((t, record));
+    }
+    Ok(records)
+}
@@ -0,0 +1,40 @@
+//! Registry of the scalar channels the firmware streams as `header:value` lines.
+//! Each header maps to the rerun entity path and label it is logged under.
+
+pub struct ChannelDef {
+    pub header: &'static str,
+    pub entity_path: &'static str,
+    pub label: &'static str,
+}
+
+pub const CHANNELS: &[ChannelDef] = &[
+    ChannelDef { header: "bus_voltage", entity_path: "bus/V", label: "Voltage" },
+    ChannelDef { header: "bus_current", entity_path: "bus/I", label: "Current" },
+    ChannelDef { header: "enc_pos_0", entity_path: "encoder_positions/0", label: "Axis 0 position" },
+    ChannelDef { header: "enc_vel_0", entity_path: "encoder_velocities/0", label: "Axis 0 velocity" },
+    ChannelDef { header: "enc_pos_1", entity_path: "encoder_positions/1", label: "Axis 1 position" },
+    ChannelDef { header: "enc_vel_1", entity_path: "encoder_velocities/1", label: "Axis 1 velocity" },
+    ChannelDef { header: "axis_state_0", entity_path: "odrive/axis_state/0", label: "Axis 0 state" },
+    ChannelDef { header: "axis_error_0", entity_path: "odrive/axis_error/0", label: "Axis 0 error" },
+    ChannelDef { header: "axis_state_1", entity_path: "odrive/axis_state/1", label: "Axis 1 state" },
+    ChannelDef { header: "axis_error_1", entity_path: "odrive/axis_error/1", label: "Axis 1 error" },
+    ChannelDef { header: "ctrl_u_0", entity_path: "ctrl_u/0", label: "Axis 0 Control U" },
+    ChannelDef { header: "x", entity_path: "state/x", label: "state_x" },
+    ChannelDef { header: "theta", entity_path: "state/theta", label: "state_theta" },
+    ChannelDef { header: "x_dot", entity_path: "state/x_dot", label: "state_x_dot" },
+    ChannelDef { header: "theta_dot", entity_path: "state/theta_dot", label: "state_theta_dot" },
+    ChannelDef { header: "imu_r", entity_path: "imu/roll", label: "imu_roll" },
+    ChannelDef { header: "imu_p", entity_path: "imu/pitch", label: "imu_pitch" },
+    ChannelDef { header: "imu_p_dot", entity_path: "imu/pitch_dot", label: "imu_pitch_dot" },
+    ChannelDef { header: "imu_y", entity_path: "imu/yaw", label: "imu_yaw" },
+    ChannelDef { header: "acc_x", entity_path: "accelerometer/X", label: "acc_X" },
+    ChannelDef { header: "acc_y", entity_path: "accelerometer/Y", label: "acc_Y" },
+    ChannelDef { header: "acc_z", entity_path: "accelerometer/Z", label: "acc_Z" },
+    ChannelDef { header: "gyr_x", entity_path: "gyro/X", label: "gyro_X" },
+    ChannelDef { header: "gyr_y", entity_path: "gyro/Y", label: "gyro_Y" },
+    ChannelDef { header: "gyr_z", entity_path: "gyro/Z", label: "gyro_Z" },
+];
+
+pub fn lookup(header: &str) -> Option<&'static ChannelDef> {
+    CHANNELS.iter().find(|c| c.header == header)
+}
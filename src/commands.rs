@@ -0,0 +1,117 @@
+//! Commands understood by the firmware, and their wire format.
+//!
+//! Commands are short ASCII strings written to the serial port as-is, e.g. `posn_ctrl` or
+//! `sp:1.2500`. The firmware reads them with a fixed-size DMA transfer, so an encoded
+//! command is at most [`MAX_COMMAND_LEN`] bytes.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crossbeam_channel::{SendError, Sender, TrySendError};
+
+pub const MAX_COMMAND_LEN: usize = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    CalibrationRoutine,
+    ClearErrors,
+    PositionControl,
+    VelocityControl,
+    TorqueControl,
+    VoltageControl,
+    Idle,
+    AutoControl,
+    /// Setpoint of the active controller, sent with 4 decimals and truncated to fit
+    Setpoint(f32),
+}
+
+impl Command {
+    /// Every command but the setpoint
+    pub const NAMED: &'static [Command] = &[
+        Command::CalibrationRoutine,
+        Command::ClearErrors,
+        Command::PositionControl,
+        Command::VelocityControl,
+        Command::TorqueControl,
+        Command::VoltageControl,
+        Command::Idle,
+        Command::AutoControl,
+    ];
+
+    /// The command as sent to the device.
+    pub fn encode(&self) -> String {
+        let s = match self {
+            Command::CalibrationRoutine => "calib_rtn",
+            Command::ClearErrors => "clear_err",
+            Command::PositionControl => "posn_ctrl",
+            Command::VelocityControl => "velo_ctrl",
+            Command::TorqueControl => "torq_ctrl",
+            Command::VoltageControl => "volt_ctrl",
+            Command::Idle => "idle_ctrl",
+            Command::AutoControl => "auto_ctrl",
+            Command::Setpoint(sp) => {
+                let mut s = format!("sp:{:.4}", sp);
+                s.truncate(MAX_COMMAND_LEN);
+                return s;
+            }
+        };
+        s.to_string()
+    }
+
+    /// Parse a command as sent to the device, e.g. from a capture file.
+    pub fn decode(s: &str) -> Option<Command> {
+        let command = match s.trim_end() {
+            "calib_rtn" => Command::CalibrationRoutine,
+            "clear_err" => Command::ClearErrors,
+            "posn_ctrl" => Command::PositionControl,
+            "velo_ctrl" => Command::VelocityControl,
+            "torq_ctrl" => Command::TorqueControl,
+            "volt_ctrl" => Command::VoltageControl,
+            "idle_ctrl" => Command::Idle,
+            "auto_ctrl" => Command::AutoControl,
+            s => Command::Setpoint(s.strip_prefix("sp:")?.trim().parse().ok()?),
+        };
+        Some(command)
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.encode())
+    }
+}
+
+/// The sending end of the queue of commands to dispatch to the serial port, shared by the
+/// command window, scripts and the remote interfaces. Commands dropped because the queue was
+/// full are counted, by every clone together.
+#[derive(Clone)]
+pub struct CommandSender {
+    commands_s: Sender<String>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl CommandSender {
+    pub fn new(commands_s: Sender<String>) -> Self {
+        CommandSender { commands_s, dropped: Arc::new(AtomicU64::new(0)) }
+    }
+
+    /// Queue `command`, waiting for room.
+    pub fn send(&self, command: String) -> Result<(), SendError<String>> {
+        self.commands_s.send(command)
+    }
+
+    /// Queue `command` if there's room, dropping it otherwise.
+    pub fn try_send(&self, command: String) -> Result<(), TrySendError<String>> {
+        let result = self.commands_s.try_send(command);
+        if let Err(TrySendError::Full(_)) = result {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Commands dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,588 @@
+//! Derived channels: user-defined expressions over the incoming channels, evaluated
+//! in the listener pipeline and logged to rerun under `derived/<name>`.
+//!
+//! Definitions are read one per line as `name = expression`, e.g.
+//!
+//! ```text
+//! power      = bus_voltage * bus_current
+//! wheel_diff = enc_vel_0 - enc_vel_1
+//! theta_deg  = deg(theta)
+//! energy_j   = integrate(power)
+//! accel      = differentiate(x_dot)
+//! smooth_i   = moving_average(bus_current, 20)
+//! ```
+//!
+//! Supported: + - * / ^, parentheses, `pi`, `t` (seconds since start), sin, cos, tan,
+//! asin, acos, atan, atan2, sqrt, abs, exp, ln, log10, min, max, deg, rad, integrate,
+//! differentiate and moving_average. A derived channel is re-evaluated whenever one of
+//! its inputs receives a sample, and may reference derived channels defined above it.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use crate::channels;
+
+#[derive(Debug)]
+pub struct ExprError(pub String);
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ExprError {}
+
+#[derive(Debug, Clone, Copy)]
+enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Func {
+    Sin,
+    Cos,
+    Tan,
+    Asin,
+    Acos,
+    Atan,
+    Atan2,
+    Sqrt,
+    Abs,
+    Exp,
+    Ln,
+    Log10,
+    Min,
+    Max,
+    Deg,
+    Rad,
+}
+
+impl Func {
+    fn from_name(name: &str) -> Option<(Func, usize)> {
+        let f = match name {
+            "sin" => (Func::Sin, 1),
+            "cos" => (Func::Cos, 1),
+            "tan" => (Func::Tan, 1),
+            "asin" => (Func::Asin, 1),
+            "acos" => (Func::Acos, 1),
+            "atan" => (Func::Atan, 1),
+            "atan2" => (Func::Atan2, 2),
+            "sqrt" => (Func::Sqrt, 1),
+            "abs" => (Func::Abs, 1),
+            "exp" => (Func::Exp, 1),
+            "ln" => (Func::Ln, 1),
+            "log10" => (Func::Log10, 1),
+            "min" => (Func::Min, 2),
+            "max" => (Func::Max, 2),
+            "deg" => (Func::Deg, 1),
+            "rad" => (Func::Rad, 1),
+            _ => return None,
+        };
+        Some(f)
+    }
+
+    fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            Func::Sin => a.sin(),
+            Func::Cos => a.cos(),
+            Func::Tan => a.tan(),
+            Func::Asin => a.asin(),
+            Func::Acos => a.acos(),
+            Func::Atan => a.atan(),
+            Func::Atan2 => a.atan2(b),
+            Func::Sqrt => a.sqrt(),
+            Func::Abs => a.abs(),
+            Func::Exp => a.exp(),
+            Func::Ln => a.ln(),
+            Func::Log10 => a.log10(),
+            Func::Min => a.min(b),
+            Func::Max => a.max(b),
+            Func::Deg => a.to_degrees(),
+            Func::Rad => a.to_radians(),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Expr {
+    Num(f64),
+    Time,
+    Channel(String),
+    Neg(Box<Expr>),
+    Bin(BinOp, Box<Expr>, Box<Expr>),
+    Call(Func, Vec<Expr>),
+    Integrate { inner: Box<Expr>, acc: f64, prev: Option<(f64, f64)> },
+    Differentiate { inner: Box<Expr>, last: f64, prev: Option<(f64, f64)> },
+    MovingAverage { inner: Box<Expr>, window: usize, samples: VecDeque<f64>, sum: f64 },
+}
+
+impl Expr {
+    fn eval(&mut self, values: &HashMap<String, f64>, t: f64) -> f64 {
+        match self {
+            Expr::Num(n) => *n,
+            Expr::Time => t,
+            Expr::Channel(name) => values.get(name).copied().unwrap_or(f64::NAN),
+            Expr::Neg(e) => -e.eval(values, t),
+            Expr::Bin(op, l, r) => {
+                let (l, r) = (l.eval(values, t), r.eval(values, t));
+                match op {
+                    BinOp::Add => l + r,
+                    BinOp::Sub => l - r,
+                    BinOp::Mul => l * r,
+                    BinOp::Div => l / r,
+                    BinOp::Pow => l.powf(r),
+                }
+            }
+            Expr::Call(func, args) => {
+                let a = args[0].eval(values, t);
+                let b = args.get_mut(1).map_or(0.0, |e| e.eval(values, t));
+                func.apply(a, b)
+            }
+            Expr::Integrate { inner, acc, prev } => {
+                let v = inner.eval(values, t);
+                if let Some((prev_t, prev_v)) = *prev {
+                    *acc += 0.5 * (v + prev_v) * (t - prev_t);
+                }
+                *prev = Some((t, v));
+                *acc
+            }
+            Expr::Differentiate { inner, last, prev } => {
+                let v = inner.eval(values, t);
+                if let Some((prev_t, prev_v)) = *prev {
+                    // Several channels can arrive with the same timestamp; keep the last slope
+                    if t > prev_t {
+                        *last = (v - prev_v) / (t - prev_t);
+                    }
+                } else {
+                    *last = f64::NAN;
+                }
+                *prev = Some((t, v));
+                *last
+            }
+            Expr::MovingAverage { inner, window, samples, sum } => {
+                let v = inner.eval(values, t);
+                // A NaN or infinity would stay in the running sum after leaving the window
+                if v.is_finite() {
+                    samples.push_back(v);
+                    *sum += v;
+                }
+                if samples.len() > *window {
+                    *sum -= samples.pop_front().unwrap_or(0.0);
+                }
+                *sum / samples.len() as f64
+            }
+        }
+    }
+
+    fn collect_channels(&self, out: &mut Vec<String>) {
+        match self {
+            Expr::Channel(name) => {
+                if !out.contains(name) {
+                    out.push(name.clone());
+                }
+            }
+            Expr::Neg(e) => e.collect_channels(out),
+            Expr::Bin(_, l, r) => {
+                l.collect_channels(out);
+                r.collect_channels(out);
+            }
+            Expr::Call(_, args) => args.iter().for_each(|a| a.collect_channels(out)),
+            Expr::Integrate { inner, .. }
+            | Expr::Differentiate { inner, .. }
+            | Expr::MovingAverage { inner, .. } => inner.collect_channels(out),
+            Expr::Num(_) | Expr::Time => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(f64),
+    Ident(String),
+    Op(char),
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>, ExprError> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = src.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            // Exponent, e.g. 1e-3
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                let mut j = i + 1;
+                if j < chars.len() && (chars[j] == '+' || chars[j] == '-') {
+                    j += 1;
+                }
+                if j < chars.len() && chars[j].is_ascii_digit() {
+                    i = j;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+            let text: String = chars[start..i].iter().collect();
+            let n = text.parse::<f64>().map_err(|_| ExprError(format!("invalid number '{}'", text)))?;
+            tokens.push(Token::Num(n));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else if "+-*/^(),".contains(c) {
+            tokens.push(Token::Op(c));
+            i += 1;
+        } else {
+            return Err(ExprError(format!("unexpected character '{}'", c)));
+        }
+    }
+    Ok(tokens)
+}
+
+struct ExprParser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl ExprParser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn eat(&mut self, op: char) -> bool {
+        if self.peek() == Some(&Token::Op(op)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, op: char) -> Result<(), ExprError> {
+        if self.eat(op) {
+            Ok(())
+        } else {
+            Err(ExprError(format!("expected '{}'", op)))
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, ExprError> {
+        let mut lhs = self.term()?;
+        loop {
+            let op = if self.eat('+') {
+                BinOp::Add
+            } else if self.eat('-') {
+                BinOp::Sub
+            } else {
+                return Ok(lhs);
+            };
+            lhs = Expr::Bin(op, Box::new(lhs), Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, ExprError> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = if self.eat('*') {
+                BinOp::Mul
+            } else if self.eat('/') {
+                BinOp::Div
+            } else {
+                return Ok(lhs);
+            };
+            lhs = Expr::Bin(op, Box::new(lhs), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, ExprError> {
+        if self.eat('-') {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        let base = self.atom()?;
+        if self.eat('^') {
+            return Ok(Expr::Bin(BinOp::Pow, Box::new(base), Box::new(self.unary()?)));
+        }
+        Ok(base)
+    }
+
+    fn args(&mut self) -> Result<Vec<Expr>, ExprError> {
+        self.expect('(')?;
+        let mut args = vec![self.expr()?];
+        while self.eat(',') {
+            args.push(self.expr()?);
+        }
+        self.expect(')')?;
+        Ok(args)
+    }
+
+    fn atom(&mut self) -> Result<Expr, ExprError> {
+        match self.next() {
+            Some(Token::Num(n)) => Ok(Expr::Num(n)),
+            Some(Token::Op('(')) => {
+                let e = self.expr()?;
+                self.expect(')')?;
+                Ok(e)
+            }
+            Some(Token::Ident(name)) => {
+                if self.peek() != Some(&Token::Op('(')) {
+                    return Ok(match name.as_str() {
+                        "pi" => Expr::Num(std::f64::consts::PI),
+                        "t" => Expr::Time,
+                        _ => Expr::Channel(name),
+                    });
+                }
+                let mut args = self.args()?;
+                let arity_err = |n: usize| ExprError(format!("{}() takes {} argument(s)", name, n));
+                match name.as_str() {
+                    "integrate" | "differentiate" => {
+                        if args.len() != 1 {
+                            return Err(arity_err(1));
+                        }
+                        let inner = Box::new(args.remove(0));
+                        Ok(if name == "integrate" {
+                            Expr::Integrate { inner, acc: 0.0, prev: None }
+                        } else {
+                            Expr::Differentiate { inner, last: f64::NAN, prev: None }
+                        })
+                    }
+                    "moving_average" => {
+                        let window = match args.as_slice() {
+                            [_, Expr::Num(n)] if *n >= 1.0 && n.fract() == 0.0 => *n as usize,
+                            _ => return Err(ExprError("moving_average(expr, n) needs a whole window size n >= 1".into())),
+                        };
+                        Ok(Expr::MovingAverage {
+                            inner: Box::new(args.remove(0)),
+                            window,
+                            samples: VecDeque::with_capacity(window + 1),
+                            sum: 0.0,
+                        })
+                    }
+                    _ => {
+                        let (func, arity) = Func::from_name(&name)
+                            .ok_or_else(|| ExprError(format!("unknown function '{}'", name)))?;
+                        if args.len() != arity {
+                            return Err(arity_err(arity));
+                        }
+                        Ok(Expr::Call(func, args))
+                    }
+                }
+            }
+            Some(Token::Op(c)) => Err(ExprError(format!("unexpected '{}'", c))),
+            None => Err(ExprError("unexpected end of expression".into())),
+        }
+    }
+}
+
+fn parse_expr(src: &str) -> Result<Expr, ExprError> {
+    let mut parser = ExprParser { tokens: tokenize(src)?, pos: 0 };
+    let expr = parser.expr()?;
+    if parser.pos != parser.tokens.len() {
+        return Err(ExprError("trailing input after expression".into()));
+    }
+    Ok(expr)
+}
+
+pub struct DerivedChannel {
+    pub name: String,
+    pub entity_path: String,
+    expr: Expr,
+    inputs: Vec<String>,
+}
+
+pub struct DerivedChannels {
+    channels: Vec<DerivedChannel>,
+    latest: HashMap<String, f64>,
+}
+
+impl DerivedChannels {
+    pub fn empty() -> Self {
+        DerivedChannels { channels: Vec::new(), latest: HashMap::new() }
+    }
+
+    /// Parse definitions in the `name = expression` format, one per line. `#` starts a comment.
+    pub fn parse(src: &str) -> Result<Self, ExprError> {
+        let mut derived = DerivedChannels::empty();
+        for (line_no, line) in src.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let err = |msg: String| ExprError(format!("line {}: {}", line_no + 1, msg));
+
+            let (name, expr_src) = line.split_once('=').ok_or_else(|| err("expected 'name = expression'".into()))?;
+            let name = name.trim();
+            if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                return Err(err(format!("invalid channel name '{}'", name)));
+            }
+            if channels::lookup(name).is_some() || derived.channels.iter().any(|c| c.name == name) {
+                return Err(err(format!("channel '{}' is already defined", name)));
+            }
+
+            let expr = parse_expr(expr_src).map_err(|e| err(e.0))?;
+            let mut inputs = Vec::new();
+            expr.collect_channels(&mut inputs);
+            if inputs.is_empty() {
+                return Err(err("expression does not reference any channel".into()));
+            }
+            for input in &inputs {
+                if channels::lookup(input).is_none() && !derived.channels.iter().any(|c| &c.name == input) {
+                    return Err(err(format!("unknown channel '{}'", input)));
+                }
+            }
+
+            derived.channels.push(DerivedChannel {
+                name: name.to_string(),
+                entity_path: format!("derived/{}", name),
+                expr,
+                inputs,
+            });
+        }
+        Ok(derived)
+    }
+
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let src = std::fs::read_to_string(path)?;
+        Ok(DerivedChannels::parse(&src)?)
+    }
+
+    pub fn channels(&self) -> &[DerivedChannel] {
+        &self.channels
+    }
+
+    /// Feed a new sample of channel `name` at time `t` (seconds). Every derived channel that
+    /// depends on it is re-evaluated and passed to `emit` with its new value.
+    pub fn update(&mut self, name: &str, value: f64, t: f64, mut emit: impl FnMut(&DerivedChannel, f64)) {
+        if self.channels.is_empty() {
+            return;
+        }
+        self.latest.insert(name.to_string(), value);
+
+        let mut changed = vec![name.to_string()];
+        for channel in &mut self.channels {
+            if !channel.inputs.iter().any(|i| changed.contains(i)) {
+                continue;
+            }
+            if !channel.inputs.iter().all(|i| self.latest.contains_key(i)) {
+                continue;
+            }
+            let v = channel.expr.eval(&self.latest, t);
+            if v.is_finite() {
+                self.latest.insert(channel.name.clone(), v);
+                changed.push(channel.name.clone());
+                emit(channel, v);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feed `samples` in order, returning the values emitted for derived channel `name`
+    fn run(src: &str, name: &str, samples: &[(&str, f64, f64)]) -> Vec<f64> {
+        let mut derived = DerivedChannels::parse(src).unwrap();
+        let mut out = Vec::new();
+        for &(channel, value, t) in samples {
+            derived.update(channel, value, t, |d, v| {
+                if d.name == name {
+                    out.push(v);
+                }
+            });
+        }
+        out
+    }
+
+    fn parse_error(src: &str) -> String {
+        match DerivedChannels::parse(src) {
+            Ok(_) => panic!("'{}' parsed", src),
+            Err(e) => e.0,
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        let eval = |expr: &str| run(&format!("d = {}", expr), "d", &[("theta", 2.0, 0.0)])[0];
+        assert_eq!(eval("1 + theta * 3"), 7.0);
+        assert_eq!(eval("(1 + theta) * 3"), 9.0);
+        assert_eq!(eval("-theta ^ 2"), -4.0);
+        assert_eq!(eval("theta ^ 3 ^ 2"), 512.0);
+        assert_eq!(eval("12 / theta / 3"), 2.0);
+        assert_eq!(eval("10 - theta - 3"), 5.0);
+        assert_eq!(eval("max(theta, 1e1) - deg(pi)"), -170.0);
+    }
+
+    #[test]
+    fn derived_channels_chain_and_wait_for_inputs() {
+        let src = "power = bus_voltage * bus_current\nhalf = power / 2";
+        let samples = [("bus_voltage", 24.0, 0.0), ("bus_current", 2.0, 0.0), ("bus_current", 3.0, 0.1)];
+        assert_eq!(run(src, "power", &samples), [48.0, 72.0]);
+        assert_eq!(run(src, "half", &samples), [24.0, 36.0]);
+    }
+
+    #[test]
+    fn integrate_and_differentiate() {
+        let samples = [("theta", 0.0, 0.0), ("theta", 2.0, 1.0), ("theta", 2.0, 2.0), ("theta", 8.0, 4.0)];
+        assert_eq!(run("i = integrate(theta)", "i", &samples), [0.0, 1.0, 3.0, 13.0]);
+        // No slope from the first sample
+        assert_eq!(run("d = differentiate(theta)", "d", &samples), [2.0, 0.0, 3.0]);
+        assert_eq!(run("m = moving_average(theta, 2)", "m", &samples), [0.0, 1.0, 2.0, 5.0]);
+    }
+
+    #[test]
+    fn moving_average_skips_non_finite_values() {
+        let samples = [("theta", 4.0, 0.0), ("theta", -1.0, 1.0), ("theta", 9.0, 2.0), ("theta", 16.0, 3.0)];
+        // The square root of -1 is left out, the average holds until the next value
+        assert_eq!(run("m = moving_average(sqrt(theta), 2)", "m", &samples), [2.0, 2.0, 2.5, 3.5]);
+        let samples = [("theta", 0.0, 0.0), ("theta", 1.0, 1.0), ("theta", 0.5, 2.0)];
+        assert_eq!(run("m = moving_average(1 / theta, 1)", "m", &samples), [1.0, 2.0]);
+    }
+
+    #[test]
+    fn unknown_channels() {
+        assert!(parse_error("d = theta + bogus").contains("unknown channel 'bogus'"));
+        // Derived channels can only use those defined above them
+        assert!(parse_error("a = b * 2\nb = theta").contains("unknown channel 'b'"));
+        assert!(parse_error("theta = bus_voltage").contains("already defined"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        for (src, error) in [
+            ("d = moving_average(theta, 2.5)", "whole window size"),
+            ("d = moving_average(theta, 0)", "whole window size"),
+            ("d = moving_average(theta, bus_voltage)", "whole window size"),
+            ("d = theta +", "unexpected end"),
+            ("d = (theta", "expected ')'"),
+            ("d = theta theta", "trailing input"),
+            ("d = foo(theta)", "unknown function 'foo'"),
+            ("d = atan2(theta)", "takes 2 argument(s)"),
+            ("d = theta $ 2", "unexpected character '$'"),
+            ("d = 2 * pi", "does not reference any channel"),
+            ("bad name = theta", "invalid channel name"),
+            ("theta * 2", "expected 'name = expression'"),
+        ] {
+            let e = parse_error(src);
+            assert!(e.starts_with("line 1: ") && e.contains(error), "'{}' gave '{}'", src, e);
+        }
+    }
+}
@@ -0,0 +1,265 @@
+//! Host-side checks of the `enc_pos_*`/`enc_vel_*` channels and wheel odometry.
+//! Positions are in turns and velocities in turns/s, as reported by the ODrive.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EncoderConfig {
+    pub wheel_radius: f64,
+    pub track_width: f64,
+    // +1/-1 so that positive turns move the robot forward, per axis
+    pub axis_sign: [f64; 2],
+    // Range after which the reported position wraps around, if it does (e.g. 1.0 turn)
+    pub wrap_range: Option<f64>,
+    // Position steps larger than this between two samples are flagged as glitches, turns
+    pub max_jump: f64,
+}
+
+impl Default for EncoderConfig {
+    fn default() -> Self {
+        EncoderConfig {
+            wheel_radius: 0.05,
+            track_width: 0.3,
+            axis_sign: [1.0, -1.0],
+            wrap_range: None,
+            max_jump: 0.5,
+        }
+    }
+}
+
+// Sent from the GUI to the listener thread
+pub enum EncoderMsg {
+    SetConfig(EncoderConfig),
+    ResetOdometry,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncoderEvent {
+    Wraparound { axis: usize },
+    Glitch { axis: usize, jump: f64 },
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Pose {
+    pub x: f64,
+    pub y: f64,
+    pub heading: f64,
+    pub distance: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct EncoderUpdate {
+    pub axis: usize,
+    // Velocity from differentiating the position, turns/s
+    pub host_velocity: Option<f64>,
+    // Reported velocity minus host velocity
+    pub velocity_residual: Option<f64>,
+    pub event: Option<EncoderEvent>,
+    pub pose: Option<Pose>,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Axis {
+    // Last raw and unwrapped positions
+    raw: Option<f64>,
+    unwrapped: f64,
+    // Time of the last sample at a new timestamp, and the unwrapped position then
+    last_t: f64,
+    last_t_unwrapped: f64,
+    host_velocity: Option<f64>,
+    // Unwrapped position at the last odometry step
+    odom_pos: Option<f64>,
+}
+
+pub struct EncoderMonitor {
+    config: EncoderConfig,
+    axes: [Axis; 2],
+    pose: Pose,
+}
+
+impl EncoderMonitor {
+    pub fn new(config: EncoderConfig) -> Self {
+        EncoderMonitor { config, axes: [Axis::default(); 2], pose: Pose::default() }
+    }
+
+    pub fn handle(&mut self, msg: EncoderMsg) {
+        match msg {
+            EncoderMsg::SetConfig(config) => self.config = config,
+            EncoderMsg::ResetOdometry => {
+                self.pose = Pose::default();
+                for axis in &mut self.axes {
+                    axis.odom_pos = None;
+                }
+            }
+        }
+    }
+
+    /// Feed a channel sample at time `t` (seconds).
+    pub fn update(&mut self, header: &str, value: f64, t: f64) -> Option<EncoderUpdate> {
+        match header {
+            "enc_pos_0" => Some(self.position(0, value, t)),
+            "enc_pos_1" => Some(self.position(1, value, t)),
+            "enc_vel_0" => Some(self.velocity(0, value)),
+            "enc_vel_1" => Some(self.velocity(1, value)),
+            _ => None,
+        }
+    }
+
+    fn velocity(&self, axis: usize, reported: f64) -> EncoderUpdate {
+        EncoderUpdate {
+            axis,
+            velocity_residual: self.axes[axis].host_velocity.map(|v| reported - v),
+            ..Default::default()
+        }
+    }
+
+    fn position(&mut self, index: usize, raw: f64, t: f64) -> EncoderUpdate {
+        let mut update = EncoderUpdate { axis: index, ..Default::default() };
+        let config = self.config;
+        let axis = &mut self.axes[index];
+
+        let Some(prev_raw) = axis.raw.replace(raw) else {
+            axis.unwrapped = raw;
+            axis.last_t = t;
+            axis.last_t_unwrapped = raw;
+            // Odometry starts from the first position of both wheels
+            update.pose = self.odometry();
+            return update;
+        };
+
+        let mut step = raw - prev_raw;
+        if let Some(range) = config.wrap_range {
+            if step.abs() > range / 2.0 {
+                step -= range * step.signum();
+                update.event = Some(EncoderEvent::Wraparound { axis: index });
+            }
+        }
+        if step.abs() > config.max_jump {
+            // Don't let a count jump corrupt the velocity or odometry
+            update.event = Some(EncoderEvent::Glitch { axis: index, jump: step });
+            axis.odom_pos = None;
+            return update;
+        }
+        axis.unwrapped += step;
+
+        // Lines from one serial read share a timestamp, their steps count towards the next one
+        let dt = t - axis.last_t;
+        if dt > 0.0 {
+            axis.host_velocity = Some((axis.unwrapped - axis.last_t_unwrapped) / dt);
+            axis.last_t = t;
+            axis.last_t_unwrapped = axis.unwrapped;
+        }
+        update.host_velocity = axis.host_velocity;
+
+        update.pose = self.odometry();
+        update
+    }
+
+    // Differential drive dead reckoning from the two wheel positions
+    fn odometry(&mut self) -> Option<Pose> {
+        let [left, right] = &mut self.axes;
+        if left.raw.is_none() || right.raw.is_none() {
+            return None;
+        }
+        let (Some(l0), Some(r0)) = (left.odom_pos, right.odom_pos) else {
+            left.odom_pos = Some(left.unwrapped);
+            right.odom_pos = Some(right.unwrapped);
+            return Some(self.pose);
+        };
+        left.odom_pos = Some(left.unwrapped);
+        right.odom_pos = Some(right.unwrapped);
+
+        let turns_to_m = 2.0 * std::f64::consts::PI * self.config.wheel_radius;
+        let dl = (left.unwrapped - l0) * self.config.axis_sign[0] * turns_to_m;
+        let dr = (right.unwrapped - r0) * self.config.axis_sign[1] * turns_to_m;
+        let d = 0.5 * (dl + dr);
+        let d_heading = if self.config.track_width > 0.0 { (dr - dl) / self.config.track_width } else { 0.0 };
+
+        let mid_heading = self.pose.heading + 0.5 * d_heading;
+        self.pose.x += d * mid_heading.cos();
+        self.pose.y += d * mid_heading.sin();
+        self.pose.heading += d_heading;
+        self.pose.distance += d.abs();
+        Some(self.pose)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One turn of the wheel is one meter
+    fn monitor(config: EncoderConfig) -> EncoderMonitor {
+        EncoderMonitor::new(EncoderConfig { wheel_radius: 0.5 / std::f64::consts::PI, ..config })
+    }
+
+    fn position(monitor: &mut EncoderMonitor, axis: usize, raw: f64, t: f64) -> EncoderUpdate {
+        monitor.update(&format!("enc_pos_{}", axis), raw, t).unwrap()
+    }
+
+    // Both wheels stepped by (left, right) turns per sample, returning the last pose
+    fn drive(monitor: &mut EncoderMonitor, steps: usize, left: f64, right: f64) -> Pose {
+        let mut pose = None;
+        for i in 0..=steps {
+            let t = i as f64 * 0.01;
+            position(monitor, 0, i as f64 * left, t);
+            pose = position(monitor, 1, i as f64 * right, t).pose;
+        }
+        pose.unwrap()
+    }
+
+    #[test]
+    fn wraparound_is_unwrapped() {
+        let mut monitor = monitor(EncoderConfig { wrap_range: Some(1.0), ..Default::default() });
+        position(&mut monitor, 0, 0.9, 0.0);
+        let update = position(&mut monitor, 0, 0.1, 0.1);
+        assert_eq!(update.event, Some(EncoderEvent::Wraparound { axis: 0 }));
+        assert!((update.host_velocity.unwrap() - 2.0).abs() < 1e-9);
+        let update = position(&mut monitor, 0, 0.2, 0.2);
+        assert_eq!(update.event, None);
+        assert!((update.host_velocity.unwrap() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn glitches_are_left_out_of_the_velocity() {
+        let mut monitor = monitor(EncoderConfig::default());
+        position(&mut monitor, 0, 0.0, 0.0);
+        assert_eq!(position(&mut monitor, 0, 0.1, 0.1).host_velocity, Some(1.0));
+        let update = position(&mut monitor, 0, 3.1, 0.2);
+        assert!(matches!(update.event, Some(EncoderEvent::Glitch { axis: 0, .. })));
+        assert_eq!(update.host_velocity, None);
+        // The residual is against the last good velocity, not the whole reported one
+        let residual = monitor.update("enc_vel_0", 1.0, 0.2).unwrap().velocity_residual;
+        assert_eq!(residual, Some(0.0));
+    }
+
+    #[test]
+    fn samples_sharing_a_timestamp_count_towards_the_velocity() {
+        let mut monitor = monitor(EncoderConfig::default());
+        position(&mut monitor, 0, 0.0, 0.0);
+        assert_eq!(position(&mut monitor, 0, 0.1, 1.0).host_velocity, Some(0.1));
+        // Same read as the previous sample
+        assert_eq!(position(&mut monitor, 0, 0.2, 1.0).host_velocity, Some(0.1));
+        let velocity = position(&mut monitor, 0, 0.3, 2.0).host_velocity.unwrap();
+        assert!((velocity - 0.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn odometry_on_a_straight_line() {
+        let mut monitor = monitor(EncoderConfig::default());
+        // The right wheel turns the other way to go forward
+        let pose = drive(&mut monitor, 1000, 0.001, -0.001);
+        assert!((pose.x - 1.0).abs() < 1e-3, "{:?}", pose);
+        assert!(pose.y.abs() < 1e-2, "{:?}", pose);
+        assert!(pose.heading.abs() < 1e-9, "{:?}", pose);
+    }
+
+    #[test]
+    fn odometry_turning_in_place() {
+        let mut monitor = monitor(EncoderConfig::default());
+        let pose = drive(&mut monitor, 100, -0.001, -0.001);
+        assert!(pose.x.abs() < 1e-3 && pose.y.abs() < 1e-3, "{:?}", pose);
+        assert!((pose.heading - 0.2 / 0.3).abs() < 1e-9, "{:?}", pose);
+
+        monitor.handle(EncoderMsg::ResetOdometry);
+        assert_eq!(position(&mut monitor, 0, 0.0, 2.0).pose.unwrap().heading, 0.0);
+    }
+}
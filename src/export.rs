@@ -0,0 +1,268 @@
+//! Export of telemetry sessions for analysis outside rerun.
+//!
+//! The output format follows the file extension (.csv, or .parquet when built with the
+//! `parquet` feature). In the long layout every sample is a (time, channel, value) row and
+//! is written as it arrives; the wide layout has one column per channel, resampled to a
+//! common time base with the last known value, and is written when the session ends.
+//! Dispatched commands go to a `<name>_commands.<ext>` file next to the output.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::capture::{read_capture, CaptureRecord};
+use crate::pipeline::Pipeline;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    Long,
+    Wide,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Csv,
+    Parquet,
+}
+
+/// The setpoint carried by an `sp:` command, exported as its own channel.
+pub fn setpoint_from_command(command: &str) -> Option<f64> {
+    command.strip_prefix("sp:")?.trim().parse().ok()
+}
+
+// Channels are parsed as f32; print those without the f64 widening noise
+pub(crate) fn fmt_value(v: f64) -> String {
+    if (v as f32) as f64 == v {
+        (v as f32).to_string()
+    } else {
+        v.to_string()
+    }
+}
+
+pub struct Exporter {
+    path: PathBuf,
+    format: Format,
+    layout: Layout,
+    resample_hz: f64,
+    // Streaming writer for long CSV
+    long_csv: Option<BufWriter<File>>,
+    // Buffered until `finish` for everything else
+    channel_order: Vec<String>,
+    samples: Vec<(f64, usize, f64)>,
+    commands: Vec<(f64, String)>,
+}
+
+impl Exporter {
+    pub fn create(path: &Path, layout: Layout, resample_hz: f64) -> Result<Self, Box<dyn std::error::Error>> {
+        let format = match path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => Format::Csv,
+            Some("parquet") => Format::Parquet,
+            _ => return Err(format!("unsupported export file '{}', use .csv or .parquet", path.display()).into()),
+        };
+        if format == Format::Parquet && !cfg!(feature = "parquet") {
+            return Err("Parquet export needs the `parquet` feature".into());
+        }
+        if layout == Layout::Wide && resample_hz <= 0.0 {
+            return Err("resample rate must be positive".into());
+        }
+
+        let long_csv = if format == Format::Csv && layout == Layout::Long {
+            let mut out = BufWriter::new(File::create(path)?);
+            writeln!(out, "time,channel,value")?;
+            Some(out)
+        } else {
+            None
+        };
+
+        Ok(Exporter {
+            path: path.to_path_buf(),
+            format,
+            layout,
+            resample_hz,
+            long_csv,
+            channel_order: Vec::new(),
+            samples: Vec::new(),
+            commands: Vec::new(),
+        })
+    }
+
+    pub fn sample(&mut self, t: f64, channel: &str, value: f64) {
+        if let Some(out) = &mut self.long_csv {
+            let _ = writeln!(out, "{:.6},{},{}", t, channel, fmt_value(value));
+            return;
+        }
+        let index = match self.channel_order.iter().position(|c| c == channel) {
+            Some(i) => i,
+            None => {
+                self.channel_order.push(channel.to_string());
+                self.channel_order.len() - 1
+            }
+        };
+        self.samples.push((t, index, value));
+    }
+
+    pub fn command(&mut self, t: f64, command: &str) {
+        self.commands.push((t, command.trim_end().to_string()));
+        if let Some(sp) = setpoint_from_command(command) {
+            self.sample(t, "setpoint", sp);
+        }
+    }
+
+    fn commands_path(&self) -> PathBuf {
+        let stem = self.path.file_stem().and_then(|s| s.to_str()).unwrap_or("export");
+        let ext = self.path.extension().and_then(|s| s.to_str()).unwrap_or("csv");
+        self.path.with_file_name(format!("{}_commands.{}", stem, ext))
+    }
+
+    // Rows of the wide layout: time followed by one optional value per channel
+    fn resampled(&self) -> Vec<(f64, Vec<Option<f64>>)> {
+        let mut samples = self.samples.clone();
+        samples.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let (Some(first), Some(last)) = (samples.first(), samples.last()) else {
+            return Vec::new();
+        };
+        let (t0, t_end) = (first.0, last.0);
+        let period = 1.0 / self.resample_hz;
+        // The last row is the first one at or after the last sample, with some slack for
+        // rounding when it falls right on the time base
+        let last_step = ((t_end - t0) * self.resample_hz - 1e-9).ceil().max(0.0) as u64;
+
+        let mut rows = Vec::new();
+        let mut current = vec![None; self.channel_order.len()];
+        let mut next = 0;
+        for step in 0..=last_step {
+            let t = t0 + step as f64 * period;
+            while next < samples.len() && samples[next].0 <= t + 1e-9 {
+                let (_, channel, value) = samples[next];
+                current[channel] = Some(value);
+                next += 1;
+            }
+            rows.push((t, current.clone()));
+        }
+        rows
+    }
+
+    pub fn finish(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(out) = &mut self.long_csv {
+            out.flush()?;
+        }
+        match self.format {
+            Format::Csv => {
+                if self.layout == Layout::Wide {
+                    let mut out = BufWriter::new(File::create(&self.path)?);
+                    writeln!(out, "time,{}", self.channel_order.join(","))?;
+                    for (t, values) in self.resampled() {
+                        let cells: Vec<String> = values.iter().map(|v| v.map_or(String::new(), fmt_value)).collect();
+                        writeln!(out, "{:.6},{}", t, cells.join(","))?;
+                    }
+                    out.flush()?;
+                }
+                let mut out = BufWriter::new(File::create(self.commands_path())?);
+                writeln!(out, "time,command")?;
+                for (t, command) in &self.commands {
+                    writeln!(out, "{:.6},\"{}\"", t, command.replace('"', "\"\""))?;
+                }
+                out.flush()?;
+            }
+            Format::Parquet => {
+                #[cfg(feature = "parquet")]
+                parquet_out::write(&self)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Export a session recorded with `--capture`, replaying it through the same pipeline as a
+/// live session, derived channels and IMU calibration included.
+pub fn export_capture(capture: &Path, output: &Path, layout: Layout, resample_hz: f64) -> Result<(), Box<dyn std::error::Error>> {
+    let exporter = Exporter::create(output, layout, resample_hz)?;
+    let mut pipeline = Pipeline::new(rerun::RecordingStream::disabled(), Some(exporter));
+    for (t, record) in read_capture(capture)? {
+        match record {
+            CaptureRecord::Command(command) => pipeline.command(t, &command),
+            CaptureRecord::Line(line) => pipeline.line(t, line.as_bytes()),
+        }
+    }
+    pipeline.finish()
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_out {
+    use std::fs::File;
+    use std::sync::Arc;
+
+    use arrow::array::{ArrayRef, Float64Array, StringArray};
+    use arrow::datatypes::{Field, Schema};
+    use arrow::record_batch::RecordBatch;
+    use parquet::arrow::ArrowWriter;
+
+    use super::{Exporter, Layout};
+
+    fn write_batch(path: &std::path::Path, columns: Vec<(&str, ArrayRef)>) -> Result<(), Box<dyn std::error::Error>> {
+        let schema = Arc::new(Schema::new(
+            columns
+                .iter()
+                .map(|(name, array)| Field::new(*name, array.data_type().clone(), true))
+                .collect::<Vec<_>>(),
+        ));
+        let batch = RecordBatch::try_new(schema.clone(), columns.into_iter().map(|(_, a)| a).collect())?;
+        let mut writer = ArrowWriter::try_new(File::create(path)?, schema, None)?;
+        writer.write(&batch)?;
+        writer.close()?;
+        Ok(())
+    }
+
+    pub fn write(exporter: &Exporter) -> Result<(), Box<dyn std::error::Error>> {
+        match exporter.layout {
+            Layout::Long => {
+                let time: Float64Array = exporter.samples.iter().map(|s| Some(s.0)).collect();
+                let channel: StringArray = exporter.samples.iter().map(|s| Some(exporter.channel_order[s.1].as_str())).collect();
+                let value: Float64Array = exporter.samples.iter().map(|s| Some(s.2)).collect();
+                write_batch(
+                    &exporter.path,
+                    vec![("time", Arc::new(time)), ("channel", Arc::new(channel)), ("value", Arc::new(value))],
+                )?;
+            }
+            Layout::Wide => {
+                let rows = exporter.resampled();
+                let mut columns: Vec<(&str, ArrayRef)> =
+                    vec![("time", Arc::new(rows.iter().map(|r| Some(r.0)).collect::<Float64Array>()))];
+                for (i, name) in exporter.channel_order.iter().enumerate() {
+                    columns.push((name.as_str(), Arc::new(rows.iter().map(|r| r.1[i]).collect::<Float64Array>())));
+                }
+                write_batch(&exporter.path, columns)?;
+            }
+        }
+
+        let time: Float64Array = exporter.commands.iter().map(|c| Some(c.0)).collect();
+        let command: StringArray = exporter.commands.iter().map(|c| Some(c.1.as_str())).collect();
+        write_batch(&exporter.commands_path(), vec![("time", Arc::new(time)), ("command", Arc::new(command))])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wide(samples: &[(f64, &str, f64)]) -> Vec<(f64, Vec<Option<f64>>)> {
+        let mut exporter = Exporter::create(Path::new("unused.csv"), Layout::Wide, 10.0).unwrap();
+        for &(t, channel, value) in samples {
+            exporter.sample(t, channel, value);
+        }
+        exporter.resampled()
+    }
+
+    #[test]
+    fn resampling_ends_at_the_last_sample() {
+        let rows = wide(&[(0.0, "theta", 1.0), (0.2, "theta", 2.0)]);
+        let times: Vec<f64> = rows.iter().map(|r| (r.0 * 10.0).round() / 10.0).collect();
+        assert_eq!(times, [0.0, 0.1, 0.2]);
+        assert_eq!(rows[2].1, [Some(2.0)]);
+
+        // Off the time base, the last sample shows up in the row after it
+        let rows = wide(&[(0.0, "theta", 1.0), (0.25, "theta", 2.0)]);
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[3].1, [Some(2.0)]);
+    }
+}
@@ -0,0 +1,338 @@
+//! Foxglove WebSocket protocol server (`foxglove.websocket.v1`), so the telemetry can be
+//! viewed in Foxglove Studio next to the rerun viewer. Every registered channel is
+//! advertised as a JSON topic named after its rerun entity path, e.g. `/bus/V` carrying
+//! `{"value": 24.1}`, along with:
+//!
+//! ```text
+//! /state            {"x", "theta", "x_dot", "theta_dot"}, on any update of the state vector
+//! /imu/quaternion   foxglove.Quaternion {"x", "y", "z", "w"}, the firmware's attitude
+//! ```
+//!
+//! Message timestamps are wall-clock times, derived from the session time of each sample.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crossbeam_channel::{Receiver, Sender};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::HeaderValue;
+use tungstenite::{Message, WebSocket};
+
+use crate::channels;
+use crate::pipeline::ListenerMsg;
+
+const SUBPROTOCOL: &str = "foxglove.websocket.v1";
+// Upper bound between messages sent
+const POLL_PERIOD: Duration = Duration::from_millis(20);
+// Samples buffered per channel between polls
+const SUBSCRIPTION_CAPACITY: usize = 1024;
+// Opcode of binary message data
+const MESSAGE_DATA: u8 = 0x01;
+
+const STATE_FIELDS: [&str; 4] = ["x", "theta", "x_dot", "theta_dot"];
+const QUATERNION_FIELDS: [&str; 4] = ["x", "y", "z", "w"];
+
+// The error is large, box it rather than copying it up the stack
+type WsResult<T> = Result<T, Box<tungstenite::Error>>;
+
+// A topic made of one or more pipeline channels, sent as one JSON object
+struct Topic {
+    name: String,
+    schema_name: &'static str,
+    // (field, pipeline channel)
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Topic {
+    fn schema(&self) -> String {
+        let properties: serde_json::Map<String, Value> = self.fields.iter().map(|(field, _)| (field.to_string(), json!({ "type": "number" }))).collect();
+        let required: Vec<&str> = self.fields.iter().map(|(field, _)| *field).collect();
+        json!({ "type": "object", "properties": properties, "required": required }).to_string()
+    }
+}
+
+fn topics() -> Vec<Topic> {
+    let mut topics: Vec<Topic> = channels::CHANNELS
+        .iter()
+        .map(|channel| Topic {
+            name: format!("/{}", channel.entity_path),
+            schema_name: "mission_control.Scalar",
+            fields: vec![("value", channel.header.to_string())],
+        })
+        .collect();
+    topics.push(Topic {
+        name: "/state".to_string(),
+        schema_name: "mission_control.State",
+        fields: STATE_FIELDS.iter().map(|field| (*field, field.to_string())).collect(),
+    });
+    topics.push(Topic {
+        name: "/imu/quaternion".to_string(),
+        schema_name: "foxglove.Quaternion",
+        fields: QUATERNION_FIELDS.iter().map(|field| (*field, format!("quaternion_{}", field))).collect(),
+    });
+    topics
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+enum ClientMsg {
+    Subscribe { subscriptions: Vec<SubscribeTo> },
+    Unsubscribe {
+        #[serde(rename = "subscriptionIds")]
+        subscription_ids: Vec<u32>,
+    },
+}
+
+#[derive(Deserialize)]
+struct SubscribeTo {
+    id: u32,
+    #[serde(rename = "channelId")]
+    channel_id: usize,
+}
+
+struct Subscription {
+    topic: usize,
+    // One per topic field
+    samples_r: Vec<Receiver<(f64, f64)>>,
+    latest: Vec<Option<f64>>,
+}
+
+struct Client {
+    socket: WebSocket<TcpStream>,
+    listener_msgs_s: Sender<ListenerMsg>,
+    topics: Vec<Topic>,
+    subscriptions: HashMap<u32, Subscription>,
+    // Unix time of session time 0
+    epoch: Option<f64>,
+}
+
+impl Client {
+    fn send_json(&mut self, msg: Value) -> WsResult<()> {
+        Ok(self.socket.send(Message::text(msg.to_string()))?)
+    }
+
+    fn status(&mut self, level: u8, message: String) -> WsResult<()> {
+        self.send_json(json!({ "op": "status", "level": level, "message": message }))
+    }
+
+    fn advertise(&mut self) -> WsResult<()> {
+        self.send_json(json!({
+            "op": "serverInfo",
+            "name": "Mission Control",
+            "capabilities": [],
+            "metadata": {},
+        }))?;
+        let channels: Vec<Value> = self
+            .topics
+            .iter()
+            .enumerate()
+            .map(|(id, topic)| {
+                json!({
+                    "id": id,
+                    "topic": topic.name,
+                    "encoding": "json",
+                    "schemaName": topic.schema_name,
+                    "schema": topic.schema(),
+                    "schemaEncoding": "jsonschema",
+                })
+            })
+            .collect();
+        self.send_json(json!({ "op": "advertise", "channels": channels }))
+    }
+
+    fn handle(&mut self, text: &str) -> WsResult<()> {
+        let msg: ClientMsg = match serde_json::from_str(text) {
+            Ok(msg) => msg,
+            // Other ops aren't supported, as advertised by the empty capabilities
+            Err(e) => return self.status(1, format!("ignored message: {}", e)),
+        };
+        match msg {
+            ClientMsg::Subscribe { subscriptions } => {
+                for SubscribeTo { id, channel_id } in subscriptions {
+                    let Some(topic) = self.topics.get(channel_id) else {
+                        self.status(2, format!("unknown channel {}", channel_id))?;
+                        continue;
+                    };
+                    let mut samples_r = Vec::new();
+                    for (_, channel) in &topic.fields {
+                        let (samples_s, r) = crossbeam_channel::bounded(SUBSCRIPTION_CAPACITY);
+                        let _ = self.listener_msgs_s.send(ListenerMsg::Subscribe(channel.clone(), samples_s));
+                        samples_r.push(r);
+                    }
+                    let latest = vec![None; samples_r.len()];
+                    self.subscriptions.insert(id, Subscription { topic: channel_id, samples_r, latest });
+                }
+            }
+            // The pipeline drops a subscription once its receivers are gone
+            ClientMsg::Unsubscribe { subscription_ids } => {
+                for id in subscription_ids {
+                    self.subscriptions.remove(&id);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn send_messages(&mut self) -> WsResult<()> {
+        let mut messages = Vec::new();
+        for (id, subscription) in &mut self.subscriptions {
+            let topic = &self.topics[subscription.topic];
+            let single = subscription.samples_r.len() == 1;
+            let mut updated_t = None;
+            for (i, samples_r) in subscription.samples_r.iter().enumerate() {
+                for (t, v) in samples_r.try_iter() {
+                    subscription.latest[i] = Some(v);
+                    // Scalars send every sample, composite topics their latest state
+                    if single {
+                        messages.push((*id, t, json!({ "value": v })));
+                    } else {
+                        updated_t = Some(updated_t.map_or(t, |u: f64| u.max(t)));
+                    }
+                }
+            }
+            // Composite topics wait until every field has a value
+            if let (Some(t), true) = (updated_t, subscription.latest.iter().all(Option::is_some)) {
+                let fields: serde_json::Map<String, Value> = topic
+                    .fields
+                    .iter()
+                    .zip(&subscription.latest)
+                    .map(|((field, _), v)| (field.to_string(), json!(v)))
+                    .collect();
+                messages.push((*id, t, Value::Object(fields)));
+            }
+        }
+
+        for (id, t, payload) in messages {
+            let epoch = *self.epoch.get_or_insert_with(|| {
+                SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64()) - t
+            });
+            let nanos = ((epoch + t) * 1e9) as u64;
+            let mut data = vec![MESSAGE_DATA];
+            data.extend_from_slice(&id.to_le_bytes());
+            data.extend_from_slice(&nanos.to_le_bytes());
+            data.extend_from_slice(payload.to_string().as_bytes());
+            self.socket.send(Message::binary(data))?;
+        }
+        Ok(())
+    }
+
+    fn run(&mut self) -> WsResult<()> {
+        self.advertise()?;
+        loop {
+            match self.socket.read() {
+                Ok(Message::Text(text)) => self.handle(&text)?,
+                Ok(_) => {}
+                // No message within the read timeout
+                Err(tungstenite::Error::Io(e)) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+                Err(e) => return Err(e.into()),
+            }
+            self.send_messages()?;
+        }
+    }
+}
+
+// Clients only talk to servers that agree on the subprotocol. The error type is tungstenite's.
+#[allow(clippy::result_large_err)]
+fn select_subprotocol(request: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
+    let offered = request.headers().get("Sec-WebSocket-Protocol").and_then(|v| v.to_str().ok()).unwrap_or("");
+    if offered.split(',').any(|p| p.trim() == SUBPROTOCOL) {
+        response.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(SUBPROTOCOL));
+    }
+    Ok(response)
+}
+
+fn serve_client(stream: TcpStream, listener_msgs_s: Sender<ListenerMsg>) {
+    let peer = stream.peer_addr().map_or("?".to_string(), |addr| addr.to_string());
+    let socket = match tungstenite::accept_hdr(stream, select_subprotocol) {
+        Ok(socket) => socket,
+        Err(e) => {
+            println!("Foxglove handshake with {} failed: {}", peer, e);
+            return;
+        }
+    };
+    // Reads time out so messages keep flowing while the client is quiet
+    if let Err(e) = socket.get_ref().set_read_timeout(Some(POLL_PERIOD)) {
+        println!("Foxglove client {}: {}", peer, e);
+        return;
+    }
+    println!("Foxglove client {} connected", peer);
+
+    let mut client = Client { socket, listener_msgs_s, topics: topics(), subscriptions: HashMap::new(), epoch: None };
+    if let Err(e) = client.run() {
+        match *e {
+            tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => println!("Foxglove client {} disconnected", peer),
+            e => println!("Foxglove client {} dropped: {}", peer, e),
+        }
+    }
+}
+
+/// Accept Foxglove clients on `addr`, each served on its own thread.
+pub fn serve(addr: SocketAddr, listener_msgs_s: Sender<ListenerMsg>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    println!("Foxglove server listening on ws://{}", addr);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let listener_msgs_s = listener_msgs_s.clone();
+        thread::spawn(move || serve_client(stream, listener_msgs_s));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tungstenite::client::IntoClientRequest;
+
+    fn read_json(socket: &mut WebSocket<TcpStream>) -> Value {
+        match socket.read().unwrap() {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            msg => panic!("expected a text message, got {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn subscribed_channel_is_sent_as_message_data() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (listener_msgs_s, listener_msgs_r) = crossbeam_channel::unbounded();
+        thread::spawn(move || serve_client(listener.accept().unwrap().0, listener_msgs_s));
+
+        let mut request = format!("ws://{}", addr).into_client_request().unwrap();
+        request.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(SUBPROTOCOL));
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (mut socket, response) = tungstenite::client(request, stream).unwrap();
+        assert_eq!(response.headers()["Sec-WebSocket-Protocol"], SUBPROTOCOL);
+
+        assert_eq!(read_json(&mut socket)["op"], "serverInfo");
+        let advertise = read_json(&mut socket);
+        assert_eq!(advertise["op"], "advertise");
+        let channel = advertise["channels"].as_array().unwrap().iter().find(|c| c["topic"] == "/bus/V").unwrap();
+        let subscribe = json!({ "op": "subscribe", "subscriptions": [{ "id": 7, "channelId": channel["id"] }] });
+        socket.send(Message::text(subscribe.to_string())).unwrap();
+
+        // Stand in for the pipeline
+        let Ok(ListenerMsg::Subscribe(name, samples_s)) = listener_msgs_r.recv_timeout(Duration::from_secs(5)) else {
+            panic!("expected a subscription");
+        };
+        assert_eq!(name, "bus_voltage");
+        samples_s.send((1.5, 24.1)).unwrap();
+
+        let Message::Binary(data) = socket.read().unwrap() else {
+            panic!("expected a binary message");
+        };
+        assert_eq!(data[0], MESSAGE_DATA);
+        assert_eq!(u32::from_le_bytes(data[1..5].try_into().unwrap()), 7);
+        // Wall-clock time of the sample
+        let nanos = u64::from_le_bytes(data[5..13].try_into().unwrap());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
+        assert!(now.abs_diff(nanos) < 5_000_000_000);
+        let payload: Value = serde_json::from_slice(&data[13..]).unwrap();
+        assert_eq!(payload, json!({ "value": 24.1 }));
+    }
+}
@@ -0,0 +1,240 @@
+//! Splits the raw serial byte stream into lines. The buffer is bounded: a line that grows
+//! past the maximum length is discarded up to the next delimiter, after which framing
+//! resumes (e.g. after connecting mid-line or to a device sending binary garbage).
+
+pub const DEFAULT_MAX_LINE_LEN: usize = 256;
+
+// Borrowed from the framer's buffer, valid until the next call
+#[derive(Debug, PartialEq)]
+pub enum Frame<'a> {
+    Line(&'a [u8]),
+    // Start of a line that was too long, the rest is skipped
+    Overflow(&'a [u8]),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delimiter(pub Vec<u8>);
+
+/// Parse a `--delimiter` value: `lf`, `crlf`, or literal text with `\n`, `\r`, `\t` escapes.
+pub fn parse_delimiter(s: &str) -> Result<Delimiter, String> {
+    let delimiter = match s {
+        "lf" => b"\n".to_vec(),
+        "crlf" => b"\r\n".to_vec(),
+        _ => s.replace("\\n", "\n").replace("\\r", "\r").replace("\\t", "\t").into_bytes(),
+    };
+    if delimiter.is_empty() {
+        return Err("delimiter can't be empty".to_string());
+    }
+    Ok(Delimiter(delimiter))
+}
+
+pub struct Framer {
+    delimiter: Vec<u8>,
+    max_len: usize,
+    buf: Vec<u8>,
+    // Bytes before this offset were already handed out
+    start: usize,
+    // Bytes before this offset are known not to start a delimiter
+    scanned: usize,
+    // Skipping the remainder of an oversized line
+    discarding: bool,
+}
+
+impl Framer {
+    pub fn new(delimiter: Vec<u8>, max_len: usize) -> Self {
+        assert!(!delimiter.is_empty(), "empty frame delimiter");
+        Framer {
+            buf: Vec::with_capacity(max_len + delimiter.len()),
+            delimiter,
+            max_len,
+            start: 0,
+            scanned: 0,
+            discarding: false,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        // Only the partial line left over from the last read gets moved
+        if self.start > 0 {
+            self.buf.drain(..self.start);
+            self.scanned -= self.start;
+            self.start = 0;
+        }
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Next complete line without its delimiter, or `None` until more bytes arrive.
+    pub fn next_frame(&mut self) -> Option<Frame<'_>> {
+        let dlen = self.delimiter.len();
+        loop {
+            if let Some(i) = find(&self.buf[self.scanned..], &self.delimiter) {
+                let (start, end) = (self.start, self.scanned + i);
+                self.start = end + dlen;
+                self.scanned = self.start;
+                if self.discarding {
+                    // Tail of an oversized line, resync on the next one
+                    self.discarding = false;
+                    continue;
+                }
+                let line = &self.buf[start..end];
+                if line.len() > self.max_len {
+                    return Some(Frame::Overflow(line));
+                }
+                return Some(Frame::Line(line));
+            }
+
+            // The last bytes could be the start of a delimiter split across reads
+            let keep = (dlen - 1).min(self.buf.len() - self.start);
+            self.scanned = self.buf.len() - keep;
+            if self.buffered() > self.max_len {
+                let start = self.start;
+                self.start = self.scanned;
+                if !self.discarding {
+                    self.discarding = true;
+                    return Some(Frame::Overflow(&self.buf[start..self.scanned]));
+                }
+            }
+            return None;
+        }
+    }
+
+    /// Forget the partial line held, after bytes following it were lost. The bytes pushed
+    /// next are skipped up to the next delimiter, as they end some other line.
+    pub fn resync(&mut self) {
+        self.start = self.buf.len();
+        self.scanned = self.buf.len();
+        self.discarding = true;
+    }
+
+    /// Bytes currently held waiting for a delimiter
+    pub fn buffered(&self) -> usize {
+        self.buf.len() - self.start
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if let [byte] = needle {
+        return haystack.iter().position(|c| c == byte);
+    }
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Owned {
+        Line(Vec<u8>),
+        Overflow(Vec<u8>),
+    }
+
+    fn frames(framer: &mut Framer) -> Vec<Owned> {
+        let mut frames = Vec::new();
+        while let Some(frame) = framer.next_frame() {
+            frames.push(match frame {
+                Frame::Line(line) => Owned::Line(line.to_vec()),
+                Frame::Overflow(start) => Owned::Overflow(start.to_vec()),
+            });
+        }
+        frames
+    }
+
+    fn line(s: &str) -> Owned {
+        Owned::Line(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn coalesced_lines_in_one_read() {
+        let mut framer = Framer::new(b"\n".to_vec(), 64);
+        framer.push(b"bus_voltage:24.1\nbus_current:1.5\nx:0.2");
+        assert_eq!(frames(&mut framer), vec![line("bus_voltage:24.1"), line("bus_current:1.5")]);
+        assert_eq!(framer.buffered(), 5);
+        framer.push(b"\n");
+        assert_eq!(frames(&mut framer), vec![line("x:0.2")]);
+    }
+
+    #[test]
+    fn no_line_spliced_across_a_dropped_chunk() {
+        let mut framer = Framer::new(b"\n".to_vec(), 64);
+        // `2.5\nenc_pos_1:0.` was dropped in between
+        framer.push(b"x:0.1\nenc_pos_0:1");
+        assert_eq!(frames(&mut framer), vec![line("x:0.1")]);
+        framer.resync();
+        framer.push(b"7\nenc_pos_0:3\n");
+        assert_eq!(frames(&mut framer), vec![line("enc_pos_0:3")]);
+        assert_eq!(framer.buffered(), 0);
+    }
+
+    #[test]
+    fn line_split_across_reads() {
+        let mut framer = Framer::new(b"\n".to_vec(), 64);
+        for chunk in [&b"enc_"[..], b"pos_0", b":1.2", b"5\nenc"] {
+            framer.push(chunk);
+        }
+        assert_eq!(frames(&mut framer), vec![line("enc_pos_0:1.25")]);
+        framer.push(b"_vel_0:3\n");
+        assert_eq!(frames(&mut framer), vec![line("enc_vel_0:3")]);
+    }
+
+    #[test]
+    fn crlf_split_between_cr_and_lf() {
+        let mut framer = Framer::new(b"\r\n".to_vec(), 64);
+        framer.push(b"a:1\r");
+        assert_eq!(frames(&mut framer), vec![]);
+        framer.push(b"\nb:2\r\nc:3\r");
+        assert_eq!(frames(&mut framer), vec![line("a:1"), line("b:2")]);
+        framer.push(b"\n");
+        assert_eq!(frames(&mut framer), vec![line("c:3")]);
+    }
+
+    #[test]
+    fn custom_delimiter() {
+        let mut framer = Framer::new(parse_delimiter(";").unwrap().0, 64);
+        framer.push(b"a:1;b:2\n;");
+        assert_eq!(frames(&mut framer), vec![line("a:1"), line("b:2\n")]);
+    }
+
+    #[test]
+    fn overflow_discards_until_next_delimiter() {
+        let mut framer = Framer::new(b"\n".to_vec(), 8);
+        framer.push(b"garbagegarbage");
+        assert_eq!(frames(&mut framer), vec![Owned::Overflow(b"garbagegarbage".to_vec())]);
+        assert_eq!(framer.buffered(), 0);
+
+        // Still inside the oversized line: dropped without reporting it again
+        framer.push(b"more garbage");
+        assert_eq!(frames(&mut framer), vec![]);
+        assert!(framer.buffered() <= 8 + 12);
+
+        framer.push(b"tail\nx:1\n");
+        assert_eq!(frames(&mut framer), vec![line("x:1")]);
+    }
+
+    #[test]
+    fn oversized_complete_line() {
+        let mut framer = Framer::new(b"\n".to_vec(), 4);
+        framer.push(b"toolong\nab:1\n");
+        assert_eq!(frames(&mut framer), vec![Owned::Overflow(b"toolong".to_vec()), line("ab:1")]);
+    }
+
+    #[test]
+    fn buffer_stays_bounded_without_delimiters() {
+        let mut framer = Framer::new(b"\r\n".to_vec(), 16);
+        for _ in 0..1000 {
+            framer.push(&[0xAA; 7]);
+            frames(&mut framer);
+            assert!(framer.buffered() <= 16 + 7);
+        }
+        framer.push(b"\r\nok:1\r\n");
+        assert_eq!(frames(&mut framer), vec![line("ok:1")]);
+    }
+
+    #[test]
+    fn delimiter_values() {
+        assert_eq!(parse_delimiter("lf").unwrap().0, b"\n");
+        assert_eq!(parse_delimiter("crlf").unwrap().0, b"\r\n");
+        assert_eq!(parse_delimiter("\\r").unwrap().0, b"\r");
+        assert!(parse_delimiter("").is_err());
+    }
+}
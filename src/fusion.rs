@@ -0,0 +1,355 @@
+//! Host-side attitude estimation from the raw `acc_*` and `gyr_*` channels, used to
+//! validate the firmware's own estimate (`imu_r`, `imu_p` and the quaternion).
+
+use std::f64::consts::{PI, TAU};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterKind {
+    Madgwick { beta: f64 },
+    Mahony { kp: f64, ki: f64 },
+    Complementary { alpha: f64 },
+}
+
+impl FilterKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FilterKind::Madgwick { .. } => "Madgwick",
+            FilterKind::Mahony { .. } => "Mahony",
+            FilterKind::Complementary { .. } => "Complementary",
+        }
+    }
+
+    pub const DEFAULTS: [FilterKind; 3] = [
+        FilterKind::Madgwick { beta: 0.1 },
+        FilterKind::Mahony { kp: 1.0, ki: 0.0 },
+        FilterKind::Complementary { alpha: 0.98 },
+    ];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FusionConfig {
+    pub filter: FilterKind,
+    // Units the firmware uses for `gyr_*` and for `imu_r`/`imu_p`
+    pub gyro_in_degrees: bool,
+    pub angles_in_degrees: bool,
+}
+
+impl Default for FusionConfig {
+    fn default() -> Self {
+        FusionConfig {
+            filter: FilterKind::Madgwick { beta: 0.1 },
+            gyro_in_degrees: true,
+            angles_in_degrees: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FusionOutput {
+    // w, x, y, z
+    pub quaternion: [f64; 4],
+    // Degrees
+    pub roll: f64,
+    pub pitch: f64,
+    pub yaw: f64,
+    // Host estimate minus firmware estimate, degrees. None until the firmware angle is seen.
+    pub roll_residual: Option<f64>,
+    pub pitch_residual: Option<f64>,
+}
+
+// One reading of all six raw IMU axes, in the firmware's units
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ImuSample {
+    pub acc: [f64; 3],
+    pub gyr: [f64; 3],
+}
+
+// The axes arrive as separate channels; collects them into whole samples
+#[derive(Default)]
+pub struct ImuAssembler {
+    sample: ImuSample,
+    received: u8,
+}
+
+impl ImuAssembler {
+    /// Returns a sample once every axis has been received since the previous one.
+    pub fn update(&mut self, header: &str, value: f64) -> Option<ImuSample> {
+        let (slot, bit) = match header {
+            "acc_x" => (&mut self.sample.acc[0], 0),
+            "acc_y" => (&mut self.sample.acc[1], 1),
+            "acc_z" => (&mut self.sample.acc[2], 2),
+            "gyr_x" => (&mut self.sample.gyr[0], 3),
+            "gyr_y" => (&mut self.sample.gyr[1], 4),
+            "gyr_z" => (&mut self.sample.gyr[2], 5),
+            _ => return None,
+        };
+        *slot = value;
+        self.received |= 1 << bit;
+        if self.received != 0b11_1111 {
+            return None;
+        }
+        self.received = 0;
+        Some(self.sample)
+    }
+}
+
+pub struct ImuFusion {
+    config: FusionConfig,
+    assembler: ImuAssembler,
+    acc: [f64; 3],
+    gyr: [f64; 3],
+    q: [f64; 4],
+    // Mahony integral feedback
+    integral: [f64; 3],
+    last_t: Option<f64>,
+    fw_roll: Option<f64>,
+    fw_pitch: Option<f64>,
+}
+
+impl ImuFusion {
+    pub fn new(config: FusionConfig) -> Self {
+        ImuFusion {
+            config,
+            assembler: ImuAssembler::default(),
+            acc: [0.0; 3],
+            gyr: [0.0; 3],
+            q: [1.0, 0.0, 0.0, 0.0],
+            integral: [0.0; 3],
+            last_t: None,
+            fw_roll: None,
+            fw_pitch: None,
+        }
+    }
+
+    pub fn set_config(&mut self, config: FusionConfig) {
+        if config.filter != self.config.filter {
+            self.integral = [0.0; 3];
+        }
+        self.config = config;
+    }
+
+    /// Feed a channel sample at time `t` (seconds). A new estimate is produced once a full
+    /// set of accelerometer and gyro axes has arrived since the previous one.
+    pub fn update(&mut self, header: &str, value: f64, t: f64) -> Option<FusionOutput> {
+        let angle = if self.config.angles_in_degrees { value } else { value.to_degrees() };
+        match header {
+            "imu_r" => self.fw_roll = Some(angle),
+            "imu_p" => self.fw_pitch = Some(angle),
+            _ => {}
+        }
+        let sample = self.assembler.update(header, value)?;
+        self.acc = sample.acc;
+        self.gyr = if self.config.gyro_in_degrees { sample.gyr.map(f64::to_radians) } else { sample.gyr };
+
+        let Some(last_t) = self.last_t.replace(t) else {
+            // First full sample: start from the attitude the accelerometer alone gives
+            let (roll, pitch) = accel_roll_pitch(self.acc);
+            self.q = quaternion_from_euler(roll, pitch, 0.0);
+            return Some(self.output());
+        };
+        let dt = t - last_t;
+        if dt <= 0.0 {
+            return None;
+        }
+
+        match self.config.filter {
+            FilterKind::Madgwick { beta } => self.madgwick_step(beta, dt),
+            FilterKind::Mahony { kp, ki } => self.mahony_step(kp, ki, dt),
+            FilterKind::Complementary { alpha } => self.complementary_step(alpha, dt),
+        }
+        Some(self.output())
+    }
+
+    fn output(&self) -> FusionOutput {
+        let (roll, pitch, yaw) = euler_from_quaternion(self.q);
+        let (roll, pitch, yaw) = (roll.to_degrees(), pitch.to_degrees(), yaw.to_degrees());
+        FusionOutput {
+            quaternion: self.q,
+            roll,
+            pitch,
+            yaw,
+            roll_residual: self.fw_roll.map(|fw| wrap_degrees(roll - fw)),
+            pitch_residual: self.fw_pitch.map(|fw| wrap_degrees(pitch - fw)),
+        }
+    }
+
+    fn madgwick_step(&mut self, beta: f64, dt: f64) {
+        let [q0, q1, q2, q3] = self.q;
+        let [gx, gy, gz] = self.gyr;
+
+        // Rate of change of quaternion from gyroscope
+        let mut q_dot = [
+            0.5 * (-q1 * gx - q2 * gy - q3 * gz),
+            0.5 * (q0 * gx + q2 * gz - q3 * gy),
+            0.5 * (q0 * gy - q1 * gz + q3 * gx),
+            0.5 * (q0 * gz + q1 * gy - q2 * gx),
+        ];
+
+        if let Some([ax, ay, az]) = normalized(self.acc) {
+            // Gradient descent corrective step
+            let f = [
+                2.0 * (q1 * q3 - q0 * q2) - ax,
+                2.0 * (q0 * q1 + q2 * q3) - ay,
+                2.0 * (0.5 - q1 * q1 - q2 * q2) - az,
+            ];
+            let step = [
+                -2.0 * q2 * f[0] + 2.0 * q1 * f[1],
+                2.0 * q3 * f[0] + 2.0 * q0 * f[1] - 4.0 * q1 * f[2],
+                -2.0 * q0 * f[0] + 2.0 * q3 * f[1] - 4.0 * q2 * f[2],
+                2.0 * q1 * f[0] + 2.0 * q2 * f[1],
+            ];
+            if let Some(step) = normalized(step) {
+                for i in 0..4 {
+                    q_dot[i] -= beta * step[i];
+                }
+            }
+        }
+
+        let q = [q0 + q_dot[0] * dt, q1 + q_dot[1] * dt, q2 + q_dot[2] * dt, q3 + q_dot[3] * dt];
+        self.q = normalized(q).unwrap_or([1.0, 0.0, 0.0, 0.0]);
+    }
+
+    fn mahony_step(&mut self, kp: f64, ki: f64, dt: f64) {
+        let [q0, q1, q2, q3] = self.q;
+        let [mut gx, mut gy, mut gz] = self.gyr;
+
+        if let Some([ax, ay, az]) = normalized(self.acc) {
+            // Estimated direction of gravity
+            let vx = 2.0 * (q1 * q3 - q0 * q2);
+            let vy = 2.0 * (q0 * q1 + q2 * q3);
+            let vz = q0 * q0 - q1 * q1 - q2 * q2 + q3 * q3;
+            // Error is the cross product between measured and estimated gravity
+            let e = [ay * vz - az * vy, az * vx - ax * vz, ax * vy - ay * vx];
+            for (integral, e) in self.integral.iter_mut().zip(e) {
+                *integral += ki * e * dt;
+            }
+            gx += kp * e[0] + self.integral[0];
+            gy += kp * e[1] + self.integral[1];
+            gz += kp * e[2] + self.integral[2];
+        }
+
+        let q = [
+            q0 + 0.5 * (-q1 * gx - q2 * gy - q3 * gz) * dt,
+            q1 + 0.5 * (q0 * gx + q2 * gz - q3 * gy) * dt,
+            q2 + 0.5 * (q0 * gy - q1 * gz + q3 * gx) * dt,
+            q3 + 0.5 * (q0 * gz + q1 * gy - q2 * gx) * dt,
+        ];
+        self.q = normalized(q).unwrap_or([1.0, 0.0, 0.0, 0.0]);
+    }
+
+    fn complementary_step(&mut self, alpha: f64, dt: f64) {
+        let (roll, pitch, yaw) = euler_from_quaternion(self.q);
+        let [gx, gy, gz] = self.gyr;
+        let (acc_roll, acc_pitch) = accel_roll_pitch(self.acc);
+
+        // Blend the difference, so estimates either side of ±180° don't average to 0
+        let (roll, pitch) = (roll + gx * dt, pitch + gy * dt);
+        let roll = roll + (1.0 - alpha) * wrap_radians(acc_roll - roll);
+        let pitch = pitch + (1.0 - alpha) * wrap_radians(acc_pitch - pitch);
+        let yaw = yaw + gz * dt;
+        self.q = quaternion_from_euler(roll, pitch, yaw);
+    }
+}
+
+fn normalized<const N: usize>(v: [f64; N]) -> Option<[f64; N]> {
+    let norm = v.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if norm == 0.0 || !norm.is_finite() {
+        return None;
+    }
+    Some(v.map(|x| x / norm))
+}
+
+fn wrap_degrees(a: f64) -> f64 {
+    (a + 180.0).rem_euclid(360.0) - 180.0
+}
+
+fn wrap_radians(a: f64) -> f64 {
+    (a + PI).rem_euclid(TAU) - PI
+}
+
+fn accel_roll_pitch([ax, ay, az]: [f64; 3]) -> (f64, f64) {
+    let roll = ay.atan2(az);
+    let pitch = (-ax).atan2((ay * ay + az * az).sqrt());
+    (roll, pitch)
+}
+
+fn quaternion_from_euler(roll: f64, pitch: f64, yaw: f64) -> [f64; 4] {
+    let (sr, cr) = (roll * 0.5).sin_cos();
+    let (sp, cp) = (pitch * 0.5).sin_cos();
+    let (sy, cy) = (yaw * 0.5).sin_cos();
+    [
+        cr * cp * cy + sr * sp * sy,
+        sr * cp * cy - cr * sp * sy,
+        cr * sp * cy + sr * cp * sy,
+        cr * cp * sy - sr * sp * cy,
+    ]
+}
+
+fn euler_from_quaternion([w, x, y, z]: [f64; 4]) -> (f64, f64, f64) {
+    let roll = (2.0 * (w * x + y * z)).atan2(1.0 - 2.0 * (x * x + y * y));
+    let pitch = (2.0 * (w * y - z * x)).clamp(-1.0, 1.0).asin();
+    let yaw = (2.0 * (w * z + x * y)).atan2(1.0 - 2.0 * (y * y + z * z));
+    (roll, pitch, yaw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f64 = 0.01;
+
+    // Gravity seen by an accelerometer rolled by `roll` degrees, in g
+    fn rolled(roll: f64) -> [f64; 3] {
+        let (s, c) = roll.to_radians().sin_cos();
+        [0.0, s, c]
+    }
+
+    fn feed(fusion: &mut ImuFusion, acc: [f64; 3], gyr: [f64; 3], t: f64) -> Option<FusionOutput> {
+        let mut output = None;
+        for (header, value) in ["acc_x", "acc_y", "acc_z", "gyr_x", "gyr_y", "gyr_z"].into_iter().zip(acc.into_iter().chain(gyr)) {
+            output = fusion.update(header, value, t);
+        }
+        output
+    }
+
+    // Run `seconds` of samples after a first one at `start`, returning the last estimate
+    fn run(filter: FilterKind, start: [f64; 3], acc: [f64; 3], gyr: [f64; 3], seconds: f64) -> FusionOutput {
+        let mut fusion = ImuFusion::new(FusionConfig { filter, ..Default::default() });
+        let mut output = feed(&mut fusion, start, [0.0; 3], 0.0).unwrap();
+        for i in 1..=(seconds / DT).round() as usize {
+            output = feed(&mut fusion, acc, gyr, i as f64 * DT).unwrap();
+        }
+        output
+    }
+
+    #[test]
+    fn converges_to_level_at_rest() {
+        for filter in FilterKind::DEFAULTS {
+            let output = run(filter, rolled(30.0), rolled(0.0), [0.0; 3], 30.0);
+            assert!(output.roll.abs() < 1.0 && output.pitch.abs() < 1.0, "{}: {:?}", filter.name(), output);
+        }
+    }
+
+    #[test]
+    fn integrates_a_constant_rate() {
+        // Yaw isn't corrected by the accelerometer, so it follows the gyro alone
+        for filter in FilterKind::DEFAULTS {
+            let output = run(filter, rolled(0.0), rolled(0.0), [0.0, 0.0, 10.0], 2.0);
+            assert!((output.yaw - 20.0).abs() < 0.1, "{}: {:?}", filter.name(), output);
+            assert!(output.roll.abs() < 0.1 && output.pitch.abs() < 0.1, "{}: {:?}", filter.name(), output);
+        }
+    }
+
+    #[test]
+    fn complementary_holds_an_upside_down_roll() {
+        // Started at 179°, the accelerometer then says -179°: 2° away across ±180°, not 358°
+        let mut fusion = ImuFusion::new(FusionConfig { filter: FilterKind::Complementary { alpha: 0.98 }, ..Default::default() });
+        feed(&mut fusion, rolled(179.0), [0.0; 3], 0.0);
+        for i in 1..=500 {
+            let output = feed(&mut fusion, rolled(-179.0), [0.0; 3], i as f64 * DT).unwrap();
+            assert!(output.roll.abs() > 178.0, "{:?}", output);
+        }
+        let output = feed(&mut fusion, rolled(-179.0), [0.0; 3], 5.01).unwrap();
+        assert!((output.roll + 179.0).abs() < 0.1, "{:?}", output);
+    }
+}
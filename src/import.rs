@@ -0,0 +1,235 @@
+//! Offline import of logs recorded without a live connection (e.g. to an SD card), fed
+//! through the same pipeline as a live session so the data looks identical in rerun.
+//!
+//! CSV files need a header row. Columns named like a channel header (`bus_voltage`,
+//! `enc_pos_0`, ...) are used as-is, others can be mapped with `--map column=header`.
+//! A `time,channel,value` file as written by `--export` is read as well. Any other file
+//! is read as `header:value` lines, optionally prefixed with a capture timestamp.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use crate::channels;
+use crate::parser::Parser;
+use crate::pipeline::Pipeline;
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ImportOpts {
+    pub file: PathBuf,
+
+    /// Map a CSV column to a channel header, e.g. `--map vbat=bus_voltage`
+    #[arg(long = "map", value_name = "COLUMN=HEADER", value_parser = parse_mapping)]
+    pub mappings: Vec<(String, String)>,
+
+    /// CSV column, or header in a text dump, holding the sample time
+    #[arg(long, default_value = "time")]
+    pub time_column: String,
+
+    /// Factor converting the time column to seconds, e.g. 0.001 for milliseconds
+    #[arg(long, default_value_t = 1.0)]
+    pub time_scale: f64,
+
+    /// Rows or lines per second, used when the file has no time column
+    #[arg(long, default_value_t = 100.0)]
+    pub line_rate: f64,
+}
+
+fn parse_mapping(s: &str) -> Result<(String, String), String> {
+    let (column, header) = s.split_once('=').ok_or("expected COLUMN=HEADER")?;
+    if channels::lookup(header.trim()).is_none() {
+        return Err(format!("unknown channel header '{}'", header.trim()));
+    }
+    Ok((column.trim().to_string(), header.trim().to_string()))
+}
+
+pub fn import_file(opts: &ImportOpts, pipeline: &mut Pipeline) -> Result<(), Box<dyn std::error::Error>> {
+    let is_csv = opts.file.extension().is_some_and(|e| e.eq_ignore_ascii_case("csv"));
+    let count = if is_csv { import_csv(&opts.file, opts, pipeline)? } else { import_lines(&opts.file, opts, pipeline)? };
+    println!("Imported {} samples from {}", count, opts.file.display());
+    let errors = pipeline.parse_stats().errors();
+    if errors > 0 {
+        println!("{} line(s) failed to parse", errors);
+    }
+    pipeline.finish()
+}
+
+fn import_csv(path: &Path, opts: &ImportOpts, pipeline: &mut Pipeline) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    let header_row = lines.next().ok_or("empty CSV file")??;
+    let columns: Vec<String> = header_row.split(',').map(|c| c.trim().trim_matches('"').to_string()).collect();
+    let time_index = columns.iter().position(|c| *c == opts.time_column);
+    let long_format = columns == ["time", "channel", "value"];
+
+    // Column index -> channel header
+    let mappings: HashMap<&str, &str> = opts.mappings.iter().map(|(c, h)| (c.as_str(), h.as_str())).collect();
+    let mut targets: Vec<(usize, &str)> = Vec::new();
+    if !long_format {
+        for (i, column) in columns.iter().enumerate() {
+            if Some(i) == time_index {
+                continue;
+            }
+            if let Some(header) = mappings.get(column.as_str()) {
+                targets.push((i, header));
+            } else if let Some(channel) = channels::lookup(column) {
+                targets.push((i, channel.header));
+            } else {
+                println!("Ignoring unmapped column '{}'", column);
+            }
+        }
+    }
+
+    let mut count = 0;
+    for (row, line) in lines.enumerate() {
+        let line = line?;
+        let cells: Vec<&str> = line.split(',').map(|c| c.trim()).collect();
+        let t = match time_index.and_then(|i| cells.get(i)) {
+            Some(cell) => match cell.parse::<f64>() {
+                Ok(t) => t * opts.time_scale,
+                Err(_) => continue,
+            },
+            None => row as f64 / opts.line_rate,
+        };
+
+        if long_format {
+            if let [_, channel, value] = cells[..] {
+                // Derived channels are recomputed rather than imported
+                let header = mappings.get(channel).copied().unwrap_or(channel);
+                if channels::lookup(header).is_some() {
+                    pipeline.message(t, header, value.as_bytes());
+                    count += 1;
+                }
+            }
+            continue;
+        }
+        for &(i, header) in &targets {
+            if let Some(cell) = cells.get(i).filter(|c| !c.is_empty()) {
+                pipeline.message(t, header, cell.as_bytes());
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+fn import_lines(path: &Path, opts: &ImportOpts, pipeline: &mut Pipeline) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut count = 0;
+    let mut t = 0.0;
+    let mut has_time_header = false;
+    // Read as bytes so that corrupted lines are counted by the parser rather than aborting
+    for (index, line) in BufReader::new(File::open(path)?).split(b'\n').enumerate() {
+        let line = line?;
+        let line = line.strip_suffix(b"\r").unwrap_or(&line);
+
+        // Lines from a `--capture` file carry their own timestamp
+        let timestamp = line
+            .iter()
+            .position(|&c| c == b'\t')
+            .and_then(|i| Some((std::str::from_utf8(&line[..i]).ok()?.parse::<f64>().ok()?, &line[i + 1..])));
+        let message = match timestamp {
+            Some((ts, rest)) => {
+                t = ts;
+                if let Some(command) = rest.strip_prefix(b">") {
+                    pipeline.command(t, &String::from_utf8_lossy(command));
+                    continue;
+                }
+                rest
+            }
+            None => {
+                if !has_time_header {
+                    t = index as f64 / opts.line_rate;
+                }
+                line
+            }
+        };
+        if message.is_empty() {
+            continue;
+        }
+
+        match Parser::split_line(message) {
+            Ok((header, data)) if header == opts.time_column => {
+                // Not parse_float: an f32 already rounds micros() timestamps after 17 seconds
+                if let Some(time) = std::str::from_utf8(data).ok().and_then(|s| s.trim().parse::<f64>().ok()) {
+                    t = time * opts.time_scale;
+                    has_time_header = true;
+                }
+            }
+            Ok((header, data)) => {
+                pipeline.message(t, header, data);
+                count += 1;
+            }
+            Err(_) => pipeline.line(t, message),
+        }
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::export::{Exporter, Layout};
+
+    // Import `contents` from a file called `name`, returning the samples and commands that
+    // reach the pipeline, as exported rows
+    fn import(name: &str, contents: &str, configure: impl FnOnce(&mut ImportOpts)) -> (Vec<String>, Vec<String>) {
+        let dir = std::env::temp_dir().join(format!("mc_import_{}_{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join(name);
+        fs::write(&file, contents).unwrap();
+        let mut opts = ImportOpts { file, mappings: Vec::new(), time_column: "time".to_string(), time_scale: 1.0, line_rate: 100.0 };
+        configure(&mut opts);
+
+        let export = dir.join("export.csv");
+        let exporter = Exporter::create(&export, Layout::Long, 10.0).unwrap();
+        let mut pipeline = Pipeline::new(rerun::RecordingStream::disabled(), Some(exporter));
+        import_file(&opts, &mut pipeline).unwrap();
+        let rows = |path| fs::read_to_string(path).unwrap_or_default().lines().skip(1).map(String::from).collect();
+        let (samples, commands) = (rows(export), rows(dir.join("export_commands.csv")));
+        fs::remove_dir_all(&dir).unwrap();
+        (samples, commands)
+    }
+
+    #[test]
+    fn wide_csv_with_mapped_columns() {
+        let csv = "time,vbat,theta,temp\n0.5,24.1,0.25,31\nbad,1,1,1\n1.0,,0.5,32\n";
+        let (samples, _) = import("wide.csv", csv, |opts| opts.mappings.push(("vbat".to_string(), "bus_voltage".to_string())));
+        // The row whose time doesn't parse is skipped, as are empty cells and unmapped columns
+        assert_eq!(samples, ["0.500000,bus_voltage,24.1", "0.500000,theta,0.25", "1.000000,theta,0.5"]);
+    }
+
+    #[test]
+    fn csv_without_a_time_column_uses_the_line_rate() {
+        let (samples, _) = import("rows.csv", "theta\n1\n2\n", |opts| opts.line_rate = 10.0);
+        assert_eq!(samples, ["0.000000,theta,1", "0.100000,theta,2"]);
+    }
+
+    #[test]
+    fn long_csv() {
+        let csv = "time,channel,value\n0.1,theta,1.5\n0.2,not_a_channel,3\nbad,theta,2\n0.3,x,2\n";
+        let (samples, _) = import("long.csv", csv, |_| {});
+        assert_eq!(samples, ["0.100000,theta,1.5", "0.300000,x,2"]);
+    }
+
+    #[test]
+    fn text_dump_with_a_time_header() {
+        // Microseconds, past what an f32 holds exactly
+        let dump = "theta:0\ntime:100000001\ntheta:1\ntime:100250001\ntheta:2\n";
+        let (samples, _) = import("dump.txt", dump, |opts| opts.time_scale = 1e-6);
+        assert_eq!(samples, ["0.000000,theta,0", "100.000001,theta,1", "100.250001,theta,2"]);
+
+        // Without one, lines are spaced by the line rate
+        let (samples, _) = import("lines.txt", "theta:1\ntheta:2\n", |_| {});
+        assert_eq!(samples, ["0.000000,theta,1", "0.010000,theta,2"]);
+    }
+
+    #[test]
+    fn capture_file_with_commands() {
+        let capture = "0.500000\ttheta:1\n0.750000\t>velo_ctrl\n1.000000\ttheta:2\n";
+        let (samples, commands) = import("session.cap", capture, |_| {});
+        assert_eq!(samples, ["0.500000,theta,1", "1.000000,theta,2"]);
+        assert_eq!(commands, ["0.750000,\"velo_ctrl\""]);
+    }
+}
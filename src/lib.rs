@@ -0,0 +1,76 @@
+//! Telemetry ingestion and command dispatch for the MissionControl firmware.
+//!
+//! The firmware streams `header:value` lines over a serial port and accepts short ASCII
+//! commands back. This crate holds everything between the port and rerun, so other tools
+//! can reuse it without the command window:
+//!
+//! - [`serial`]: reading and writing the port on dedicated threads
+//! - [`framing`]: splitting the byte stream into lines
+//! - [`parser`]: splitting lines into a header and a value
+//! - [`channels`]: the registry of known channels and where they are logged
+//! - [`commands`]: the commands the firmware understands and their encoding
+//! - [`pipeline`]: processing parsed lines and logging them to rerun
+//! - [`session`]: all of the above, wired together for a live port
+//! - [`odrive`]: a session with an ODrive speaking its ASCII protocol on the serial port
+//! - `can`: a session over SocketCAN with ODrives speaking [`cansimple`], on Linux
+//! - [`binary`]: a session decoding packed binary frames described by a descriptor file
+//! - [`mavlink`]: a session with a MAVLink autopilot, on the serial port or UDP
+//! - [`script`]: automated test sequences run against a session
+//! - [`api`]: HTTP control API for a session
+//! - [`websocket`]: streaming samples to WebSocket clients
+//! - [`foxglove`]: serving the telemetry to Foxglove Studio
+//! - [`plotjuggler`]: streaming samples to PlotJuggler over UDP
+//! - [`mqtt`]: bridging samples and commands to an MQTT broker
+//!
+//! A minimal live session, logging to an .rrd file:
+//!
+//! ```no_run
+//! use visualizer::commands::Command;
+//! use visualizer::pipeline::{self, ListenerMsg, Pipeline};
+//! use visualizer::session::{self, SessionConfig};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let rec = pipeline::recording_builder().save("session.rrd")?;
+//! let port = session::open_port(Some("/dev/ttyACM0"), 115_200)?;
+//! let (cmds_s, cmds_r) = crossbeam_channel::unbounded();
+//! let (msgs_s, msgs_r) = crossbeam_channel::unbounded();
+//!
+//! cmds_s.send(Command::VelocityControl.encode())?;
+//! cmds_s.send(Command::Setpoint(1.5).encode())?;
+//! std::thread::spawn(move || {
+//!     std::thread::sleep(std::time::Duration::from_secs(10));
+//!     let _ = msgs_s.send(ListenerMsg::Shutdown);
+//! });
+//! session::run(port, &SessionConfig::default(), Pipeline::new(rec, None), cmds_r, msgs_r)?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod api;
+pub mod binary;
+pub mod calibration;
+#[cfg(target_os = "linux")]
+pub mod can;
+pub mod cansimple;
+pub mod capture;
+pub mod channels;
+pub mod commands;
+pub mod derived;
+pub mod encoder;
+pub mod export;
+pub mod foxglove;
+pub mod framing;
+pub mod fusion;
+pub mod import;
+pub mod mavlink;
+pub mod mqtt;
+pub mod odrive;
+pub mod parser;
+pub mod pipeline;
+pub mod plotjuggler;
+pub mod power;
+pub mod scalars;
+pub mod script;
+pub mod serial;
+pub mod session;
+pub mod websocket;
@@ -1,641 +1,249 @@
-use rerun;
-use std::time::{Duration, SystemTime};
-use std::io;
+use std::time::SystemTime;
 use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::thread;
-use std::num::ParseFloatError;
-use crossbeam_channel;
+
+use clap::Parser as _;
+
+use visualizer::api;
+use visualizer::binary::{self, Descriptor};
+#[cfg(target_os = "linux")]
+use visualizer::can;
+use visualizer::calibration::{CalibrationMsg, CalibrationWizard, ImuCalibration, WizardStep};
+use visualizer::commands::{Command, CommandSender};
+use visualizer::encoder::{EncoderConfig, EncoderMsg};
+use visualizer::export::{self, Exporter, Layout};
+use visualizer::foxglove;
+use visualizer::framing;
+use visualizer::fusion::{FilterKind, FusionConfig, ImuSample};
+use visualizer::import::{self, ImportOpts};
+use visualizer::mavlink::{self, CommandLong, MavlinkOpts};
+use visualizer::mqtt::{self, MqttOpts};
+use visualizer::odrive::{self, OdriveOpts};
+use visualizer::parser::ParseStats;
+use visualizer::pipeline::{self, GuiLinks, ListenerMsg, Pipeline};
+use visualizer::plotjuggler;
+use visualizer::power::{BatteryModel, Chemistry, PowerMonitorMsg, PowerStats};
+use visualizer::script::ScriptRun;
+use visualizer::serial::{OverflowPolicy, QueueStats};
+use visualizer::session::{self, SessionConfig};
+use visualizer::websocket;
 
 // System command sender
 use eframe::Theme;
 use eframe::egui;
 use eframe::egui::{Style, Visuals};
 
-struct Parser{}
+#[derive(clap::Parser, Debug, Clone)]
+#[command(about = "Real-time telemetry visualizer and command dispatcher")]
+struct Args {
+    /// Serial port to open. Defaults to the first port found.
+    #[arg(long)]
+    port: Option<String>,
 
-impl Parser {
-    fn parse_float(buffer: &[u8]) -> Result<f32, ParseFloatError>
-    {
-        std::str::from_utf8(buffer).unwrap().trim().parse::<f32>()
-    }
+    #[arg(long, default_value_t = 115_200)]
+    baud: u32,
 
-    fn parse_int(buffer: &[u8]) -> i32 {
-        let string = std::str::from_utf8(buffer).unwrap();
-        string.trim().parse().unwrap()
-    }
+    /// Line delimiter: `lf`, `crlf`, or custom text such as `;` or `\r`
+    #[arg(long, value_parser = framing::parse_delimiter, default_value = "lf")]
+    delimiter: framing::Delimiter,
 
-    fn parse_string(buffer: &[u8]) -> String {
-        String::from_utf8_lossy(buffer).to_string()
-    }
-}
+    /// Longer lines are discarded up to the next delimiter
+    #[arg(long, default_value_t = framing::DEFAULT_MAX_LINE_LEN)]
+    max_line_len: usize,
 
-fn serial_listener(cmds_to_dispatch_r: crossbeam_channel::Receiver<String>, dbg_msgs_s: crossbeam_channel::Sender<String>) -> Result<(), Box<dyn std::error::Error>>
-{
-    let opts = rerun::SpawnOptions {
-        memory_limit: "10%".into(),
-        ..Default::default()
-    };
-    let rec = rerun::RecordingStreamBuilder::new("sensor_stream_viewer").spawn_opts(&opts, None)?;
+    /// What to drop when logging falls behind the serial port or CAN bus
+    #[arg(long, value_enum, default_value_t = OverflowPolicy::DropOldest)]
+    overflow: OverflowPolicy,
 
-    let delay_between_rereads = 10; // In millis
-    println!("Serial port:");
-    let ports = serialport::available_ports().expect("No ports found!");
-    for p in &ports {
-        println!("{}", p.port_name);
-    }
+    /// Talk CANSimple to ODrives on this SocketCAN interface instead of a serial port
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "IFACE", conflicts_with_all = ["port", "odrive", "mavlink", "descriptor"])]
+    can: Option<String>,
 
-    let port_name = ports[0].port_name.clone();
-    let mut port = serialport::new(port_name, 115_200)
-    .timeout(Duration::from_millis(10))
-    .open().expect("Failed to open port");
+    /// CAN node IDs of the ODrive axes, in axis order
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "IDS", value_delimiter = ',', default_value = "0,1", requires = "can")]
+    can_nodes: Vec<u8>,
 
-    let mut incoming_stream: VecDeque<u8> = VecDeque::with_capacity(256);
+    /// Decode packed binary frames described by this descriptor file instead of text lines,
+    /// see binary.rs
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["odrive", "mavlink"])]
+    descriptor: Option<PathBuf>,
 
-    // serial_buf may contain less or more than one whole msg (i.e. chars delimited by \n)
-    let delimiter = b'\n'; // Change this to the delimiter character(s) you're using
-    let delimiter_header = ':'; // Change this to the delimiter character(s) you're using
+    /// Write the recording to an .rrd file instead of spawning a viewer
+    #[arg(long, value_name = "FILE", conflicts_with = "connect", global = true)]
+    save: Option<PathBuf>,
 
-    let mut quaternion :[f32; 4] = [0.0, 0.0, 0.0, 0.0];
+    /// Stream to an already-running rerun viewer instead of spawning one
+    #[arg(long, value_name = "ADDR", num_args = 0..=1, default_missing_value = "127.0.0.1:9876", global = true)]
+    connect: Option<SocketAddr>,
 
-    // To measure time offset for the rerun timeline
-    let start_time = SystemTime::now();
+    /// Run without the command window or a viewer, e.g. for unattended logging.
+    /// Saves to a timestamped .rrd file unless --save or --connect is given.
+    #[arg(long)]
+    headless: bool,
 
-    loop {
-        let mut read_buf: [u8; 256] = [0; 256];
+    /// Record every received line and sent command to a capture file for later export
+    #[arg(long, value_name = "FILE")]
+    capture: Option<PathBuf>,
 
-        match port.read(&mut read_buf){
-            Ok(bits_read) =>{
-                incoming_stream.extend(&read_buf[..bits_read]);
-            },
-            Err(ref e) if e.kind() == io::ErrorKind::TimedOut =>{
-                thread::sleep(Duration::from_millis(delay_between_rereads))
-            },
-            Err(_e) => {},
-        }
-        // Process queue to see if there are any complete messages (end with \n)
-        // If it does, remove those elements from the queue and process them
-        // If it doesn't, continue reading from the serial port and appending to queue
-        if read_buf.contains(&delimiter)
-        {
-            while let Some(index_end) = incoming_stream.iter().position(|&c| c == delimiter) {
-                let message = incoming_stream.drain(..index_end + 1).map(|c| c as char).into_iter().collect::<Vec<char>>();//collect::<Vec<u8>>();
-
-                if let Some(index_header_end) = message.iter().position(|&c| c == delimiter_header){
-                    let header = message[..index_header_end].into_iter().collect::<String>();
-                    let data = message[index_header_end+1..].into_iter().map(|c| *c as u8).into_iter().collect::<Vec<u8>>();
-
-                    match header.as_str() {
-                        "bus_voltage" => {
-                            if let Ok(f) = Parser::parse_float(&data)
-                            {
-                                match start_time.elapsed() {
-                                    Ok(elapsed) => {
-                                        rec.set_time_seconds("step", elapsed.as_secs_f32());
-                                    }
-                                    Err(e) => {
-                                        println!("Could not calculate time offset: {e:?}");
-                                        continue;
-                                    }
-                                }
+    /// Test sequence to run, see script.rs. With --headless it runs at startup and the
+    /// exit code is its result; otherwise it can be started from the Script panel.
+    #[arg(long, value_name = "FILE")]
+    script: Option<PathBuf>,
 
-                                let _ = rec.log(
-                                    "bus/V",
-                                    &rerun::TimeSeriesScalar::new(f as f64)
-                                    .with_label("Voltage"),
-                                );
-                            }
-                        },
-                        "bus_current" => {
-                            if let Ok(f) = Parser::parse_float(&data)
-                            {
-                                match start_time.elapsed() {
-                                    Ok(elapsed) => {
-                                        rec.set_time_seconds("step", elapsed.as_secs_f32());
-                                    }
-                                    Err(e) => {
-                                        println!("Could not calculate time offset: {e:?}");
-                                        continue;
-                                    }
-                                }
+    /// Serve the HTTP control API, see api.rs
+    #[arg(long, value_name = "ADDR", num_args = 0..=1, default_missing_value = "127.0.0.1:8080")]
+    http: Option<SocketAddr>,
 
-                                let _ = rec.log(
-                                    "bus/I",
-                                    &rerun::TimeSeriesScalar::new(f as f64)
-                                    .with_label("Current"),
-                                );
-                            }
-                        },
-                        "enc_pos_0" => {
-                            if let Ok(f) = Parser::parse_float(&data)
-                            {
-                                match start_time.elapsed() {
-                                    Ok(elapsed) => {
-                                        rec.set_time_seconds("step", elapsed.as_secs_f32());
-                                    }
-                                    Err(e) => {
-                                        println!("Could not calculate time offset: {e:?}");
-                                        continue;
-                                    }
-                                }
+    /// Stream channel samples to WebSocket clients, see websocket.rs
+    #[arg(long, value_name = "ADDR", num_args = 0..=1, default_missing_value = "127.0.0.1:8765")]
+    websocket: Option<SocketAddr>,
 
-                                let _ = rec.log(
-                                    "encoder_positions/0",
-                                    &rerun::TimeSeriesScalar::new(f as f64)
-                                    .with_label("Axis 0 position"),
-                                );
-                            }
-                        },
-                        "enc_vel_0" => {
-                            if let Ok(f) = Parser::parse_float(&data)
-                            {
-                                match start_time.elapsed() {
-                                    Ok(elapsed) => {
-                                        rec.set_time_seconds("step", elapsed.as_secs_f32());
-                                    }
-                                    Err(e) => {
-                                        println!("Could not calculate time offset: {e:?}");
-                                        continue;
-                                    }
-                                }
+    /// Serve the telemetry to Foxglove Studio, see foxglove.rs
+    #[arg(long, value_name = "ADDR", num_args = 0..=1, default_missing_value = "127.0.0.1:8766")]
+    foxglove: Option<SocketAddr>,
 
-                                let _ = rec.log(
-                                    "encoder_velocities/0",
-                                    &rerun::TimeSeriesScalar::new(f as f64)
-                                    .with_label("Axis 0 velocity"),
-                                );
-                            }
-                        },
-                        "enc_pos_1" => {
-                            if let Ok(f) = Parser::parse_float(&data)
-                            {
-                                match start_time.elapsed() {
-                                    Ok(elapsed) => {
-                                        rec.set_time_seconds("step", elapsed.as_secs_f32());
-                                    }
-                                    Err(e) => {
-                                        println!("Could not calculate time offset: {e:?}");
-                                        continue;
-                                    }
-                                }
+    /// Stream samples as JSON to PlotJuggler's UDP server, see plotjuggler.rs
+    #[arg(long, value_name = "ADDR", num_args = 0..=1, default_missing_value = "127.0.0.1:9870")]
+    plotjuggler: Option<SocketAddr>,
 
-                                let _ = rec.log(
-                                    "encoder_positions/1",
-                                    &rerun::TimeSeriesScalar::new(f as f64)
-                                    .with_label("Axis 1 position"),
-                                );
-                            }
-                        },
-                        "enc_vel_1" => {
-                            if let Ok(f) = Parser::parse_float(&data)
-                            {
-                                match start_time.elapsed() {
-                                    Ok(elapsed) => {
-                                        rec.set_time_seconds("step", elapsed.as_secs_f32());
-                                    }
-                                    Err(e) => {
-                                        println!("Could not calculate time offset: {e:?}");
-                                        continue;
-                                    }
-                                }
+    /// Channels streamed to PlotJuggler, comma-separated; every registered channel if omitted
+    #[arg(long, value_name = "CHANNELS", value_delimiter = ',', requires = "plotjuggler")]
+    plotjuggler_channels: Vec<String>,
 
-                                let _ = rec.log(
-                                    "encoder_velocities/1",
-                                    &rerun::TimeSeriesScalar::new(f as f64)
-                                    .with_label("Axis 1 velocity"),
-                                );
-                            }
-                        },
-                        "ctrl_u_0" => {
-                            if let Ok(f) = Parser::parse_float(&data)
-                            {
-                                match start_time.elapsed() {
-                                    Ok(elapsed) => {
-                                        rec.set_time_seconds("step", elapsed.as_secs_f32());
-                                    }
-                                    Err(e) => {
-                                        println!("Could not calculate time offset: {e:?}");
-                                        continue;
-                                    }
-                                }
-                                
-                                let _ = rec.log(
-                                    "ctrl_u/0",
-                                    &rerun::TimeSeriesScalar::new(f as f64)
-                                    .with_label("Axis 0 Control U"),
-                                );
-                            }
-                        },
-                        // "q_w" => {
-                        //     if let Ok(f) = Parser::parse_float(&data)
-                        //     {
-                        //         rec.set_time_sequence("step", ctr);
-                                
-                        //         quaternion[0] = f;
-
-                        //         rec.log(
-                        //             "simple",
-                        //             &rerun::Boxes3D::from_centers_and_half_sizes(
-                        //                 [(0.0, 0.0, 0.0)],
-                        //                 [(2.0, 2.0, 1.0)],
-                        //             ).with_rotations([rerun::Quaternion::from_xyzw([quaternion[1], quaternion[2], quaternion[3], quaternion[0]])]),
-                        //         )?;
-
-                        //         ctr = ctr + 1;
-                        //     }
-                        // },
-                        // "q_x" => {
-                        //     if let Ok(f) = Parser::parse_float(&data)
-                        //     {
-                        //         rec.set_time_sequence("step", ctr);
-                                
-                        //         quaternion[1] = f;
-
-                        //         rec.log(
-                        //             "simple",
-                        //             &rerun::Boxes3D::from_centers_and_half_sizes(
-                        //                 [(0.0, 0.0, 0.0)],
-                        //                 [(2.0, 2.0, 1.0)],
-                        //             ).with_rotations([rerun::Quaternion::from_xyzw([quaternion[1], quaternion[2], quaternion[3], quaternion[0]])]),
-                        //         )?;
-
-                        //         ctr = ctr + 1;
-                        //     }
-                        // },
-                        // "q_y" => {
-                        //     if let Ok(f) = Parser::parse_float(&data)
-                        //     {
-                        //         rec.set_time_sequence("step", ctr);
-                                
-                        //         quaternion[2] = f;
-
-                        //         rec.log(
-                        //             "simple",
-                        //             &rerun::Boxes3D::from_centers_and_half_sizes(
-                        //                 [(0.0, 0.0, 0.0)],
-                        //                 [(2.0, 2.0, 1.0)],
-                        //             ).with_rotations([rerun::Quaternion::from_xyzw([quaternion[1], quaternion[2], quaternion[3], quaternion[0]])]),
-                        //         )?;
-
-                        //         ctr = ctr + 1;
-                        //     }
-                        // },
-                        // "q_z" => {
-                        //     if let Ok(f) = Parser::parse_float(&data)
-                        //     {
-                        //         rec.set_time_sequence("step", ctr);
-                                
-                        //         quaternion[3] = f;
-
-                        //         rec.log(
-                        //             "simple",
-                        //             &rerun::Boxes3D::from_centers_and_half_sizes(
-                        //                 [(0.0, 0.0, 0.0)],
-                        //                 [(2.0, 2.0, 1.0)],
-                        //             ).with_rotations([rerun::Quaternion::from_xyzw([quaternion[1], quaternion[2], quaternion[3], quaternion[0]])]),
-                        //         )?;
-
-                        //         ctr = ctr + 1;
-                        //     }
-                        // },
-                        "x" => {
-                            if let Ok(f) = Parser::parse_float(&data)
-                            {
-                                match start_time.elapsed() {
-                                    Ok(elapsed) => {
-                                        rec.set_time_seconds("step", elapsed.as_secs_f32());
-                                    }
-                                    Err(e) => {
-                                        println!("Could not calculate time offset: {e:?}");
-                                        continue;
-                                    }
-                                }
-                                
-                                let _ = rec.log(
-                                    "state/x",
-                                    &rerun::TimeSeriesScalar::new(f as f64)
-                                    .with_label("state_x"),
-                                );
-                            }
-                        },
-                        "theta" => {
-                            if let Ok(f) = Parser::parse_float(&data)
-                            {
-                                match start_time.elapsed() {
-                                    Ok(elapsed) => {
-                                        rec.set_time_seconds("step", elapsed.as_secs_f32());
-                                    }
-                                    Err(e) => {
-                                        println!("Could not calculate time offset: {e:?}");
-                                        continue;
-                                    }
-                                }
-                                
-                                let _ = rec.log(
-                                    "state/theta",
-                                    &rerun::TimeSeriesScalar::new(f as f64)
-                                    .with_label("state_theta"),
-                                );
-                            }
-                        },
-                        "x_dot" => {
-                            if let Ok(f) = Parser::parse_float(&data)
-                            {
-                                match start_time.elapsed() {
-                                    Ok(elapsed) => {
-                                        rec.set_time_seconds("step", elapsed.as_secs_f32());
-                                    }
-                                    Err(e) => {
-                                        println!("Could not calculate time offset: {e:?}");
-                                        continue;
-                                    }
-                                }
-                                
-                                let _ = rec.log(
-                                    "state/x_dot",
-                                    &rerun::TimeSeriesScalar::new(f as f64)
-                                    .with_label("state_x_dot"),
-                                );
-                            }
-                        },
-                        "theta_dot" => {
-                            if let Ok(f) = Parser::parse_float(&data)
-                            {
-                                match start_time.elapsed() {
-                                    Ok(elapsed) => {
-                                        rec.set_time_seconds("step", elapsed.as_secs_f32());
-                                    }
-                                    Err(e) => {
-                                        println!("Could not calculate time offset: {e:?}");
-                                        continue;
-                                    }
-                                }
-                                
-                                let _ = rec.log(
-                                    "state/theta_dot",
-                                    &rerun::TimeSeriesScalar::new(f as f64)
-                                    .with_label("state_theta_dot"),
-                                );
-                            }
-                        },
-
-
-                        "imu_r" => {
-                            if let Ok(f) = Parser::parse_float(&data)
-                            {
-                                match start_time.elapsed() {
-                                    Ok(elapsed) => {
-                                        rec.set_time_seconds("step", elapsed.as_secs_f32());
-                                    }
-                                    Err(e) => {
-                                        println!("Could not calculate time offset: {e:?}");
-                                        continue;
-                                    }
-                                }
-                                
-                                let _ = rec.log(
-                                    "imu/roll",
-                                    &rerun::TimeSeriesScalar::new(f as f64)
-                                    .with_label("imu_roll"),
-                                );
-                            }
-                        },
-                        "imu_p" => {
-                            if let Ok(f) = Parser::parse_float(&data)
-                            {
-                                match start_time.elapsed() {
-                                    Ok(elapsed) => {
-                                        rec.set_time_seconds("step", elapsed.as_secs_f32());
-                                    }
-                                    Err(e) => {
-                                        println!("Could not calculate time offset: {e:?}");
-                                        continue;
-                                    }
-                                }
-                                
-                                let _ = rec.log(
-                                    "imu/pitch",
-                                    &rerun::TimeSeriesScalar::new(f as f64)
-                                    .with_label("imu_pitch"),
-                                );
-                            }
-                        },
-                        "imu_p_dot" => {
-                            if let Ok(f) = Parser::parse_float(&data)
-                            {
-                                match start_time.elapsed() {
-                                    Ok(elapsed) => {
-                                        rec.set_time_seconds("step", elapsed.as_secs_f32());
-                                    }
-                                    Err(e) => {
-                                        println!("Could not calculate time offset: {e:?}");
-                                        continue;
-                                    }
-                                }
-                                
-                                let _ = rec.log(
-                                    "imu/pitch_dot",
-                                    &rerun::TimeSeriesScalar::new(f as f64)
-                                    .with_label("imu_pitch_dot"),
-                                );
-                            }
-                        },
-                        "imu_y" => {
-                            if let Ok(f) = Parser::parse_float(&data)
-                            {
-                                match start_time.elapsed() {
-                                    Ok(elapsed) => {
-                                        rec.set_time_seconds("step", elapsed.as_secs_f32());
-                                    }
-                                    Err(e) => {
-                                        println!("Could not calculate time offset: {e:?}");
-                                        continue;
-                                    }
-                                }
+    /// Log lines that fail to parse to a `parse_errors` TextLog in rerun
+    #[arg(long, global = true)]
+    log_malformed: bool,
 
-                                let _ = rec.log(
-                                    "imu/yaw",
-                                    &rerun::TimeSeriesScalar::new(f as f64)
-                                    .with_label("imu_yaw"),
-                                );
-                            }
-                        },
-
-                        "acc_x" => {
-                            if let Ok(f) = Parser::parse_float(&data)
-                            {
-                                match start_time.elapsed() {
-                                    Ok(elapsed) => {
-                                        rec.set_time_seconds("step", elapsed.as_secs_f32());
-                                    }
-                                    Err(e) => {
-                                        println!("Could not calculate time offset: {e:?}");
-                                        continue;
-                                    }
-                                }
+    /// Export all channels and commands live to a .csv or .parquet file
+    #[arg(long, value_name = "FILE", global = true)]
+    export: Option<PathBuf>,
 
-                                let _ = rec.log(
-                                    "accelerometer/X",
-                                    &rerun::TimeSeriesScalar::new(f as f64)
-                                    .with_label("acc_X"),
-                                );
-                            }
-                        },
-                        "acc_y" => {
-                            if let Ok(f) = Parser::parse_float(&data)
-                            {
-                                match start_time.elapsed() {
-                                    Ok(elapsed) => {
-                                        rec.set_time_seconds("step", elapsed.as_secs_f32());
-                                    }
-                                    Err(e) => {
-                                        println!("Could not calculate time offset: {e:?}");
-                                        continue;
-                                    }
-                                }
+    #[command(flatten)]
+    export_opts: ExportOpts,
 
-                                let _ = rec.log(
-                                    "accelerometer/Y",
-                                    &rerun::TimeSeriesScalar::new(f as f64)
-                                    .with_label("acc_Y"),
-                                );
-                            }
-                        },
-                        "acc_z" => {
-                            if let Ok(f) = Parser::parse_float(&data)
-                            {
-                                match start_time.elapsed() {
-                                    Ok(elapsed) => {
-                                        rec.set_time_seconds("step", elapsed.as_secs_f32());
-                                    }
-                                    Err(e) => {
-                                        println!("Could not calculate time offset: {e:?}");
-                                        continue;
-                                    }
-                                }
+    #[command(flatten)]
+    mqtt_opts: MqttOpts,
 
-                                let _ = rec.log(
-                                    "accelerometer/Z",
-                                    &rerun::TimeSeriesScalar::new(f as f64)
-                                    .with_label("acc_Z"),
-                                );
-                            }
-                        },
-                        "gyr_x" => {
-                            if let Ok(f) = Parser::parse_float(&data)
-                            {
-                                match start_time.elapsed() {
-                                    Ok(elapsed) => {
-                                        rec.set_time_seconds("step", elapsed.as_secs_f32());
-                                    }
-                                    Err(e) => {
-                                        println!("Could not calculate time offset: {e:?}");
-                                        continue;
-                                    }
-                                }
+    #[command(flatten)]
+    odrive_opts: OdriveOpts,
 
-                                let _ = rec.log(
-                                    "gyro/X",
-                                    &rerun::TimeSeriesScalar::new(f as f64)
-                                    .with_label("gyro_X"),
-                                );
-                            }
-                        },
-                        "gyr_y" => {
-                            if let Ok(f) = Parser::parse_float(&data)
-                            {
-                                match start_time.elapsed() {
-                                    Ok(elapsed) => {
-                                        rec.set_time_seconds("step", elapsed.as_secs_f32());
-                                    }
-                                    Err(e) => {
-                                        println!("Could not calculate time offset: {e:?}");
-                                        continue;
-                                    }
-                                }
-                                
-                                let _ = rec.log(
-                                    "gyro/Y",
-                                    &rerun::TimeSeriesScalar::new(f as f64)
-                                    .with_label("gyro_Y"),
-                                );
-                            }
-                        },
-                        "gyr_z" => {
-                            if let Ok(f) = Parser::parse_float(&data)
-                            {
-                                match start_time.elapsed() {
-                                    Ok(elapsed) => {
-                                        rec.set_time_seconds("step", elapsed.as_secs_f32());
-                                    }
-                                    Err(e) => {
-                                        println!("Could not calculate time offset: {e:?}");
-                                        continue;
-                                    }
-                                }
-                                
-                                let _ = rec.log(
-                                    "gyro/Z",
-                                    &rerun::TimeSeriesScalar::new(f as f64)
-                                    .with_label("gyro_Z"),
-                                );
-                            }
-                        },
-                        "dbg_msg" => {
-                            let s = Parser::parse_string(&data);
-                            println!("dbg_msg:{}", s);
-
-                            // Hacky way to publish an array as a whole
-                            if s.contains("quaternion,")
-                            {
-                                // Split the input string by ','
-                                let parts: Vec<&str> = s.split(',').collect();
-
-                                // Extract the string and floats
-                                // let key = parts[0];
-                                let floats: Vec<f32> = parts[1..].iter().filter_map(|&s| s.parse().ok()).collect();
-
-                                // println!("s: {}", s);
-                                // println!("parts: {:?}", parts);
-                                // println!("floats: {:?}", floats.len());
-                                quaternion[0] = floats[0];
-                                quaternion[1] = floats[1];
-                                quaternion[2] = floats[2];
-                                quaternion[3] = floats[3];
-
-                                // Publish to rerun
-                                rec.log(
-                                    "IMU_3D",
-                                    &rerun::Boxes3D::from_centers_and_half_sizes(
-                                        [(0.0, 0.0, 0.0)],
-                                        [(1.0, 2.0, 2.0)],
-                                    ).with_rotations([rerun::Quaternion::from_xyzw([quaternion[1], quaternion[2], quaternion[3], quaternion[0]])]),
-                                )?;
+    #[command(flatten)]
+    mavlink_opts: MavlinkOpts,
 
-                            }
+    #[command(subcommand)]
+    command: Option<Subcommand>,
+}
 
-                            let _ = dbg_msgs_s.try_send(s);
-                        },
-                        _ => {
-                            let s = Parser::parse_string(&data);
-                            println!("No match on serial! {}", s);
-                            /* else case is no-op */
-                        },
-                    }
-                    
-                }
-            }
-        }
+#[derive(clap::Args, Debug, Clone)]
+struct ExportOpts {
+    /// One (time, channel, value) row per sample, or one column per channel
+    #[arg(long, value_enum, default_value_t = Layout::Long)]
+    layout: Layout,
+
+    /// Common time base of the wide layout, in Hz
+    #[arg(long, default_value_t = 100.0)]
+    resample_hz: f64,
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum Subcommand {
+    /// Export a capture file recorded with --capture to .csv or .parquet
+    Export {
+        capture: PathBuf,
+        output: PathBuf,
+        #[command(flatten)]
+        opts: ExportOpts,
+    },
+    /// Log a CSV file or a `header:value` text dump to rerun as if it was received live
+    Import(ImportOpts),
+    /// Generate the C header of a binary telemetry descriptor, see binary.rs
+    CHeader {
+        descriptor: PathBuf,
+        /// Header to write; printed if omitted
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+}
 
+impl Args {
+    // Along with the file it saves to, if any
+    fn open_recording(&self) -> Result<(rerun::RecordingStream, Option<PathBuf>), Box<dyn std::error::Error>> {
+        let builder = pipeline::recording_builder();
+        if let Some(path) = &self.save {
+            Ok((builder.save(path)?, Some(path.clone())))
+        } else if let Some(addr) = self.connect {
+            Ok((builder.connect_opts(addr, rerun::default_flush_timeout())?, None))
+        } else if self.headless {
+            let secs = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+            let path = PathBuf::from(format!("session_{}.rrd", secs));
+            println!("Recording to {}", path.display());
+            Ok((builder.save(&path)?, Some(path)))
+        } else {
+            let opts = rerun::SpawnOptions {
+                memory_limit: "10%".into(),
+                ..Default::default()
+            };
+            Ok((builder.spawn_opts(&opts, None)?, None))
+        }
+    }
 
-        // Check for commands to dispatch thru serial. Sent from the egui thread.
-        if let Ok(command) = cmds_to_dispatch_r.try_recv() {
-            port.write(command.as_bytes()).expect("Failed to write to serial port");
+    fn open_exporter(&self) -> Result<Option<Exporter>, Box<dyn std::error::Error>> {
+        match &self.export {
+            Some(path) => Ok(Some(Exporter::create(path, self.export_opts.layout, self.export_opts.resample_hz)?)),
+            None => Ok(None),
         }
     }
 }
 
+fn serial_listener(
+    args: Args,
+    cmds_to_dispatch_r: crossbeam_channel::Receiver<String>,
+    listener_msgs_r: crossbeam_channel::Receiver<ListenerMsg>,
+    gui: GuiLinks,
+) -> Result<(), Box<dyn std::error::Error>>
+{
+    let (rec, recording_path) = args.open_recording()?;
+
+    let mut pipeline = Pipeline::new(rec, args.open_exporter()?);
+    pipeline.set_gui(gui);
+    pipeline.set_recording_path(recording_path);
+    pipeline.handle(ListenerMsg::LogMalformed(args.log_malformed));
+
+    #[cfg(target_os = "linux")]
+    if let Some(interface) = &args.can {
+        return can::run(interface, &args.can_nodes, args.overflow, args.capture.clone(), pipeline, cmds_to_dispatch_r, listener_msgs_r);
+    }
+    if args.mavlink_opts.mavlink.is_some() {
+        let link = mavlink::open(&args.mavlink_opts, args.overflow, || session::open_port(args.port.as_deref(), args.baud))?;
+        return mavlink::run(link, &args.mavlink_opts, args.capture.clone(), pipeline, cmds_to_dispatch_r, listener_msgs_r);
+    }
+    let port = session::open_port(args.port.as_deref(), args.baud)?;
+    if let Some(path) = &args.descriptor {
+        let descriptor = Descriptor::load(path)?;
+        return binary::run(port, descriptor, args.overflow, args.capture.clone(), pipeline, cmds_to_dispatch_r, listener_msgs_r);
+    }
+    if args.odrive_opts.odrive {
+        let opts = &args.odrive_opts;
+        let driver = odrive::Driver::new(opts.polls(), &opts.odrive_axes, opts.odrive_rate);
+        return odrive::run(port, driver, args.overflow, args.capture.clone(), pipeline, cmds_to_dispatch_r, listener_msgs_r);
+    }
+
+    let config = SessionConfig {
+        delimiter: args.delimiter.0.clone(),
+        max_line_len: args.max_line_len,
+        overflow: args.overflow,
+        capture: args.capture.clone(),
+    };
+    session::run(port, &config, pipeline, cmds_to_dispatch_r, listener_msgs_r)
+}
+
 
 #[derive(Debug, PartialEq)]
+#[allow(clippy::enum_variant_names)]
 enum ControlModes {
     PositionCtrl,
     VelocityCtrl,
@@ -648,92 +256,496 @@ pub struct CommandDispatcherApp {
     control_mode: ControlModes,
     controller_setpoint: f32,
     dbg_msg_channel_r: crossbeam_channel::Receiver<String>,
-    dispatch_command_s: crossbeam_channel::Sender<String>,
+    dispatch_command_s: CommandSender,
+    power_stats: PowerStats,
+    battery_model: BatteryModel,
+    power_stats_r: crossbeam_channel::Receiver<PowerStats>,
+    fusion_config: FusionConfig,
+    calibration_wizard: CalibrationWizard,
+    imu_samples_r: crossbeam_channel::Receiver<ImuSample>,
+    encoder_config: EncoderConfig,
+    parse_stats: ParseStats,
+    parse_stats_r: crossbeam_channel::Receiver<ParseStats>,
+    queue_stats: QueueStats,
+    queue_stats_r: crossbeam_channel::Receiver<QueueStats>,
+    log_malformed: bool,
+    script_path: String,
+    script_run: Option<ScriptRun>,
+    script_result: Option<Result<(), String>>,
+    // Commands sent by this window and not yet echoed back, see sync_commands
+    own_commands: VecDeque<String>,
+    commands_r: crossbeam_channel::Receiver<String>,
+    recording: Option<PathBuf>,
+    recording_r: crossbeam_channel::Receiver<Option<PathBuf>>,
+    listener_msgs_s: crossbeam_channel::Sender<ListenerMsg>,
+    // Next COMMAND_LONG to send, in a MAVLink session
+    mav_command: Option<CommandLong>,
 }
 
-impl eframe::App for CommandDispatcherApp {
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+// Commands of ours not echoed back after this many are assumed lost
+const MAX_OWN_COMMANDS: usize = 64;
 
-        egui::CentralPanel::default()
-            .show(ctx, |ui: &mut egui::Ui| {
-                
-                // ui.horizontal(|ui| {
-                //     ui.selectable_value(&mut self.control_mode, ControlModes::PositionCtrl, "Position Ctrl");
-                //     ui.selectable_value(&mut self.control_mode, ControlModes::VelocityCtrl, "Velocity Ctrl");
-                //     ui.selectable_value(&mut self.control_mode, ControlModes::TorqueCtrl, "Torque Ctrl");
-                //     ui.selectable_value(&mut self.control_mode, ControlModes::VoltageCtrl, "Voltage Ctrl");
-                // });
+impl CommandDispatcherApp {
+    fn dispatch(&mut self, command: Command) {
+        let command = command.encode();
+        if self.dispatch_command_s.try_send(command.clone()).is_ok() {
+            if self.own_commands.len() == MAX_OWN_COMMANDS {
+                self.own_commands.pop_front();
+            }
+            self.own_commands.push_back(command);
+        }
+    }
 
+    // Follow commands sent by others, e.g. scripts or the HTTP API. Ours are skipped so an
+    // echo can't move the setpoint slider back while it's being dragged.
+    fn sync_commands(&mut self) {
+        while let Ok(command) = self.commands_r.try_recv() {
+            if self.own_commands.front() == Some(&command) {
+                self.own_commands.pop_front();
+                continue;
+            }
+            match Command::decode(&command) {
+                Some(Command::PositionControl) => self.control_mode = ControlModes::PositionCtrl,
+                Some(Command::VelocityControl) => self.control_mode = ControlModes::VelocityCtrl,
+                Some(Command::TorqueControl) => self.control_mode = ControlModes::TorqueCtrl,
+                Some(Command::VoltageControl) => self.control_mode = ControlModes::VoltageCtrl,
+                Some(Command::Setpoint(sp)) => self.controller_setpoint = sp,
+                _ => {}
+            }
+        }
+        if let Some(recording) = self.recording_r.try_iter().last() {
+            self.recording = recording;
+        }
+    }
 
-                ui.horizontal(|ui| {
-                    if ui.button("Calibration Rtn").clicked() {
-                        let _ = self.dispatch_command_s.try_send(String::from("calib_rtn"));
-                    };
-                    if ui.button("Clear Errors").clicked() {
-                        let _ = self.dispatch_command_s.try_send(String::from("clear_err"));
-                    };
-                });
+    fn power_panel(&mut self, ui: &mut egui::Ui) {
+        // Only the most recent stats matter
+        if let Some(stats) = self.power_stats_r.try_iter().last() {
+            self.power_stats = stats;
+        }
+        let stats = &self.power_stats;
+
+        ui.heading("Power");
+        egui::Grid::new("power_stats").num_columns(2).show(ui, |ui| {
+            ui.label("Bus voltage");
+            ui.label(format!("{:.2} V", stats.voltage));
+            ui.end_row();
+            ui.label("Bus current");
+            ui.label(format!("{:.2} A", stats.current));
+            ui.end_row();
+            ui.label("Power");
+            ui.label(format!("{:.1} W", stats.power_w));
+            ui.end_row();
+            ui.label("Energy");
+            ui.label(format!("{:.3} Wh", stats.energy_wh));
+            ui.end_row();
+            ui.label("Charge");
+            ui.label(format!("{:.1} mAh", stats.charge_mah));
+            ui.end_row();
+            ui.label("State of charge");
+            ui.label(stats.soc.map_or("-".to_string(), |soc| format!("{:.0} %", soc * 100.0)));
+            ui.end_row();
+        });
+        if stats.under_voltage {
+            ui.colored_label(egui::Color32::RED, "UNDER-VOLTAGE");
+        }
+        if ui.button("Reset Counters").clicked() {
+            self.power_stats = PowerStats::default();
+            let _ = self.listener_msgs_s.try_send(ListenerMsg::Power(PowerMonitorMsg::Reset));
+        }
 
+        ui.separator();
+        ui.label("Battery model");
+        let mut model = self.battery_model;
+        egui::ComboBox::from_label("Chemistry")
+            .selected_text(format!("{:?}", model.chemistry))
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut model.chemistry, Chemistry::LiPo, "LiPo");
+                ui.selectable_value(&mut model.chemistry, Chemistry::LiFePO4, "LiFePO4");
+            });
+        ui.add(egui::DragValue::new(&mut model.cells).clamp_range(1..=24).prefix("Cells: "));
+        ui.add(egui::DragValue::new(&mut model.capacity_mah).clamp_range(0.0..=100_000.0).speed(10.0).prefix("Capacity: ").suffix(" mAh"));
+        ui.add(egui::DragValue::new(&mut model.min_cell_voltage).clamp_range(2.0..=4.2).speed(0.01).prefix("Min cell: ").suffix(" V"));
+        if model != self.battery_model {
+            self.battery_model = model;
+            let _ = self.listener_msgs_s.try_send(ListenerMsg::Power(PowerMonitorMsg::SetModel(model)));
+        }
+    }
+
+    fn fusion_panel(&mut self, ui: &mut egui::Ui) {
+        ui.heading("IMU Fusion");
+        let mut config = self.fusion_config;
+        egui::ComboBox::from_label("Filter")
+            .selected_text(config.filter.name())
+            .show_ui(ui, |ui| {
+                for kind in FilterKind::DEFAULTS {
+                    if ui.selectable_label(config.filter.name() == kind.name(), kind.name()).clicked() {
+                        config.filter = kind;
+                    }
+                }
+            });
+        match &mut config.filter {
+            FilterKind::Madgwick { beta } => {
+                ui.add(egui::DragValue::new(beta).clamp_range(0.0..=2.0).speed(0.005).prefix("beta: "));
+            }
+            FilterKind::Mahony { kp, ki } => {
+                ui.add(egui::DragValue::new(kp).clamp_range(0.0..=20.0).speed(0.05).prefix("kp: "));
+                ui.add(egui::DragValue::new(ki).clamp_range(0.0..=5.0).speed(0.005).prefix("ki: "));
+            }
+            FilterKind::Complementary { alpha } => {
+                ui.add(egui::DragValue::new(alpha).clamp_range(0.0..=1.0).speed(0.001).prefix("alpha: "));
+            }
+        }
+        ui.checkbox(&mut config.gyro_in_degrees, "Gyro in deg/s");
+        ui.checkbox(&mut config.angles_in_degrees, "Firmware angles in degrees");
+        if config != self.fusion_config {
+            self.fusion_config = config;
+            let _ = self.listener_msgs_s.try_send(ListenerMsg::Fusion(config));
+        }
+    }
+
+    fn encoder_panel(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Encoders & Odometry");
+        let mut config = self.encoder_config;
+        ui.add(egui::DragValue::new(&mut config.wheel_radius).clamp_range(0.001..=1.0).speed(0.001).prefix("Wheel radius: ").suffix(" m"));
+        ui.add(egui::DragValue::new(&mut config.track_width).clamp_range(0.01..=5.0).speed(0.001).prefix("Track width: ").suffix(" m"));
+        ui.horizontal(|ui| {
+            let mut inverted = [config.axis_sign[0] < 0.0, config.axis_sign[1] < 0.0];
+            ui.checkbox(&mut inverted[0], "Invert axis 0");
+            ui.checkbox(&mut inverted[1], "Invert axis 1");
+            config.axis_sign = inverted.map(|inv| if inv { -1.0 } else { 1.0 });
+        });
+        ui.add(egui::DragValue::new(&mut config.max_jump).clamp_range(0.0..=100.0).speed(0.01).prefix("Glitch threshold: ").suffix(" turns"));
+        let mut wraps = config.wrap_range.is_some();
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut wraps, "Position wraps");
+            let mut range = config.wrap_range.unwrap_or(1.0);
+            if wraps {
+                ui.add(egui::DragValue::new(&mut range).clamp_range(0.001..=1e6).speed(0.01).suffix(" turns"));
+            }
+            config.wrap_range = wraps.then_some(range);
+        });
+        if config != self.encoder_config {
+            self.encoder_config = config;
+            let _ = self.listener_msgs_s.try_send(ListenerMsg::Encoder(EncoderMsg::SetConfig(config)));
+        }
+        if ui.button("Reset Odometry").clicked() {
+            let _ = self.listener_msgs_s.try_send(ListenerMsg::Encoder(EncoderMsg::ResetOdometry));
+        }
+    }
+
+    fn parser_panel(&mut self, ui: &mut egui::Ui) {
+        if let Some(stats) = self.parse_stats_r.try_iter().last() {
+            self.parse_stats = stats;
+        }
+        let stats = &self.parse_stats;
+
+        ui.heading("Parser");
+        egui::Grid::new("parse_stats").num_columns(4).striped(true).show(ui, |ui| {
+            ui.strong("Channel");
+            ui.strong("OK");
+            ui.strong("Bad number");
+            ui.strong("Non-UTF8");
+            ui.end_row();
+            for (header, counters) in &stats.channels {
+                ui.label(header);
+                ui.label(counters.ok.to_string());
+                ui.label(counters.bad_number.to_string());
+                ui.label(counters.non_utf8.to_string());
                 ui.end_row();
-                ui.separator();
+            }
+        });
+        egui::Grid::new("parse_line_errors").num_columns(2).show(ui, |ui| {
+            ui.label("Unknown header");
+            ui.label(stats.unknown_header.to_string());
+            ui.end_row();
+            ui.label("Oversized");
+            ui.label(stats.oversized.to_string());
+            ui.end_row();
+            ui.label("Malformed");
+            ui.label(stats.malformed.to_string());
+            ui.end_row();
+        });
+        if ui.checkbox(&mut self.log_malformed, "Log malformed lines to rerun").changed() {
+            let _ = self.listener_msgs_s.try_send(ListenerMsg::LogMalformed(self.log_malformed));
+        }
 
-                ui.horizontal(|ui| {
-                    if ui.button("Position Ctrl").clicked() {
+        if let Some(stats) = self.queue_stats_r.try_iter().last() {
+            self.queue_stats = stats;
+        }
+        let stats = &self.queue_stats;
+        ui.label("Serial link");
+        egui::Grid::new("queue_stats").num_columns(2).show(ui, |ui| {
+            ui.label("Read queue");
+            ui.label(format!("{} / {} (peak {})", stats.read_depth, stats.read_capacity, stats.read_peak));
+            ui.end_row();
+            ui.label("Dropped");
+            ui.label(format!("{} chunks, {} bytes", stats.dropped_chunks, stats.dropped_bytes));
+            ui.end_row();
+            ui.label("Command queue");
+            ui.label(stats.command_depth.to_string());
+            ui.end_row();
+        });
+    }
 
-                        self.control_mode = ControlModes::PositionCtrl;
-                        let _ = self.dispatch_command_s.try_send(String::from("posn_ctrl"));
-                    };
-                    if ui.button("Velocity Ctrl").clicked() {
+    fn firmware_panel(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            if ui.button("Calibration Rtn").clicked() {
+                self.dispatch(Command::CalibrationRoutine);
+            };
+            if ui.button("Clear Errors").clicked() {
+                self.dispatch(Command::ClearErrors);
+            };
+        });
 
-                        self.control_mode = ControlModes::VelocityCtrl;
-                        let _ = self.dispatch_command_s.try_send(String::from("velo_ctrl"));
-                    };
-                    if ui.button("Torque Ctrl").clicked() {
+        ui.end_row();
+        ui.separator();
 
-                        self.control_mode = ControlModes::TorqueCtrl;
-                        let _ = self.dispatch_command_s.try_send(String::from("torq_ctrl"));
-                    };
-                    if ui.button("Voltage Ctrl").clicked() {
+        ui.horizontal(|ui| {
+            if ui.button("Position Ctrl").clicked() {
 
-                        self.control_mode = ControlModes::VoltageCtrl;
-                        let _ = self.dispatch_command_s.try_send(String::from("volt_ctrl"));
-                    };
-                });
+                self.control_mode = ControlModes::PositionCtrl;
+                self.dispatch(Command::PositionControl);
+            };
+            if ui.button("Velocity Ctrl").clicked() {
 
-                ui.end_row();
-                ui.separator();
+                self.control_mode = ControlModes::VelocityCtrl;
+                self.dispatch(Command::VelocityControl);
+            };
+            if ui.button("Torque Ctrl").clicked() {
 
-                ui.horizontal(|ui| {
-                    if ui.button("Idle").clicked() {
-                        let _ = self.dispatch_command_s.try_send(String::from("idle_ctrl"));
-                    };
-                    if ui.button("Start Auto Control").clicked() {
-                        let _ = self.dispatch_command_s.try_send(String::from("auto_ctrl"));
-                    };
+                self.control_mode = ControlModes::TorqueCtrl;
+                self.dispatch(Command::TorqueControl);
+            };
+            if ui.button("Voltage Ctrl").clicked() {
+
+                self.control_mode = ControlModes::VoltageCtrl;
+                self.dispatch(Command::VoltageControl);
+            };
+            ui.label(format!("Mode: {:?}", self.control_mode));
+        });
+
+        ui.end_row();
+        ui.separator();
+
+        ui.horizontal(|ui| {
+            if ui.button("Idle").clicked() {
+                self.dispatch(Command::Idle);
+            };
+            if ui.button("Start Auto Control").clicked() {
+                self.dispatch(Command::AutoControl);
+            };
+        });
+
+        ui.end_row();
+        ui.separator();
+
+        ui.style_mut().spacing.slider_width = 500.0;
+
+        let mut new_setpoint = self.controller_setpoint;
+        ui.horizontal(|ui| {
+            if ui.button("Zero Setpoint").clicked() {
+                new_setpoint = 0.0;
+            }
+            ui.add(egui::Slider::new(&mut new_setpoint, -5.0..=5.0).text("Controller Setpoint"));
+        });
+        
+        // If value changed, send it to the ODrive
+        if new_setpoint != self.controller_setpoint
+        {
+            self.controller_setpoint = new_setpoint;
+            self.dispatch(Command::Setpoint(self.controller_setpoint));
+        }
+    }
+
+    fn mavlink_panel(&mut self, ui: &mut egui::Ui) {
+        let Some(command) = &mut self.mav_command else {
+            return;
+        };
+        ui.label("MAVLink COMMAND_LONG");
+        ui.horizontal(|ui| {
+            let name = mavlink::COMMANDS.iter().find(|(_, id)| *id == command.command).map_or("Other", |(name, _)| name);
+            egui::ComboBox::from_id_source("mav_cmd")
+                .selected_text(name)
+                .show_ui(ui, |ui| {
+                    for &(name, id) in mavlink::COMMANDS {
+                        ui.selectable_value(&mut command.command, id, name);
+                    }
                 });
+            ui.add(egui::DragValue::new(&mut command.command).prefix("MAV_CMD "));
+        });
+        ui.horizontal(|ui| {
+            for (i, param) in command.params.iter_mut().enumerate() {
+                ui.add(egui::DragValue::new(param).speed(0.1).prefix(format!("p{}: ", i + 1)));
+            }
+        });
+        if ui.button("Send").clicked() {
+            let _ = self.dispatch_command_s.try_send(command.encode());
+        }
+    }
 
-                ui.end_row();
-                ui.separator();
+    fn script_panel(&mut self, ui: &mut egui::Ui) {
+        if let Some(result) = self.script_run.as_ref().and_then(|run| run.result()) {
+            self.script_result = Some(result);
+            self.script_run = None;
+        }
+
+        ui.heading("Script");
+        ui.horizontal(|ui| {
+            ui.add_enabled(self.script_run.is_none(), egui::TextEdit::singleline(&mut self.script_path));
+            if let Some(run) = &self.script_run {
+                if ui.button("Stop").clicked() {
+                    run.stop();
+                }
+            } else if ui.button("Run").clicked() {
+                self.script_result = None;
+                self.script_run = Some(ScriptRun::start(
+                    PathBuf::from(&self.script_path),
+                    self.dispatch_command_s.clone(),
+                    self.listener_msgs_s.clone(),
+                ));
+            }
+        });
+        match &self.script_result {
+            _ if self.script_run.is_some() => {
+                ui.label("Running...");
+            }
+            Some(Ok(())) => {
+                ui.colored_label(egui::Color32::GREEN, "Passed");
+            }
+            Some(Err(reason)) => {
+                ui.colored_label(egui::Color32::RED, format!("Failed: {}", reason));
+            }
+            None => {}
+        }
+    }
 
-                ui.style_mut().spacing.slider_width = 500.0;
+    fn calibration_panel(&mut self, ui: &mut egui::Ui) {
+        ui.heading("IMU Calibration");
+        let wizard = &mut self.calibration_wizard;
 
-                let mut new_setpoint = self.controller_setpoint;
+        for sample in self.imu_samples_r.try_iter() {
+            if wizard.add_sample(sample) {
+                let _ = self.listener_msgs_s.try_send(ListenerMsg::Calibration(CalibrationMsg::Capture(false)));
+            }
+        }
+        if wizard.is_capturing() {
+            ui.add(egui::ProgressBar::new(wizard.capture_progress()).show_percentage());
+            ui.ctx().request_repaint();
+        }
+
+        match wizard.step {
+            WizardStep::Idle => {
+                ui.add(egui::DragValue::new(&mut wizard.samples_per_capture).clamp_range(10..=5000).prefix("Samples per capture: "));
+                if ui.button("Start Calibration").clicked() {
+                    wizard.start();
+                }
+            }
+            WizardStep::GyroBias => {
+                ui.label("Place the robot on a level surface and keep it still.");
+                if ui.add_enabled(!wizard.is_capturing(), egui::Button::new("Capture Stationary")).clicked() {
+                    wizard.begin_capture();
+                    let _ = self.listener_msgs_s.try_send(ListenerMsg::Calibration(CalibrationMsg::Capture(true)));
+                }
+            }
+            WizardStep::Orientations => {
+                let g = wizard.gyro_bias;
+                ui.label(format!("Gyro bias: {:.4}, {:.4}, {:.4}", g[0], g[1], g[2]));
+                ui.label(format!(
+                    "Hold the robot still in a new orientation (e.g. each face up/down, then some in between). Captured: {} (6 needed, 9 or more best)",
+                    wizard.orientations.len()
+                ));
                 ui.horizontal(|ui| {
-                    if ui.button("Zero Setpoint").clicked() {
-                        new_setpoint = 0.0;
+                    if ui.add_enabled(!wizard.is_capturing(), egui::Button::new("Capture Orientation")).clicked() {
+                        wizard.begin_capture();
+                        let _ = self.listener_msgs_s.try_send(ListenerMsg::Calibration(CalibrationMsg::Capture(true)));
+                    }
+                    if ui.add_enabled(!wizard.is_capturing() && wizard.orientations.len() >= 6, egui::Button::new("Compute")).clicked() {
+                        wizard.compute();
                     }
-                    ui.add(egui::Slider::new(&mut new_setpoint, -5.0..=5.0).text("Controller Setpoint"));
                 });
-                
-                // If value changed, send it to the ODrive
-                if new_setpoint != self.controller_setpoint
-                {
-                    self.controller_setpoint = new_setpoint;
-                    let max_msg_len = 9; // TODO: Make configurable, later. This is a limitation of DMA Serial read on the Nucleo
-                    let msg = format!("sp:{:.4}", self.controller_setpoint)[..max_msg_len].to_string();
-                    let _ = self.dispatch_command_s.send(msg);
+            }
+            WizardStep::Done => {
+                match &wizard.result {
+                    Some(Ok(cal)) => {
+                        ui.monospace(cal.to_string());
+                        match wizard.fit.and_then(|fit| fit.rms_error) {
+                            Some(rms_error) => ui.label(format!("Fit RMS error: {:.4} ({} orientations)", rms_error, wizard.orientations.len())),
+                            None => ui.label("Fit quality needs at least 7 orientations, as any 6 fit exactly"),
+                        };
+                        let cal = *cal;
+                        ui.horizontal(|ui| {
+                            if ui.button("Apply").clicked() {
+                                let _ = self.listener_msgs_s.try_send(ListenerMsg::Calibration(CalibrationMsg::Apply(cal)));
+                            }
+                            if ui.button("Save to File").clicked() {
+                                if let Err(e) = cal.save("imu_calibration.txt") {
+                                    println!("Could not save IMU calibration: {}", e);
+                                }
+                            }
+                        });
+                    }
+                    Some(Err(e)) => {
+                        ui.colored_label(egui::Color32::RED, e);
+                    }
+                    None => {}
                 }
+                ui.horizontal(|ui| {
+                    if ui.button("Restart").clicked() {
+                        wizard.start();
+                    }
+                    if ui.button("Clear Applied").clicked() {
+                        let _ = self.listener_msgs_s.try_send(ListenerMsg::Calibration(CalibrationMsg::Apply(ImuCalibration::default())));
+                    }
+                });
+            }
+        }
+    }
+}
+
+impl eframe::App for CommandDispatcherApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.sync_commands();
+
+        egui::SidePanel::right("tools_panel")
+            .show(ctx, |ui: &mut egui::Ui| {
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    match &self.recording {
+                        Some(path) => ui.label(format!("Recording to {}", path.display())),
+                        None => ui.label("Not recording to a file"),
+                    };
+                    ui.separator();
+                    self.power_panel(ui);
+                    ui.separator();
+                    self.fusion_panel(ui);
+                    ui.separator();
+                    self.calibration_panel(ui);
+                    ui.separator();
+                    self.encoder_panel(ui);
+                    ui.separator();
+                    self.parser_panel(ui);
+                    ui.separator();
+                    self.script_panel(ui);
+                });
+            });
+
+        egui::CentralPanel::default()
+            .show(ctx, |ui: &mut egui::Ui| {
+                
+                // ui.horizontal(|ui| {
+                //     ui.selectable_value(&mut self.control_mode, ControlModes::PositionCtrl, "Position Ctrl");
+                //     ui.selectable_value(&mut self.control_mode, ControlModes::VelocityCtrl, "Velocity Ctrl");
+                //     ui.selectable_value(&mut self.control_mode, ControlModes::TorqueCtrl, "Torque Ctrl");
+                //     ui.selectable_value(&mut self.control_mode, ControlModes::VoltageCtrl, "Voltage Ctrl");
+                // });
+
 
+                // The firmware's commands mean nothing to an autopilot
+                if self.mav_command.is_some() {
+                    self.mavlink_panel(ui);
+                } else {
+                    self.firmware_panel(ui);
+                }
                 ui.separator();
                 
                 // Get any new dbg msgs from the other thread
@@ -750,7 +762,7 @@ impl eframe::App for CommandDispatcherApp {
                 if ui.button("   Clear All Messages   ").clicked() {
                     self.dbg_msgs.clear();
                 }
-                let mut display_string = self.dbg_msgs.iter().fold(String::new(), |acc, s| acc + s + "\n");
+                let mut display_string = self.dbg_msgs.iter().fold(String::new(), |acc, s| acc + s.as_str() + "\n");
                 ui.add_sized(ui.available_size(), egui::TextEdit::multiline(&mut display_string));
 
                 ui.add_space(10.0);
@@ -759,32 +771,196 @@ impl eframe::App for CommandDispatcherApp {
 }
 
 fn main() {
+    let args = Args::parse();
+
+    if let Some(Subcommand::Export { capture, output, opts }) = &args.command {
+        if let Err(e) = export::export_capture(capture, output, opts.layout, opts.resample_hz) {
+            println!("Export failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Subcommand::CHeader { descriptor, output }) = &args.command {
+        let result = Descriptor::load(descriptor).and_then(|d| {
+            let name = descriptor.file_name().map_or("descriptor".into(), |name| name.to_string_lossy());
+            let header = d.c_header(&name);
+            match output {
+                Some(path) => std::fs::write(path, header)?,
+                None => print!("{}", header),
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            println!("Header generation failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Subcommand::Import(opts)) = &args.command {
+        let result = args
+            .open_recording()
+            .and_then(|(rec, _)| Ok(Pipeline::new(rec, args.open_exporter()?)))
+            .and_then(|mut pipeline| {
+                pipeline.handle(ListenerMsg::LogMalformed(args.log_malformed));
+                import::import_file(opts, &mut pipeline)
+            });
+        if let Err(e) = result {
+            println!("Import failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    
     let channel_capacity = 10;
     let (dispatch_command_s, dispatch_command_r) = crossbeam_channel::bounded::<String>(channel_capacity);
+    let dispatch_command_s = CommandSender::new(dispatch_command_s);
     let (dbg_msgs_s, dbg_msgs_r) = crossbeam_channel::bounded::<String>(channel_capacity);
+    let (listener_msgs_s, listener_msgs_r) = crossbeam_channel::bounded::<ListenerMsg>(channel_capacity);
+    let (power_stats_s, power_stats_r) = crossbeam_channel::bounded::<PowerStats>(channel_capacity);
+    // Sized to hold a whole calibration capture between GUI frames
+    let (imu_samples_s, imu_samples_r) = crossbeam_channel::bounded::<ImuSample>(5000);
+    let (parse_stats_s, parse_stats_r) = crossbeam_channel::bounded::<ParseStats>(channel_capacity);
+    let (queue_stats_s, queue_stats_r) = crossbeam_channel::bounded::<QueueStats>(channel_capacity);
+    // Sized for commands sent in bursts, e.g. by a script or the HTTP API
+    let (commands_s, commands_r) = crossbeam_channel::bounded::<String>(100);
+    let (recording_s, recording_r) = crossbeam_channel::bounded::<Option<PathBuf>>(channel_capacity);
+
+    // Lets the recording be flushed when the window closes or on Ctrl-C
+    let shutdown_s = listener_msgs_s.clone();
 
     // Listen and parse serial stream, publish to rerun viewer
-    thread::spawn(move || {
-        let _ = serial_listener(dispatch_command_r, dbg_msgs_s);
+    let headless = args.headless;
+    let log_malformed = args.log_malformed;
+    let script = args.script.clone();
+    let http = args.http;
+    let websocket = args.websocket;
+    let foxglove = args.foxglove;
+    let plotjuggler = args.plotjuggler;
+    let plotjuggler_channels = args.plotjuggler_channels.clone();
+    let mqtt_opts = args.mqtt_opts.clone();
+    let mavlink = args.mavlink_opts.mavlink.is_some();
+    let listener = thread::spawn(move || {
+        let gui = GuiLinks { dbg_msgs_s, power_stats_s, imu_samples_s, parse_stats_s, queue_stats_s, commands_s, recording_s };
+        if let Err(e) = serial_listener(args, dispatch_command_r, listener_msgs_r, gui) {
+            println!("Serial listener stopped: {}", e);
+        }
     });
 
+    if let Some(addr) = http {
+        let (cmds_s, listener_msgs_s) = (dispatch_command_s.clone(), listener_msgs_s.clone());
+        thread::spawn(move || {
+            if let Err(e) = api::serve(addr, cmds_s, listener_msgs_s) {
+                println!("HTTP API stopped: {}", e);
+            }
+        });
+    }
+    if let Some(addr) = websocket {
+        let (cmds_s, listener_msgs_s) = (dispatch_command_s.clone(), listener_msgs_s.clone());
+        thread::spawn(move || {
+            if let Err(e) = websocket::serve(addr, cmds_s, listener_msgs_s) {
+                println!("WebSocket server stopped: {}", e);
+            }
+        });
+    }
+    if let Some(addr) = foxglove {
+        let listener_msgs_s = listener_msgs_s.clone();
+        thread::spawn(move || {
+            if let Err(e) = foxglove::serve(addr, listener_msgs_s) {
+                println!("Foxglove server stopped: {}", e);
+            }
+        });
+    }
+    if let Some(target) = plotjuggler {
+        let listener_msgs_s = listener_msgs_s.clone();
+        thread::spawn(move || {
+            if let Err(e) = plotjuggler::stream(target, &plotjuggler_channels, listener_msgs_s) {
+                println!("PlotJuggler stream stopped: {}", e);
+            }
+        });
+    }
+    // Joined after the listener, so the bridge can say it's going offline
+    let mqtt_bridge = mqtt_opts.mqtt.is_some().then(|| {
+        let (cmds_s, listener_msgs_s) = (dispatch_command_s.clone(), listener_msgs_s.clone());
+        thread::spawn(move || {
+            if let Err(e) = mqtt::run(&mqtt_opts, cmds_s, listener_msgs_s) {
+                println!("MQTT bridge stopped: {}", e);
+            }
+        })
+    });
+    let join_listener = move || {
+        let _ = listener.join();
+        if let Some(bridge) = mqtt_bridge {
+            let _ = bridge.join();
+        }
+    };
+
+    if headless {
+        let script_run = script.map(|path| ScriptRun::start(path, dispatch_command_s, listener_msgs_s));
+        {
+            let (shutdown_s, script_run) = (shutdown_s.clone(), script_run.clone());
+            if let Err(e) = ctrlc::set_handler(move || {
+                // A script sitting in sleep() or wait() would otherwise hold the process open
+                if let Some(run) = &script_run {
+                    run.stop();
+                }
+                let _ = shutdown_s.send(ListenerMsg::Shutdown);
+            }) {
+                println!("Could not install Ctrl-C handler: {}", e);
+            }
+        }
+        // Without a script, run until Ctrl-C
+        let Some(script_run) = script_run else {
+            join_listener();
+            return;
+        };
+        let result = script_run.wait();
+        let _ = shutdown_s.send(ListenerMsg::Shutdown);
+        join_listener();
+        if result.is_err() {
+            std::process::exit(1);
+        }
+        return;
+    }
 
     let command_dispatcher_app = CommandDispatcherApp {
         dbg_msgs: VecDeque::<String>::new(),
         control_mode: ControlModes::PositionCtrl,
         controller_setpoint: 0.0,
         dbg_msg_channel_r: dbg_msgs_r,
-        dispatch_command_s: dispatch_command_s,
+        dispatch_command_s,
+        power_stats: PowerStats::default(),
+        battery_model: BatteryModel::default(),
+        power_stats_r,
+        fusion_config: FusionConfig::default(),
+        calibration_wizard: CalibrationWizard::default(),
+        imu_samples_r,
+        encoder_config: EncoderConfig::default(),
+        parse_stats: ParseStats::default(),
+        parse_stats_r,
+        queue_stats: QueueStats::default(),
+        queue_stats_r,
+        log_malformed,
+        script_path: script.map_or_else(|| "test.rhai".to_string(), |path| path.display().to_string()),
+        script_run: None,
+        script_result: None,
+        own_commands: VecDeque::new(),
+        commands_r,
+        recording: None,
+        recording_r,
+        listener_msgs_s,
+        mav_command: mavlink.then_some(CommandLong { command: 400, params: [0.0; 7] }),
     };
     // Egui app to send system commands
-    let mut native_options = eframe::NativeOptions::default();
-    native_options.maximized = false;
-    native_options.decorated = true;
-    native_options.default_theme = Theme::Dark;
-    native_options.hardware_acceleration = eframe::HardwareAcceleration::Preferred;
-    native_options.initial_window_size = Option::from(egui::Vec2::new(1000.0, 400.0));
+    let native_options = eframe::NativeOptions {
+        maximized: false,
+        decorated: true,
+        default_theme: Theme::Dark,
+        hardware_acceleration: eframe::HardwareAcceleration::Preferred,
+        initial_window_size: Option::from(egui::Vec2::new(1000.0, 400.0)),
+        ..Default::default()
+    };
 
     let _ = eframe::run_native("Mission Control", native_options, Box::new(
         |creation_context| {
@@ -794,4 +970,7 @@ fn main() {
             };
         creation_context.egui_ctx.set_style(style);
         Box::new(command_dispatcher_app)}));
+
+    let _ = shutdown_s.send(ListenerMsg::Shutdown);
+    join_listener();
 }
\ No newline at end of file
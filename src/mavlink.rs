@@ -0,0 +1,704 @@
+//! MAVLink, for autopilots rather than the firmware, over the serial port or UDP. Frames
+//! are decoded into the same `header:value` lines the firmware sends, so they go through the
+//! [`Pipeline`] unchanged:
+//!
+//! ```text
+//! ATTITUDE        roll, pitch, yaw, pitchspeed   imu_r, imu_p, imu_y (deg), imu_p_dot (deg/s)
+//! SCALED_IMU      xacc.., xgyro..                acc_* (g), gyr_* (deg/s)
+//! RAW_IMU         as SCALED_IMU                  ArduPilot fills it in mG and mrad/s
+//! SYS_STATUS      voltage_battery, current_*     bus_voltage (V), bus_current (A)
+//! COMMAND_ACK     command, result                dbg_msg
+//! STATUSTEXT      text                           dbg_msg
+//! ```
+//!
+//! MAVLink v1 frames are understood as well. Commands are sent as COMMAND_LONG, written
+//! `mav_cmd:<command>,<param1>,...` with trailing zero params left out, e.g. `mav_cmd:400,1`
+//! to arm; the firmware's own commands have no MAVLink equivalent and are ignored.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, Sender};
+
+use crate::capture::CaptureWriter;
+use crate::pipeline::{ListenerMsg, Pipeline};
+use crate::serial::{Chunk, OverflowPolicy, SerialLink, READ_QUEUE_CAPACITY};
+
+const STX_V1: u8 = 0xFE;
+const STX_V2: u8 = 0xFD;
+const HEADER_LEN_V1: usize = 6;
+const HEADER_LEN_V2: usize = 10;
+const SIGNATURE_LEN: usize = 13;
+const INCOMPAT_FLAG_SIGNED: u8 = 0x01;
+
+const HEARTBEAT: u32 = 0;
+const SYS_STATUS: u32 = 1;
+const SCALED_IMU: u32 = 26;
+const RAW_IMU: u32 = 27;
+const ATTITUDE: u32 = 30;
+const COMMAND_LONG: u32 = 76;
+const COMMAND_ACK: u32 = 77;
+const STATUSTEXT: u32 = 253;
+
+const MAV_TYPE_GCS: u8 = 6;
+const MAV_AUTOPILOT_INVALID: u8 = 8;
+const MAV_MODE_FLAG_SAFETY_ARMED: u8 = 0x80;
+
+const COMMAND_PREFIX: &str = "mav_cmd:";
+// How often the UDP reader wakes up to notice a stop
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+// Seconds between queue depth updates
+const QUEUE_STATS_PERIOD: f64 = 0.5;
+
+/// Commonly sent MAV_CMD values, offered in the command window.
+pub const COMMANDS: &[(&str, u16)] = &[
+    ("COMPONENT_ARM_DISARM", 400),
+    ("DO_SET_MODE", 176),
+    ("PREFLIGHT_CALIBRATION", 241),
+    ("PREFLIGHT_REBOOT_SHUTDOWN", 246),
+    ("SET_MESSAGE_INTERVAL", 511),
+    ("REQUEST_MESSAGE", 512),
+];
+
+// Seed of the checksum of each message, from the common dialect. Messages without one
+// here can't be checked, so are dropped.
+fn crc_extra(msgid: u32) -> Option<u8> {
+    let extra = match msgid {
+        HEARTBEAT => 50,
+        SYS_STATUS => 124,
+        SCALED_IMU => 170,
+        RAW_IMU => 144,
+        ATTITUDE => 39,
+        COMMAND_LONG => 152,
+        COMMAND_ACK => 143,
+        STATUSTEXT => 83,
+        _ => return None,
+    };
+    Some(extra)
+}
+
+// CRC-16/MCRF4XX, as MAVLink calls X.25
+fn crc(bytes: &[u8], extra: u8) -> u16 {
+    bytes.iter().chain([extra].iter()).fold(0xFFFF, |crc: u16, &b| {
+        let tmp = b ^ crc as u8;
+        let tmp = tmp ^ (tmp << 4);
+        (crc >> 8) ^ (tmp as u16) << 8 ^ (tmp as u16) << 3 ^ (tmp as u16) >> 4
+    })
+}
+
+// Fields past the end of a payload are zero, as MAVLink 2 trims trailing zeros
+fn field<const N: usize>(payload: &[u8], offset: usize) -> [u8; N] {
+    let mut bytes = [0; N];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = payload.get(offset + i).copied().unwrap_or(0);
+    }
+    bytes
+}
+
+fn f32_at(payload: &[u8], offset: usize) -> f32 {
+    f32::from_le_bytes(field(payload, offset))
+}
+
+fn i16_at(payload: &[u8], offset: usize) -> i16 {
+    i16::from_le_bytes(field(payload, offset))
+}
+
+fn u8_at(payload: &[u8], offset: usize) -> u8 {
+    payload.get(offset).copied().unwrap_or(0)
+}
+
+fn u16_at(payload: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(field(payload, offset))
+}
+
+/// A message with a valid checksum.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub sysid: u8,
+    pub compid: u8,
+    pub msgid: u32,
+    pub payload: Vec<u8>,
+}
+
+/// Splits a byte stream into messages, skipping anything that isn't a whole frame with a
+/// valid checksum.
+#[derive(Default)]
+pub struct Parser {
+    buf: Vec<u8>,
+}
+
+impl Parser {
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Forget the partial frame held, after bytes following it were lost.
+    pub fn resync(&mut self) {
+        self.buf.clear();
+    }
+
+    pub fn next_message(&mut self) -> Option<Message> {
+        loop {
+            let Some(start) = self.buf.iter().position(|&b| b == STX_V2 || b == STX_V1) else {
+                self.buf.clear();
+                return None;
+            };
+            self.buf.drain(..start);
+            let v2 = self.buf[0] == STX_V2;
+            let header_len = if v2 { HEADER_LEN_V2 } else { HEADER_LEN_V1 };
+            if self.buf.len() < header_len {
+                return None;
+            }
+            let len = self.buf[1] as usize;
+            let signature_len = if v2 && self.buf[2] & INCOMPAT_FLAG_SIGNED != 0 { SIGNATURE_LEN } else { 0 };
+            let frame_len = header_len + len + 2 + signature_len;
+            if self.buf.len() < frame_len {
+                return None;
+            }
+            let (sysid, compid, msgid) = if v2 {
+                (self.buf[5], self.buf[6], u32::from_le_bytes([self.buf[7], self.buf[8], self.buf[9], 0]))
+            } else {
+                (self.buf[3], self.buf[4], self.buf[5] as u32)
+            };
+            let checksum = u16_at(&self.buf, header_len + len);
+            // A bad or unknown frame may be a stray STX byte, so look for one right after it
+            if crc_extra(msgid).map(|extra| crc(&self.buf[1..header_len + len], extra)) != Some(checksum) {
+                self.buf.drain(..1);
+                continue;
+            }
+            let payload = self.buf[header_len..header_len + len].to_vec();
+            self.buf.drain(..frame_len);
+            return Some(Message { sysid, compid, msgid, payload });
+        }
+    }
+}
+
+/// Builds MAVLink 2 frames sent as one system and component.
+pub struct Encoder {
+    sysid: u8,
+    compid: u8,
+    seq: u8,
+}
+
+impl Encoder {
+    pub fn new(sysid: u8, compid: u8) -> Self {
+        Encoder { sysid, compid, seq: 0 }
+    }
+
+    // A frame of a message known to crc_extra, with trailing zeros trimmed
+    fn frame(&mut self, msgid: u32, payload: &[u8]) -> Vec<u8> {
+        let extra = crc_extra(msgid).unwrap_or(0);
+        let len = payload.iter().rposition(|&b| b != 0).map_or(1, |i| i + 1);
+        let id = msgid.to_le_bytes();
+        let mut frame = vec![STX_V2, len as u8, 0, 0, self.seq, self.sysid, self.compid, id[0], id[1], id[2]];
+        frame.extend_from_slice(&payload[..len]);
+        let checksum = crc(&frame[1..], extra);
+        frame.extend_from_slice(&checksum.to_le_bytes());
+        self.seq = self.seq.wrapping_add(1);
+        frame
+    }
+
+    /// A COMMAND_LONG frame for the component `target` = (sysid, compid).
+    pub fn command_long(&mut self, command: &CommandLong, target: (u8, u8)) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(33);
+        for param in command.params {
+            payload.extend_from_slice(&param.to_le_bytes());
+        }
+        payload.extend_from_slice(&command.command.to_le_bytes());
+        // No confirmation, this is the first transmission
+        payload.extend_from_slice(&[target.0, target.1, 0]);
+        self.frame(COMMAND_LONG, &payload)
+    }
+}
+
+/// A MAV_CMD with its seven params, as sent in COMMAND_LONG.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CommandLong {
+    pub command: u16,
+    pub params: [f32; 7],
+}
+
+impl CommandLong {
+    /// The command as dispatched, e.g. `mav_cmd:400,1`.
+    pub fn encode(&self) -> String {
+        let used = self.params.iter().rposition(|&p| p != 0.0).map_or(0, |i| i + 1);
+        let mut s = format!("{}{}", COMMAND_PREFIX, self.command);
+        for param in &self.params[..used] {
+            s.push_str(&format!(",{}", param));
+        }
+        s
+    }
+
+    /// Parse a dispatched command; missing params are zero.
+    pub fn decode(s: &str) -> Option<CommandLong> {
+        let mut fields = s.trim().strip_prefix(COMMAND_PREFIX)?.split(',').map(str::trim);
+        let command = fields.next()?.parse().ok()?;
+        let mut params = [0.0; 7];
+        for (i, field) in fields.enumerate() {
+            *params.get_mut(i)? = field.parse().ok()?;
+        }
+        Some(CommandLong { command, params })
+    }
+}
+
+impl fmt::Display for CommandLong {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.encode())
+    }
+}
+
+fn ack_result(result: u8) -> &'static str {
+    match result {
+        0 => "ACCEPTED",
+        1 => "TEMPORARILY_REJECTED",
+        2 => "DENIED",
+        3 => "UNSUPPORTED",
+        4 => "FAILED",
+        5 => "IN_PROGRESS",
+        6 => "CANCELLED",
+        _ => "UNKNOWN",
+    }
+}
+
+/// Turns messages into channel samples. Once an autopilot's heartbeat was heard, only
+/// messages of its system are decoded and commands are sent to it.
+#[derive(Default)]
+pub struct Decoder {
+    target: Option<(u8, u8)>,
+    armed: Option<bool>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The autopilot commands go to, as (sysid, compid).
+    pub fn target(&self) -> Option<(u8, u8)> {
+        self.target
+    }
+
+    /// `header:value` lines for the pipeline, as the firmware would send them.
+    pub fn decode(&mut self, msg: &Message) -> Vec<String> {
+        let p = &msg.payload;
+        let mut lines = Vec::new();
+        if msg.msgid == HEARTBEAT {
+            // Ground stations, e.g. another one on the same UDP port, aren't autopilots, nor
+            // are components like gimbals or cameras that say they have none
+            if u8_at(p, 4) == MAV_TYPE_GCS || u8_at(p, 5) == MAV_AUTOPILOT_INVALID {
+                return lines;
+            }
+            if self.target.is_none() {
+                self.target = Some((msg.sysid, msg.compid));
+                lines.push(format!("dbg_msg:MAVLink autopilot {}/{}", msg.sysid, msg.compid));
+            }
+        }
+        if self.target.is_some_and(|(sysid, _)| sysid != msg.sysid) {
+            return lines;
+        }
+        match msg.msgid {
+            // Only the autopilot component's armed state counts
+            HEARTBEAT if self.target.is_some_and(|(_, compid)| compid == msg.compid) => {
+                let armed = u8_at(p, 6) & MAV_MODE_FLAG_SAFETY_ARMED != 0;
+                if self.armed != Some(armed) {
+                    self.armed = Some(armed);
+                    lines.push(format!("dbg_msg:MAVLink {}", if armed { "armed" } else { "disarmed" }));
+                }
+            }
+            SYS_STATUS => {
+                let (voltage, current) = (u16_at(p, 14), i16_at(p, 16));
+                // Both have a value for unknown
+                if voltage != u16::MAX {
+                    lines.push(format!("bus_voltage:{}", voltage as f32 / 1000.0));
+                }
+                if current != -1 {
+                    lines.push(format!("bus_current:{}", current as f32 / 100.0));
+                }
+            }
+            ATTITUDE => {
+                lines.push(format!("imu_r:{}", f32_at(p, 4).to_degrees()));
+                lines.push(format!("imu_p:{}", f32_at(p, 8).to_degrees()));
+                lines.push(format!("imu_y:{}", f32_at(p, 12).to_degrees()));
+                lines.push(format!("imu_p_dot:{}", f32_at(p, 20).to_degrees()));
+            }
+            SCALED_IMU | RAW_IMU => {
+                let offset = if msg.msgid == RAW_IMU { 8 } else { 4 };
+                for (i, axis) in ["x", "y", "z"].iter().enumerate() {
+                    lines.push(format!("acc_{}:{}", axis, i16_at(p, offset + 2 * i) as f32 / 1000.0));
+                }
+                for (i, axis) in ["x", "y", "z"].iter().enumerate() {
+                    let mrad_s = i16_at(p, offset + 6 + 2 * i) as f32;
+                    lines.push(format!("gyr_{}:{}", axis, (mrad_s / 1000.0).to_degrees()));
+                }
+            }
+            COMMAND_ACK => {
+                lines.push(format!("dbg_msg:MAVLink command {} {}", u16_at(p, 0), ack_result(u8_at(p, 2))));
+            }
+            STATUSTEXT => {
+                let text = p.get(1..).unwrap_or_default();
+                let text = &text[..text.iter().position(|&b| b == 0).unwrap_or(text.len())];
+                lines.push(format!("dbg_msg:{}", String::from_utf8_lossy(text).trim()));
+            }
+            _ => {}
+        }
+        lines
+    }
+}
+
+/// Where MAVLink is spoken: the serial port, or a UDP address to listen on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoint {
+    Serial,
+    Udp(SocketAddr),
+}
+
+/// Parse `serial` or `udp:ADDR`, e.g. `udp:0.0.0.0:14550`.
+pub fn parse_endpoint(s: &str) -> Result<Endpoint, String> {
+    if s == "serial" {
+        return Ok(Endpoint::Serial);
+    }
+    let addr = s.strip_prefix("udp:").ok_or_else(|| format!("expected `serial` or `udp:ADDR`, got '{}'", s))?;
+    addr.parse().map(Endpoint::Udp).map_err(|e| format!("bad UDP address '{}': {}", addr, e))
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct MavlinkOpts {
+    /// Talk MAVLink to an autopilot, see mavlink.rs: on the serial port, or `udp:ADDR` to
+    /// listen on a UDP address such as udp:0.0.0.0:14550
+    #[arg(long, value_name = "ENDPOINT", num_args = 0..=1, default_missing_value = "serial", value_parser = parse_endpoint, conflicts_with = "odrive")]
+    pub mavlink: Option<Endpoint>,
+
+    /// System ID of sent messages
+    #[arg(long, default_value_t = 255, requires = "mavlink")]
+    pub mavlink_sysid: u8,
+
+    /// Component ID of sent messages
+    #[arg(long, default_value_t = 190, requires = "mavlink")]
+    pub mavlink_compid: u8,
+}
+
+// UDP replies go to whoever sent last
+struct UdpLink {
+    socket: UdpSocket,
+    peer: Arc<Mutex<Option<SocketAddr>>>,
+    start_time: Instant,
+    stop: Arc<AtomicBool>,
+    reader: JoinHandle<()>,
+}
+
+fn read_udp(socket: UdpSocket, start_time: Instant, peer: Arc<Mutex<Option<SocketAddr>>>, chunks_s: Sender<Chunk>, stop: Arc<AtomicBool>) {
+    let mut buf = [0u8; 65536];
+    let mut seq = 0;
+    while !stop.load(Ordering::Relaxed) {
+        match socket.recv_from(&mut buf) {
+            Ok((n, from)) => {
+                *peer.lock().unwrap() = Some(from);
+                // Datagrams are lost anyway when nobody keeps up
+                let _ = chunks_s.try_send(Chunk { t: start_time.elapsed().as_secs_f64(), seq, bytes: buf[..n].to_vec() });
+                seq += 1;
+            }
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted) => {}
+            Err(e) => {
+                println!("MAVLink UDP read failed: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+enum Transport {
+    // Frames are binary, so they are written to the port here rather than by the link
+    Serial { link: SerialLink, port: Box<dyn serialport::SerialPort>, _cmds_s: Sender<String> },
+    Udp(UdpLink),
+}
+
+/// The transport of a MAVLink session, see [`open`].
+pub struct Link {
+    transport: Transport,
+    chunks_r: Receiver<Chunk>,
+}
+
+impl Link {
+    pub fn serial(port: Box<dyn serialport::SerialPort>, overflow: OverflowPolicy) -> io::Result<Self> {
+        let writer = port.try_clone()?;
+        let (cmds_s, cmds_r) = crossbeam_channel::unbounded();
+        let link = SerialLink::start(port, overflow, cmds_r)?;
+        let chunks_r = link.chunks_r.clone();
+        Ok(Link { transport: Transport::Serial { link, port: writer, _cmds_s: cmds_s }, chunks_r })
+    }
+
+    pub fn udp(addr: SocketAddr) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_read_timeout(Some(READ_TIMEOUT))?;
+        let (start_time, stop) = (Instant::now(), Arc::new(AtomicBool::new(false)));
+        let peer = Arc::new(Mutex::new(None));
+        let (chunks_s, chunks_r) = crossbeam_channel::bounded(READ_QUEUE_CAPACITY);
+        let reader = {
+            let (socket, peer, stop) = (socket.try_clone()?, peer.clone(), stop.clone());
+            thread::spawn(move || read_udp(socket, start_time, peer, chunks_s, stop))
+        };
+        Ok(Link { transport: Transport::Udp(UdpLink { socket, peer, start_time, stop, reader }), chunks_r })
+    }
+
+    fn elapsed(&self) -> f64 {
+        match &self.transport {
+            Transport::Serial { link, .. } => link.elapsed(),
+            Transport::Udp(udp) => udp.start_time.elapsed().as_secs_f64(),
+        }
+    }
+
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        match &mut self.transport {
+            Transport::Serial { port, .. } => port.write_all(frame),
+            Transport::Udp(udp) => {
+                let peer = udp.peer.lock().unwrap().ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "nothing received yet"))?;
+                udp.socket.send_to(frame, peer).map(|_| ())
+            }
+        }
+    }
+
+    fn stop(self) {
+        match self.transport {
+            Transport::Serial { link, .. } => link.stop(),
+            Transport::Udp(udp) => {
+                udp.stop.store(true, Ordering::Relaxed);
+                let _ = udp.reader.join();
+            }
+        }
+    }
+}
+
+/// Open the transport of `opts.mavlink`, with the port `open_port` gives for a serial one.
+pub fn open(
+    opts: &MavlinkOpts,
+    overflow: OverflowPolicy,
+    open_port: impl FnOnce() -> Result<Box<dyn serialport::SerialPort>, Box<dyn std::error::Error>>,
+) -> Result<Link, Box<dyn std::error::Error>> {
+    match opts.mavlink {
+        Some(Endpoint::Udp(addr)) => {
+            let link = Link::udp(addr).map_err(|e| format!("could not listen on udp://{}: {}", addr, e))?;
+            println!("Listening for MAVLink on udp://{}", addr);
+            Ok(link)
+        }
+        _ => Ok(Link::serial(open_port()?, overflow)?),
+    }
+}
+
+/// Run a MAVLink session on `link` until a shutdown is received on `listener_msgs_r` or the
+/// link fails. Decoded lines and sent commands are recorded to `capture` if given.
+pub fn run(
+    mut link: Link,
+    opts: &MavlinkOpts,
+    capture: Option<PathBuf>,
+    mut pipeline: Pipeline,
+    mut cmds_r: Receiver<String>,
+    listener_msgs_r: Receiver<ListenerMsg>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut capture = match &capture {
+        Some(path) => Some(CaptureWriter::create(path)?),
+        None => None,
+    };
+    let (mut parser, mut decoder) = (Parser::default(), Decoder::new());
+    let mut encoder = Encoder::new(opts.mavlink_sysid, opts.mavlink_compid);
+    let chunks_r = link.chunks_r.clone();
+    let mut stats_t = 0.0;
+    let mut next_seq = 0;
+    loop {
+        crossbeam_channel::select! {
+            recv(chunks_r) -> chunk => {
+                let Ok(chunk) = chunk else {
+                    break;
+                };
+                if chunk.after_gap(&mut next_seq) {
+                    parser.resync();
+                }
+                parser.push(&chunk.bytes);
+                while let Some(msg) = parser.next_message() {
+                    for line in decoder.decode(&msg) {
+                        if let Some(capture) = &mut capture {
+                            let _ = capture.line(chunk.t, &line);
+                        }
+                        pipeline.line(chunk.t, line.as_bytes());
+                    }
+                }
+            },
+            recv(cmds_r) -> command => {
+                // Headless sessions may have no command sender left, they end on a shutdown
+                let Ok(command) = command else {
+                    cmds_r = crossbeam_channel::never();
+                    continue;
+                };
+                let Some(decoded) = CommandLong::decode(&command) else {
+                    println!("Ignored command '{}': not a MAVLink command", command);
+                    continue;
+                };
+                let Some(target) = decoder.target() else {
+                    println!("Ignored command '{}': no autopilot heard from yet", command);
+                    continue;
+                };
+                if let Err(e) = link.send(&encoder.command_long(&decoded, target)) {
+                    println!("Failed to send MAVLink command: {}", e);
+                    continue;
+                }
+                let t = link.elapsed();
+                if let Some(capture) = &mut capture {
+                    let _ = capture.command(t, &command);
+                }
+                pipeline.command(t, &command);
+            },
+            recv(listener_msgs_r) -> msg => match msg {
+                Ok(ListenerMsg::Shutdown) | Err(_) => break,
+                Ok(msg) => pipeline.handle(msg),
+            },
+        }
+        if let Transport::Serial { link: serial, .. } = &mut link.transport {
+            let t = serial.elapsed();
+            if t - stats_t >= QUEUE_STATS_PERIOD {
+                stats_t = t;
+                pipeline.queue_stats(t, serial.stats());
+            }
+        }
+    }
+    link.stop();
+    if let Some(capture) = &mut capture {
+        capture.flush()?;
+    }
+    pipeline.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attitude(roll: f32, pitch: f32, pitchspeed: f32) -> Vec<u8> {
+        let mut payload = vec![0; 28];
+        payload[4..8].copy_from_slice(&roll.to_le_bytes());
+        payload[8..12].copy_from_slice(&pitch.to_le_bytes());
+        payload[20..24].copy_from_slice(&pitchspeed.to_le_bytes());
+        payload
+    }
+
+    fn heartbeat(mav_type: u8, base_mode: u8) -> Vec<u8> {
+        vec![0, 0, 0, 0, mav_type, 3, base_mode, 4, 3]
+    }
+
+    fn decode_all(parser: &mut Parser, decoder: &mut Decoder) -> Vec<String> {
+        std::iter::from_fn(|| parser.next_message()).flat_map(|msg| decoder.decode(&msg)).collect()
+    }
+
+    #[test]
+    fn checksum_matches_reference() {
+        // CRC-16/MCRF4XX check value of "123456789"
+        assert_eq!(crc(b"12345678", b'9'), 0x6F91);
+    }
+
+    #[test]
+    fn frames_round_trip_through_noise() {
+        let mut autopilot = Encoder::new(1, 1);
+        let mut bytes = vec![0xFD, 0x00, 0x42];
+        bytes.extend(autopilot.frame(HEARTBEAT, &heartbeat(2, MAV_MODE_FLAG_SAFETY_ARMED)));
+        bytes.extend([0xFE, 0x13]);
+        bytes.extend(autopilot.frame(ATTITUDE, &attitude(std::f32::consts::FRAC_PI_2, 0.0, -1.0)));
+
+        let mut parser = Parser::default();
+        let mut decoder = Decoder::new();
+        // Split mid-frame, as serial reads are
+        let (a, b) = bytes.split_at(20);
+        parser.push(a);
+        let mut lines = decode_all(&mut parser, &mut decoder);
+        parser.push(b);
+        lines.extend(decode_all(&mut parser, &mut decoder));
+        assert_eq!(lines, ["dbg_msg:MAVLink autopilot 1/1", "dbg_msg:MAVLink armed", "imu_r:90", "imu_p:0", "imu_y:0", "imu_p_dot:-57.29578"]);
+        assert_eq!(decoder.target(), Some((1, 1)));
+    }
+
+    #[test]
+    fn corrupt_frames_are_dropped() {
+        let mut frame = Encoder::new(1, 1).frame(ATTITUDE, &attitude(1.0, 0.0, 0.0));
+        frame[12] ^= 0x01;
+        let mut parser = Parser::default();
+        parser.push(&frame);
+        assert_eq!(parser.next_message(), None);
+    }
+
+    #[test]
+    fn v1_frames_are_understood() {
+        let payload = heartbeat(2, 0);
+        let mut frame = vec![STX_V1, payload.len() as u8, 7, 1, 1, HEARTBEAT as u8];
+        frame.extend(&payload);
+        let checksum = crc(&frame[1..], 50);
+        frame.extend(checksum.to_le_bytes());
+        let mut parser = Parser::default();
+        parser.push(&frame);
+        assert_eq!(parser.next_message(), Some(Message { sysid: 1, compid: 1, msgid: HEARTBEAT, payload }));
+    }
+
+    #[test]
+    fn sys_status_and_imu_units() {
+        let mut sys_status = vec![0; 31];
+        sys_status[14..16].copy_from_slice(&12600u16.to_le_bytes());
+        sys_status[16..18].copy_from_slice(&(-1i16).to_le_bytes());
+        let mut raw_imu = vec![0; 26];
+        raw_imu[12..14].copy_from_slice(&(-1000i16).to_le_bytes());
+        raw_imu[14..16].copy_from_slice(&1000i16.to_le_bytes());
+
+        let mut decoder = Decoder::new();
+        let msg = |msgid, payload| Message { sysid: 1, compid: 1, msgid, payload };
+        assert_eq!(decoder.decode(&msg(SYS_STATUS, sys_status)), ["bus_voltage:12.6"]);
+        assert_eq!(
+            decoder.decode(&msg(RAW_IMU, raw_imu)),
+            ["acc_x:0", "acc_y:0", "acc_z:-1", "gyr_x:57.29578", "gyr_y:0", "gyr_z:0"]
+        );
+    }
+
+    #[test]
+    fn only_the_first_autopilot_is_decoded() {
+        let mut decoder = Decoder::new();
+        let msg = |sysid, msgid, payload| Message { sysid, compid: 1, msgid, payload };
+        assert!(decoder.decode(&msg(255, HEARTBEAT, heartbeat(MAV_TYPE_GCS, 0))).is_empty());
+        decoder.decode(&msg(2, HEARTBEAT, heartbeat(2, 0)));
+        assert!(decoder.decode(&msg(3, ATTITUDE, attitude(1.0, 0.0, 0.0))).is_empty());
+        assert_eq!(decoder.decode(&msg(2, ATTITUDE, attitude(0.0, 0.0, 0.0))).len(), 4);
+    }
+
+    #[test]
+    fn components_without_an_autopilot_are_not_targeted() {
+        let mut decoder = Decoder::new();
+        let msg = |compid, payload| Message { sysid: 1, compid, msgid: HEARTBEAT, payload };
+        // A gimbal heartbeat first: MAV_TYPE_GIMBAL, MAV_AUTOPILOT_INVALID
+        let mut gimbal = heartbeat(26, MAV_MODE_FLAG_SAFETY_ARMED);
+        gimbal[5] = MAV_AUTOPILOT_INVALID;
+        assert!(decoder.decode(&msg(154, gimbal)).is_empty());
+        assert_eq!(decoder.target(), None);
+        assert_eq!(decoder.decode(&msg(1, heartbeat(2, 0))), ["dbg_msg:MAVLink autopilot 1/1", "dbg_msg:MAVLink disarmed"]);
+
+        // Another component of the system that claims an autopilot doesn't change the armed state
+        assert!(decoder.decode(&msg(191, heartbeat(18, MAV_MODE_FLAG_SAFETY_ARMED))).is_empty());
+        assert_eq!(decoder.decode(&msg(1, heartbeat(2, MAV_MODE_FLAG_SAFETY_ARMED))), ["dbg_msg:MAVLink armed"]);
+        assert_eq!(decoder.target(), Some((1, 1)));
+    }
+
+    #[test]
+    fn command_long_text_and_frame() {
+        let command = CommandLong::decode("mav_cmd:400, 1").unwrap();
+        assert_eq!(command, CommandLong { command: 400, params: [1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0] });
+        assert_eq!(command.encode(), "mav_cmd:400,1");
+        assert_eq!(CommandLong::decode("mav_cmd:400,1,2,3,4,5,6,7,8"), None);
+        assert_eq!(CommandLong::decode("velo_ctrl"), None);
+
+        let frame = Encoder::new(255, 190).command_long(&command, (1, 1));
+        let mut parser = Parser::default();
+        parser.push(&frame);
+        let msg = parser.next_message().unwrap();
+        assert_eq!((msg.sysid, msg.compid, msg.msgid), (255, 190, COMMAND_LONG));
+        assert_eq!(f32_at(&msg.payload, 0), 1.0);
+        assert_eq!(u16_at(&msg.payload, 28), 400);
+        assert_eq!(&msg.payload[30..], [1, 1]);
+    }
+}
@@ -0,0 +1,240 @@
+//! MQTT bridge to a broker such as Mosquitto. Samples are published to one topic per
+//! channel, named after its rerun entity path, and commands are taken from a command topic:
+//!
+//! ```text
+//! mission_control/imu/pitch       0.0123      a sample, as text
+//! mission_control/derived/power   48.2        derived channels, if listed in --mqtt-channels
+//! mission_control/status          online      retained; offline once the session ends, or drops
+//! mission_control/command         velo_ctrl   published by others, dispatched to the serial port
+//! ```
+
+use std::error::Error;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, Sender, TryRecvError};
+use rumqttc::{Client, Event, LastWill, MqttOptions, Outgoing, Packet, QoS, RecvTimeoutError};
+
+use crate::channels;
+use crate::commands::{Command, CommandSender};
+use crate::export::fmt_value;
+use crate::pipeline::ListenerMsg;
+
+const DEFAULT_PORT: u16 = 1883;
+// Upper bound between publishes
+const POLL_PERIOD: Duration = Duration::from_millis(20);
+// Samples buffered per channel between polls
+const SUBSCRIPTION_CAPACITY: usize = 1024;
+// Requests queued for the connection, e.g. while the broker is unreachable
+const REQUEST_CAPACITY: usize = 1024;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+// How long the offline status may take to be acknowledged at shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct MqttOpts {
+    /// Bridge telemetry and commands to the MQTT broker at HOST[:PORT], see mqtt.rs
+    #[arg(long, value_name = "HOST[:PORT]", num_args = 0..=1, default_missing_value = "localhost")]
+    pub mqtt: Option<String>,
+
+    /// Prepended to every topic
+    #[arg(long, default_value = "mission_control", requires = "mqtt")]
+    pub mqtt_prefix: String,
+
+    /// QoS of published samples and the command subscription
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=2), requires = "mqtt")]
+    pub mqtt_qos: u8,
+
+    /// Publish samples as retained messages, so new subscribers get the latest value
+    #[arg(long, requires = "mqtt")]
+    pub mqtt_retain: bool,
+
+    /// Channels to publish, comma-separated; every registered channel if omitted
+    #[arg(long, value_name = "CHANNELS", value_delimiter = ',', requires = "mqtt")]
+    pub mqtt_channels: Vec<String>,
+
+    #[arg(long, default_value = "mission-control", requires = "mqtt")]
+    pub mqtt_client_id: String,
+}
+
+fn qos(level: u8) -> QoS {
+    match level {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    }
+}
+
+/// Topic of a channel, derived from its rerun entity path, e.g. `imu/pitch` for `imu_p`.
+/// Anything that isn't a registered channel is taken to be a derived channel.
+pub fn channel_topic(prefix: &str, name: &str) -> String {
+    match channels::lookup(name) {
+        Some(channel) => format!("{}/{}", prefix, channel.entity_path),
+        None => format!("{}/derived/{}", prefix, name),
+    }
+}
+
+// Publish subscribed samples until the pipeline is gone. Returning drops `_stopped_s`,
+// which tells the connection to shut down.
+fn publish(client: Client, subscriptions: Vec<(String, Receiver<(f64, f64)>)>, qos: QoS, retain: bool, _stopped_s: Sender<()>) {
+    loop {
+        let mut stopped = false;
+        for (topic, samples_r) in &subscriptions {
+            loop {
+                match samples_r.try_recv() {
+                    Ok((_, v)) => {
+                        if client.publish(topic.as_str(), qos, retain, fmt_value(v)).is_err() {
+                            return;
+                        }
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        stopped = true;
+                        break;
+                    }
+                }
+            }
+        }
+        if stopped {
+            return;
+        }
+        thread::sleep(POLL_PERIOD);
+    }
+}
+
+/// Bridge to the broker in `opts` until the serial listener stops, reconnecting whenever
+/// the connection drops. Commands go to `cmds_s`, as from the command window. The status
+/// is set to offline before disconnecting, as the broker only sends the will if the session
+/// drops.
+pub fn run(opts: &MqttOpts, cmds_s: CommandSender, listener_msgs_s: Sender<ListenerMsg>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let broker = opts.mqtt.as_deref().unwrap_or("localhost");
+    let (host, port) = match broker.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| format!("bad MQTT port '{}'", port))?),
+        None => (broker, DEFAULT_PORT),
+    };
+    let (qos, prefix) = (qos(opts.mqtt_qos), opts.mqtt_prefix.trim_end_matches('/'));
+    let (status_topic, command_topic) = (format!("{}/status", prefix), format!("{}/command", prefix));
+
+    let mut options = MqttOptions::new(&opts.mqtt_client_id, host, port);
+    options.set_keep_alive(Duration::from_secs(5));
+    options.set_last_will(LastWill::new(&status_topic, "offline", QoS::AtLeastOnce, true));
+    let (client, mut connection) = Client::new(options, REQUEST_CAPACITY);
+
+    let names: Vec<String> = if opts.mqtt_channels.is_empty() {
+        channels::CHANNELS.iter().map(|channel| channel.header.to_string()).collect()
+    } else {
+        opts.mqtt_channels.clone()
+    };
+    let mut subscriptions = Vec::new();
+    for name in names {
+        let (samples_s, samples_r) = crossbeam_channel::bounded(SUBSCRIPTION_CAPACITY);
+        listener_msgs_s.send(ListenerMsg::Subscribe(name.clone(), samples_s)).map_err(|_| "serial listener stopped")?;
+        subscriptions.push((channel_topic(prefix, &name), samples_r));
+    }
+    let (stopped_s, stopped_r) = crossbeam_channel::bounded::<()>(0);
+    {
+        let (client, retain) = (client.clone(), opts.mqtt_retain);
+        thread::spawn(move || publish(client, subscriptions, qos, retain, stopped_s));
+    }
+
+    println!("MQTT bridge to {}:{}, commands on {}", host, port, command_topic);
+    // When the shutdown began, and whether the offline status has been written out since
+    let mut stopping: Option<Instant> = None;
+    let (mut offline_sent, mut disconnecting) = (false, false);
+    loop {
+        if stopping.is_none() && stopped_r.try_recv() == Err(TryRecvError::Disconnected) {
+            let _ = client.try_publish(status_topic.as_str(), QoS::AtLeastOnce, true, "offline");
+            stopping = Some(Instant::now());
+        }
+        if let Some(since) = stopping {
+            // Acknowledged once nothing is in flight
+            if !disconnecting && ((offline_sent && connection.eventloop.state.inflight() == 0) || since.elapsed() > SHUTDOWN_TIMEOUT) {
+                disconnecting = client.try_disconnect().is_ok();
+            }
+        }
+        let event = match connection.recv_timeout(POLL_PERIOD) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        match event {
+            // Subscriptions don't survive a reconnect with a clean session
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                println!("MQTT connected to {}:{}", host, port);
+                let _ = client.try_subscribe(command_topic.as_str(), qos);
+                let _ = client.try_publish(status_topic.as_str(), QoS::AtLeastOnce, true, "online");
+            }
+            Ok(Event::Incoming(Packet::Publish(msg))) if msg.topic == command_topic => {
+                let text = String::from_utf8_lossy(&msg.payload);
+                match Command::decode(&text) {
+                    Some(command) => cmds_s.send(command.encode()).map_err(|_| "serial listener stopped")?,
+                    None => println!("Ignored MQTT command '{}'", text.trim()),
+                }
+            }
+            Ok(Event::Outgoing(Outgoing::Publish(_))) if stopping.is_some() => offline_sent = true,
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+            Ok(_) => {}
+            // The will takes care of the status
+            Err(_) if stopping.is_some() => break,
+            Err(e) => {
+                println!("MQTT connection to {}:{} failed: {}", host, port, e);
+                thread::sleep(RECONNECT_DELAY);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opts(prefix: &str) -> MqttOpts {
+        MqttOpts {
+            mqtt: Some(std::env::var("MQTT_TEST_BROKER").unwrap_or_else(|_| "localhost".to_string())),
+            mqtt_prefix: prefix.to_string(),
+            mqtt_qos: 1,
+            mqtt_retain: false,
+            mqtt_channels: vec!["theta".to_string()],
+            mqtt_client_id: format!("{}-bridge", prefix),
+        }
+    }
+
+    // The retained status as seen by a new subscriber
+    fn retained_status(opts: &MqttOpts) -> Option<String> {
+        let broker = opts.mqtt.as_deref().unwrap();
+        let (host, port) = broker.rsplit_once(':').map_or((broker, DEFAULT_PORT), |(host, port)| (host, port.parse().unwrap()));
+        let (client, mut connection) = Client::new(MqttOptions::new(format!("{}-observer", opts.mqtt_prefix), host, port), 10);
+        client.subscribe(format!("{}/status", opts.mqtt_prefix), QoS::AtLeastOnce).unwrap();
+        while let Ok(event) = connection.recv_timeout(Duration::from_secs(2)) {
+            if let Ok(Event::Incoming(Packet::Publish(msg))) = event {
+                return Some(String::from_utf8_lossy(&msg.payload).into_owned());
+            }
+        }
+        None
+    }
+
+    #[test]
+    #[ignore = "needs an MQTT broker at MQTT_TEST_BROKER, or localhost:1883"]
+    fn offline_status_published_on_clean_shutdown() {
+        let opts = opts(&format!("mission_control_test_{}", std::process::id()));
+        let (cmds_s, _cmds_r) = crossbeam_channel::unbounded();
+        let cmds_s = CommandSender::new(cmds_s);
+        let (listener_msgs_s, listener_msgs_r) = crossbeam_channel::unbounded();
+        let bridge = {
+            let opts = opts.clone();
+            thread::spawn(move || run(&opts, cmds_s, listener_msgs_s).unwrap())
+        };
+        // Hold the subscription like the pipeline would, then go away
+        let Ok(ListenerMsg::Subscribe(_, samples_s)) = listener_msgs_r.recv() else {
+            panic!("expected a subscription");
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while retained_status(&opts).as_deref() != Some("online") {
+            assert!(Instant::now() < deadline, "bridge never came online");
+        }
+        drop((samples_s, listener_msgs_r));
+        bridge.join().unwrap();
+        assert_eq!(retained_status(&opts).as_deref(), Some("offline"));
+    }
+}
@@ -0,0 +1,518 @@
+//! ODrive ASCII protocol, for a serial port connected straight to an ODrive rather than to
+//! the firmware. Properties are polled with `r <property>` at a fixed rate into channels,
+//! and the firmware's commands are translated, e.g. for axis 0:
+//!
+//! ```text
+//! velo_ctrl   w axis0.controller.config.control_mode 2
+//!             w axis0.requested_state 8
+//! sp:1.5000   v 0 1.5 0
+//! calib_rtn   w axis0.requested_state 3
+//! clear_err   sc
+//! ```
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crossbeam_channel::Receiver;
+
+use crate::capture::CaptureWriter;
+use crate::commands::Command;
+use crate::framing::{Frame, Framer, DEFAULT_MAX_LINE_LEN};
+use crate::pipeline::{ListenerMsg, Pipeline};
+use crate::serial::{OverflowPolicy, SerialLink};
+
+pub(crate) const AXIS_STATE_IDLE: u32 = 1;
+pub(crate) const AXIS_STATE_FULL_CALIBRATION_SEQUENCE: u32 = 3;
+pub(crate) const AXIS_STATE_CLOSED_LOOP_CONTROL: u32 = 8;
+
+// A request is given up on if not answered by then, e.g. after a lost line
+const REPLY_TIMEOUT: f64 = 0.5;
+// Polling pauses this long after a command or a lost reply, so an error reply to the command
+// or a late reply isn't taken for the answer to the next request
+const QUIET_PERIOD: f64 = 0.1;
+// Seconds between queue depth updates
+const QUEUE_STATS_PERIOD: f64 = 0.5;
+
+/// ODrive controller modes, by value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControlMode {
+    Voltage = 0,
+    Torque = 1,
+    Velocity = 2,
+    Position = 3,
+}
+
+impl ControlMode {
+    /// The mode selected by `command`, if it selects one
+    pub fn of(command: Command) -> Option<Self> {
+        match command {
+            Command::PositionControl => Some(ControlMode::Position),
+            Command::VelocityControl => Some(ControlMode::Velocity),
+            Command::TorqueControl => Some(ControlMode::Torque),
+            Command::VoltageControl => Some(ControlMode::Voltage),
+            _ => None,
+        }
+    }
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct OdriveOpts {
+    /// Talk the ODrive ASCII protocol on the serial port instead of the firmware's, see odrive.rs
+    #[arg(long)]
+    pub odrive: bool,
+
+    /// Poll an ODrive property into a channel, e.g. `--odrive-poll vbus_voltage=bus_voltage`.
+    /// Encoder estimates, state, error and the bus voltage and current of every axis if omitted.
+    #[arg(long, value_name = "PROPERTY=HEADER", value_parser = parse_poll, requires = "odrive")]
+    pub odrive_poll: Vec<(String, String)>,
+
+    /// Poll rounds per second
+    #[arg(long, default_value_t = 20.0, value_parser = parse_rate, requires = "odrive")]
+    pub odrive_rate: f64,
+
+    /// Axes the commands go to
+    #[arg(long, value_name = "AXES", value_delimiter = ',', default_value = "0", requires = "odrive")]
+    pub odrive_axes: Vec<u8>,
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        Ok(_) => Err("must be a positive number of rounds per second".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_poll(s: &str) -> Result<(String, String), String> {
+    let (property, header) = s.split_once('=').ok_or("expected PROPERTY=HEADER")?;
+    Ok((property.trim().to_string(), header.trim().to_string()))
+}
+
+impl OdriveOpts {
+    /// The configured polls, or the defaults for the configured axes
+    pub fn polls(&self) -> Vec<(String, String)> {
+        if !self.odrive_poll.is_empty() {
+            return self.odrive_poll.clone();
+        }
+        let mut polls = vec![("vbus_voltage".to_string(), "bus_voltage".to_string()), ("ibus".to_string(), "bus_current".to_string())];
+        for axis in &self.odrive_axes {
+            for (property, header) in [("encoder.pos_estimate", "enc_pos"), ("encoder.vel_estimate", "enc_vel"), ("current_state", "axis_state"), ("error", "axis_error")] {
+                polls.push((format!("axis{}.{}", axis, property), format!("{}_{}", header, axis)));
+            }
+        }
+        polls
+    }
+}
+
+/// Translates between the ODrive ASCII protocol and the firmware's lines and commands.
+pub struct Driver {
+    // (property, channel header)
+    polls: Vec<(String, String)>,
+    axes: Vec<u8>,
+    period: f64,
+    mode: Option<ControlMode>,
+    // The poll whose reply is awaited, and when it was sent
+    in_flight: Option<(usize, f64)>,
+    // The poll to send next in the current round
+    next: usize,
+    round_t: f64,
+    // No requests are sent before then
+    quiet_until: f64,
+}
+
+impl Driver {
+    pub fn new(polls: Vec<(String, String)>, axes: &[u8], rate_hz: f64) -> Self {
+        let next = polls.len();
+        Driver {
+            polls,
+            axes: axes.to_vec(),
+            period: 1.0 / rate_hz,
+            mode: None,
+            in_flight: None,
+            next,
+            round_t: f64::NEG_INFINITY,
+            quiet_until: f64::NEG_INFINITY,
+        }
+    }
+
+    /// The next poll request if one is due at `t`. Only one request is in flight at a time,
+    /// so a reply always belongs to the last request sent.
+    pub fn poll(&mut self, t: f64) -> Option<String> {
+        if let Some((_, sent_t)) = self.in_flight {
+            if t - sent_t < REPLY_TIMEOUT {
+                return None;
+            }
+            self.in_flight = None;
+            self.quiet_until = t + QUIET_PERIOD;
+        }
+        if t < self.quiet_until || self.polls.is_empty() {
+            return None;
+        }
+        if self.next == self.polls.len() {
+            if t - self.round_t < self.period {
+                return None;
+            }
+            self.round_t = t;
+            self.next = 0;
+        }
+        let poll = self.next;
+        self.next += 1;
+        self.in_flight = Some((poll, t));
+        Some(format!("r {}\n", self.polls[poll].0))
+    }
+
+    /// The `header:value` line for a reply from the ODrive, or `None` if no request is in
+    /// flight and the line is discarded. Errors such as `invalid property` come through as
+    /// the value, and fail to parse.
+    pub fn reply(&mut self, line: &str) -> Option<String> {
+        let (poll, _) = self.in_flight.take()?;
+        Some(format!("{}:{}", self.polls[poll].1, line.trim()))
+    }
+
+    /// The ASCII commands for `command` on every axis, or why it has no ODrive equivalent.
+    /// Polling pauses after the commands sent at `t`, in case one of them is answered.
+    pub fn command(&mut self, command: Command, t: f64) -> Result<Vec<String>, String> {
+        let requested_state = |axis: u8, state: u32| format!("w axis{}.requested_state {}\n", axis, state);
+        let mut lines = Vec::new();
+        for &axis in &self.axes {
+            match command {
+                Command::CalibrationRoutine => lines.push(requested_state(axis, AXIS_STATE_FULL_CALIBRATION_SEQUENCE)),
+                Command::Idle => lines.push(requested_state(axis, AXIS_STATE_IDLE)),
+                Command::ClearErrors => {}
+                Command::AutoControl => return Err("auto_ctrl is handled by the firmware, not the ODrive".to_string()),
+                Command::Setpoint(sp) => lines.push(match self.mode {
+                    Some(ControlMode::Position) => format!("p {} {} 0 0\n", axis, sp),
+                    Some(ControlMode::Velocity) => format!("v {} {} 0\n", axis, sp),
+                    Some(ControlMode::Torque) => format!("c {} {}\n", axis, sp),
+                    Some(ControlMode::Voltage) => return Err("the ODrive has no voltage setpoint command".to_string()),
+                    None => return Err("select a control mode before sending a setpoint".to_string()),
+                }),
+                _ => {}
+            }
+            if let Some(mode) = ControlMode::of(command) {
+                lines.push(format!("w axis{}.controller.config.control_mode {}\n", axis, mode as u32));
+                lines.push(requested_state(axis, AXIS_STATE_CLOSED_LOOP_CONTROL));
+            }
+        }
+        // Clears the errors of the whole ODrive at once
+        if command == Command::ClearErrors {
+            lines.push("sc\n".to_string());
+        }
+        if let Some(mode) = ControlMode::of(command) {
+            self.mode = Some(mode);
+        }
+        self.quiet_until = self.quiet_until.max(t + QUIET_PERIOD);
+        Ok(lines)
+    }
+}
+
+/// Run a session with an ODrive on `port` until a shutdown is received on `listener_msgs_r`,
+/// or the port fails for good. Received lines and dispatched commands are recorded to `capture` as
+/// the firmware would send them, e.g. `enc_pos_0:1.25` and `velo_ctrl`.
+pub fn run(
+    port: Box<dyn serialport::SerialPort>,
+    mut driver: Driver,
+    overflow: OverflowPolicy,
+    capture: Option<PathBuf>,
+    mut pipeline: Pipeline,
+    mut cmds_r: Receiver<String>,
+    listener_msgs_r: Receiver<ListenerMsg>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut framer = Framer::new(b"\n".to_vec(), DEFAULT_MAX_LINE_LEN);
+    let mut capture = match &capture {
+        Some(path) => Some(CaptureWriter::create(path)?),
+        None => None,
+    };
+    // The link writes the translated commands and polls
+    let (port_cmds_s, port_cmds_r) = crossbeam_channel::unbounded();
+    let mut link = SerialLink::start(port, overflow, port_cmds_r)?;
+    let tick = Duration::from_secs_f64(driver.period.min(0.1));
+    let mut stats_t = 0.0;
+    let mut next_seq = 0;
+    loop {
+        crossbeam_channel::select! {
+            recv(link.chunks_r) -> chunk => {
+                let Ok(chunk) = chunk else {
+                    break;
+                };
+                let t = chunk.t;
+                if chunk.after_gap(&mut next_seq) {
+                    framer.resync();
+                }
+                framer.push(&chunk.bytes);
+                while let Some(frame) = framer.next_frame() {
+                    let Frame::Line(reply) = frame else {
+                        continue;
+                    };
+                    let reply = String::from_utf8_lossy(reply);
+                    match driver.reply(&reply) {
+                        Some(line) => {
+                            if let Some(capture) = &mut capture {
+                                let _ = capture.line(t, &line);
+                            }
+                            pipeline.line(t, line.as_bytes());
+                        }
+                        None => println!("ODrive: {}", reply.trim()),
+                    }
+                }
+            },
+            recv(cmds_r) -> command => {
+                // Headless sessions may have no command sender left, they end on a shutdown
+                let Ok(command) = command else {
+                    cmds_r = crossbeam_channel::never();
+                    continue;
+                };
+                let t = link.elapsed();
+                let lines = Command::decode(&command).ok_or_else(|| "unknown command".to_string()).and_then(|c| driver.command(c, t));
+                match lines {
+                    Ok(lines) => {
+                        for line in lines {
+                            let _ = port_cmds_s.send(line);
+                        }
+                        if let Some(capture) = &mut capture {
+                            let _ = capture.command(t, &command);
+                        }
+                        pipeline.command(t, &command);
+                    }
+                    Err(e) => println!("Ignored command '{}': {}", command, e),
+                }
+            },
+            recv(listener_msgs_r) -> msg => match msg {
+                Ok(ListenerMsg::Shutdown) | Err(_) => break,
+                Ok(msg) => pipeline.handle(msg),
+            },
+            default(tick) => {},
+        }
+        let t = link.elapsed();
+        if let Some(request) = driver.poll(t) {
+            let _ = port_cmds_s.send(request);
+        }
+        if t - stats_t >= QUEUE_STATS_PERIOD {
+            stats_t = t;
+            pipeline.queue_stats(t, link.stats());
+        }
+    }
+    link.stop();
+    if let Some(capture) = &mut capture {
+        capture.flush()?;
+    }
+    pipeline.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::thread;
+
+    use serialport::SerialPort;
+
+    use super::*;
+
+    /// A stand-in for an ODrive, answering the ASCII protocol from a table of properties. Setpoints
+    /// are reached at once, and calibration ends straight away.
+    struct MockOdrive {
+        properties: HashMap<String, String>,
+    }
+
+    impl Default for MockOdrive {
+        fn default() -> Self {
+            let mut properties = HashMap::new();
+            properties.insert("vbus_voltage".to_string(), "24.0".to_string());
+            properties.insert("ibus".to_string(), "0.0".to_string());
+            for axis in 0..2 {
+                for (property, value) in [
+                    ("encoder.pos_estimate", "0.0"),
+                    ("encoder.vel_estimate", "0.0"),
+                    ("current_state", "1"),
+                    ("error", "0"),
+                    ("controller.config.control_mode", "3"),
+                    ("controller.input_torque", "0.0"),
+                ] {
+                    properties.insert(format!("axis{}.{}", axis, property), value.to_string());
+                }
+            }
+            MockOdrive { properties }
+        }
+    }
+
+    impl MockOdrive {
+        // Writes have no reply unless the property doesn't exist
+        fn set(&mut self, property: String, value: &str) -> Option<String> {
+            match self.properties.get_mut(&property) {
+                Some(v) => {
+                    *v = value.to_string();
+                    None
+                }
+                None => Some("invalid property".to_string()),
+            }
+        }
+
+        /// The reply to one line sent to the ODrive, if it has one.
+        fn handle(&mut self, line: &str) -> Option<String> {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["r", property] => Some(self.properties.get(*property).cloned().unwrap_or_else(|| "invalid property".to_string())),
+                ["w", property, value] => match property.strip_suffix(".requested_state") {
+                    // Calibration finishes at once, back in idle
+                    Some(axis) if value.parse::<u32>() == Ok(AXIS_STATE_FULL_CALIBRATION_SEQUENCE) => self.set(format!("{}.current_state", axis), "1"),
+                    Some(axis) => self.set(format!("{}.current_state", axis), value),
+                    None => self.set(property.to_string(), value),
+                },
+                ["p", axis, pos, ..] => self.set(format!("axis{}.encoder.pos_estimate", axis), pos),
+                ["v", axis, vel, ..] => self.set(format!("axis{}.encoder.vel_estimate", axis), vel),
+                ["c", axis, torque, ..] => self.set(format!("axis{}.controller.input_torque", axis), torque),
+                ["sc"] => {
+                    for (property, value) in self.properties.iter_mut() {
+                        if property.ends_with(".error") {
+                            *value = "0".to_string();
+                        }
+                    }
+                    None
+                }
+                _ => Some("invalid command format".to_string()),
+            }
+        }
+    }
+
+    // Run the driver's lines through the mock, returning the channel lines of the replies
+    fn exchange(driver: &mut Driver, odrive: &mut MockOdrive, lines: Vec<String>) -> Vec<String> {
+        lines.iter().filter_map(|line| odrive.handle(line)).filter_map(|reply| driver.reply(&reply)).collect()
+    }
+
+    // Poll at `t` until the round is done, answering each request from the mock
+    fn round(driver: &mut Driver, odrive: &mut MockOdrive, t: f64) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Some(request) = driver.poll(t) {
+            lines.extend(exchange(driver, odrive, vec![request]));
+        }
+        lines
+    }
+
+    fn driver() -> Driver {
+        let opts = OdriveOpts { odrive: true, odrive_poll: Vec::new(), odrive_rate: 10.0, odrive_axes: vec![0] };
+        Driver::new(opts.polls(), &opts.odrive_axes, opts.odrive_rate)
+    }
+
+    #[test]
+    fn polls_map_replies_to_channels() {
+        let (mut driver, mut odrive) = (driver(), MockOdrive::default());
+        let lines = round(&mut driver, &mut odrive, 0.0);
+        assert_eq!(lines, ["bus_voltage:24.0", "bus_current:0.0", "enc_pos_0:0.0", "enc_vel_0:0.0", "axis_state_0:1", "axis_error_0:0"]);
+    }
+
+    #[test]
+    fn one_request_in_flight_and_rounds_follow_the_rate() {
+        let mut driver = driver();
+        assert_eq!(driver.poll(0.0).as_deref(), Some("r vbus_voltage\n"));
+        // Unanswered, until the request times out and the link has been quiet for a while
+        assert_eq!(driver.poll(0.2), None);
+        assert_eq!(driver.poll(0.6), None);
+        assert_eq!(driver.reply("24.0"), None);
+        assert_eq!(driver.poll(0.75).as_deref(), Some("r ibus\n"));
+        assert_eq!(driver.reply("0.5").as_deref(), Some("bus_current:0.5"));
+
+        let (mut driver, mut odrive) = (self::driver(), MockOdrive::default());
+        assert_eq!(round(&mut driver, &mut odrive, 0.0).len(), 6);
+        assert_eq!(driver.poll(0.05), None);
+        assert_eq!(driver.poll(0.1).as_deref(), Some("r vbus_voltage\n"));
+    }
+
+    #[test]
+    fn unsolicited_lines_are_discarded() {
+        let (mut driver, mut odrive) = (driver(), MockOdrive::default());
+        assert_eq!(driver.reply("unsolicited"), None);
+
+        let request = driver.poll(0.0).unwrap();
+        assert_eq!(exchange(&mut driver, &mut odrive, vec![request]), ["bus_voltage:24.0"]);
+        assert_eq!(driver.reply("unsolicited"), None);
+        let request = driver.poll(0.0).unwrap();
+        assert_eq!(exchange(&mut driver, &mut odrive, vec![request]), ["bus_current:0.0"]);
+
+        // A command's error reply arrives while polling pauses, and isn't taken for a poll's
+        odrive.properties.remove("axis0.controller.config.control_mode");
+        let lines = driver.command(Command::VelocityControl, 0.0).unwrap();
+        assert_eq!(driver.poll(0.05), None);
+        assert!(exchange(&mut driver, &mut odrive, lines).is_empty());
+        let request = driver.poll(0.1).unwrap();
+        assert_eq!(exchange(&mut driver, &mut odrive, vec![request]), ["enc_pos_0:0.0"]);
+    }
+
+    #[test]
+    fn invalid_properties_fail_to_parse() {
+        let mut driver = Driver::new(vec![("axis0.bogus".to_string(), "theta".to_string())], &[0], 10.0);
+        let lines = round(&mut driver, &mut MockOdrive::default(), 0.0);
+        assert_eq!(lines, ["theta:invalid property"]);
+    }
+
+    #[test]
+    fn rate_must_be_positive() {
+        assert_eq!(parse_rate("20"), Ok(20.0));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("-5").is_err());
+        assert!(parse_rate("inf").is_err());
+    }
+
+    #[test]
+    fn session_outlives_its_command_senders() {
+        let (mut odrive_port, port) = serialport::TTYPort::pair().unwrap();
+        odrive_port.set_timeout(Duration::from_secs(5)).unwrap();
+        let mut replies = odrive_port.try_clone_native().unwrap();
+        let (cmds_s, cmds_r) = crossbeam_channel::unbounded();
+        let (listener_msgs_s, listener_msgs_r) = crossbeam_channel::unbounded();
+        let pipeline = Pipeline::new(rerun::RecordingStream::disabled(), None);
+        let session = thread::spawn(move || {
+            run(Box::new(port), driver(), OverflowPolicy::Block, None, pipeline, cmds_r, listener_msgs_r).is_ok()
+        });
+        // Headless without a script or API, nothing holds a command sender
+        drop(cmds_s);
+
+        // Two rounds of polls answered, and the third started
+        let (mut requests, mut odrive) = (BufReader::new(odrive_port), MockOdrive::default());
+        let mut request = String::new();
+        for _ in 0..12 {
+            request.clear();
+            requests.read_line(&mut request).unwrap();
+            let reply = odrive.handle(&request).unwrap();
+            replies.write_all(format!("{}\n", reply).as_bytes()).unwrap();
+        }
+        request.clear();
+        requests.read_line(&mut request).unwrap();
+        assert_eq!(request, "r vbus_voltage\n");
+        let (status_s, status_r) = crossbeam_channel::bounded(1);
+        listener_msgs_s.send(ListenerMsg::Status(status_s)).unwrap();
+        let status = status_r.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(status.parse_stats.channels["bus_voltage"].ok, 2);
+        assert_eq!(status.parse_stats.channels["axis_error_0"].ok, 2);
+
+        listener_msgs_s.send(ListenerMsg::Shutdown).unwrap();
+        assert!(session.join().unwrap());
+    }
+
+    #[test]
+    fn velocity_control_and_setpoint() {
+        let (mut driver, mut odrive) = (driver(), MockOdrive::default());
+        assert!(driver.command(Command::Setpoint(1.0), 0.0).is_err());
+
+        let lines = driver.command(Command::VelocityControl, 0.0).unwrap();
+        assert_eq!(lines, ["w axis0.controller.config.control_mode 2\n", "w axis0.requested_state 8\n"]);
+        let setpoint = driver.command(Command::Setpoint(1.5), 0.0).unwrap();
+        assert_eq!(setpoint, ["v 0 1.5 0\n"]);
+        assert!(exchange(&mut driver, &mut odrive, [lines, setpoint].concat()).is_empty());
+
+        assert_eq!(odrive.properties["axis0.current_state"], "8");
+        assert_eq!(odrive.properties["axis0.controller.config.control_mode"], "2");
+        assert_eq!(odrive.properties["axis0.encoder.vel_estimate"], "1.5");
+    }
+
+    #[test]
+    fn calibration_and_clearing_errors() {
+        let (mut driver, mut odrive) = (driver(), MockOdrive::default());
+        odrive.properties.insert("axis0.error".to_string(), "256".to_string());
+        let lines = [driver.command(Command::CalibrationRoutine, 0.0).unwrap(), driver.command(Command::ClearErrors, 0.0).unwrap()].concat();
+        assert_eq!(lines, ["w axis0.requested_state 3\n", "sc\n"]);
+        exchange(&mut driver, &mut odrive, lines);
+        assert_eq!(odrive.properties["axis0.error"], "0");
+        assert!(driver.command(Command::AutoControl, 0.0).is_err());
+    }
+}
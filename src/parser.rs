@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    NonUtf8,
+    BadNumber(String),
+    UnknownHeader(String),
+    // Line longer than the framing limit, in bytes
+    Oversized(usize),
+    // No `header:` before the data
+    MissingHeader,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::NonUtf8 => write!(f, "not valid UTF-8"),
+            ParseError::BadNumber(s) => write!(f, "bad number '{}'", s),
+            ParseError::UnknownHeader(h) => write!(f, "unknown header '{}'", h),
+            ParseError::Oversized(limit) => write!(f, "line longer than {} bytes", limit),
+            ParseError::MissingHeader => write!(f, "no header"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub struct Parser{}
+
+impl Parser {
+    /// Split a received line into its header and data, without the line ending.
+    pub fn split_line(line: &[u8]) -> Result<(&str, &[u8]), ParseError> {
+        let index = line.iter().position(|&c| c == b':').ok_or(ParseError::MissingHeader)?;
+        let header = std::str::from_utf8(&line[..index]).map_err(|_| ParseError::NonUtf8)?;
+        Ok((header, &line[index + 1..]))
+    }
+
+    pub fn parse_float(buffer: &[u8]) -> Result<f32, ParseError>
+    {
+        let s = std::str::from_utf8(buffer).map_err(|_| ParseError::NonUtf8)?.trim();
+        s.parse::<f32>().map_err(|_| ParseError::BadNumber(s.to_string()))
+    }
+
+    pub fn parse_int(buffer: &[u8]) -> Result<i32, ParseError> {
+        let s = std::str::from_utf8(buffer).map_err(|_| ParseError::NonUtf8)?.trim();
+        s.parse::<i32>().map_err(|_| ParseError::BadNumber(s.to_string()))
+    }
+
+    pub fn parse_string(buffer: &[u8]) -> String {
+        String::from_utf8_lossy(buffer).to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChannelCounters {
+    pub ok: u64,
+    pub non_utf8: u64,
+    pub bad_number: u64,
+}
+
+// Parse results per channel, plus the lines that couldn't be attributed to one
+#[derive(Debug, Clone, Default)]
+pub struct ParseStats {
+    pub channels: BTreeMap<String, ChannelCounters>,
+    pub unknown_header: u64,
+    pub oversized: u64,
+    pub malformed: u64,
+}
+
+impl ParseStats {
+    pub fn ok(&mut self, header: &str) {
+        self.channel(header).ok += 1;
+    }
+
+    pub fn error(&mut self, header: Option<&str>, err: &ParseError) {
+        match (header, err) {
+            (_, ParseError::UnknownHeader(_)) => self.unknown_header += 1,
+            (_, ParseError::Oversized(_)) => self.oversized += 1,
+            (Some(header), ParseError::NonUtf8) => self.channel(header).non_utf8 += 1,
+            (Some(header), ParseError::BadNumber(_)) => self.channel(header).bad_number += 1,
+            _ => self.malformed += 1,
+        }
+    }
+
+    pub fn errors(&self) -> u64 {
+        let channel_errors: u64 = self.channels.values().map(|c| c.non_utf8 + c.bad_number).sum();
+        channel_errors + self.unknown_header + self.oversized + self.malformed
+    }
+
+    fn channel(&mut self, header: &str) -> &mut ChannelCounters {
+        if !self.channels.contains_key(header) {
+            self.channels.insert(header.to_string(), ChannelCounters::default());
+        }
+        self.channels.get_mut(header).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::{Frame, Framer};
+
+    #[test]
+    fn missing_header() {
+        assert_eq!(Parser::split_line(b"24.1"), Err(ParseError::MissingHeader));
+        assert_eq!(Parser::split_line(b""), Err(ParseError::MissingHeader));
+        assert_eq!(Parser::split_line(b"bus_voltage:24.1"), Ok(("bus_voltage", &b"24.1"[..])));
+    }
+
+    #[test]
+    fn non_utf8() {
+        assert_eq!(Parser::split_line(b"bus_\xffvoltage:24.1"), Err(ParseError::NonUtf8));
+        assert_eq!(Parser::parse_float(b"24.\xff"), Err(ParseError::NonUtf8));
+        assert_eq!(Parser::parse_int(b"\xfe3"), Err(ParseError::NonUtf8));
+    }
+
+    #[test]
+    fn bad_number() {
+        assert_eq!(Parser::parse_float(b" 1.5x\r"), Err(ParseError::BadNumber("1.5x".to_string())));
+        assert_eq!(Parser::parse_float(b""), Err(ParseError::BadNumber(String::new())));
+        assert_eq!(Parser::parse_int(b"2.5"), Err(ParseError::BadNumber("2.5".to_string())));
+        assert_eq!(Parser::parse_float(b" -0.25 "), Ok(-0.25));
+        assert_eq!(Parser::parse_int(b"42\r"), Ok(42));
+    }
+
+    #[test]
+    fn oversized_line() {
+        let mut framer = Framer::new(b"\n".to_vec(), 8);
+        framer.push(b"theta:0.123456789\ntheta:1\n");
+        let mut stats = ParseStats::default();
+        while let Some(frame) = framer.next_frame() {
+            match frame {
+                Frame::Line(line) => {
+                    let (header, data) = Parser::split_line(line).unwrap();
+                    Parser::parse_float(data).unwrap();
+                    stats.ok(header);
+                }
+                Frame::Overflow(_) => stats.error(None, &ParseError::Oversized(8)),
+            }
+        }
+        assert_eq!(stats.oversized, 1);
+        assert_eq!(stats.channels["theta"].ok, 1);
+        assert_eq!(ParseError::Oversized(8).to_string(), "line longer than 8 bytes");
+    }
+
+    #[test]
+    fn errors_are_counted_per_channel() {
+        let mut stats = ParseStats::default();
+        stats.error(Some("theta"), &ParseError::NonUtf8);
+        stats.error(Some("theta"), &ParseError::BadNumber("abc".to_string()));
+        stats.error(Some("theta"), &ParseError::BadNumber("1.2.3".to_string()));
+        stats.error(None, &ParseError::NonUtf8);
+        stats.error(None, &ParseError::MissingHeader);
+        stats.error(None, &ParseError::Oversized(256));
+        stats.error(Some("bogus"), &ParseError::UnknownHeader("bogus".to_string()));
+        stats.ok("theta");
+
+        let theta = stats.channels["theta"];
+        assert_eq!((theta.ok, theta.non_utf8, theta.bad_number), (1, 1, 2));
+        // Unknown headers don't get a channel of their own
+        assert!(!stats.channels.contains_key("bogus"));
+        assert_eq!((stats.unknown_header, stats.oversized, stats.malformed), (1, 1, 2));
+        assert_eq!(stats.errors(), 7);
+    }
+}
@@ -0,0 +1,625 @@
+//! Processing of parsed `header:value` messages, shared by live sessions and offline
+//! imports so both end up under the same rerun entity paths.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crossbeam_channel::{Receiver, Sender, TryRecvError, TrySendError};
+
+use crate::calibration::{CalibrationMsg, ImuCalibration};
+use crate::channels;
+use crate::derived::DerivedChannels;
+use crate::encoder::{EncoderConfig, EncoderEvent, EncoderMonitor, EncoderMsg};
+use crate::export::Exporter;
+use crate::fusion::{FusionConfig, ImuAssembler, ImuFusion, ImuSample};
+use crate::parser::{ParseError, ParseStats, Parser};
+use crate::scalars::ScalarLogger;
+use crate::serial::QueueStats;
+use crate::power::{BatteryModel, PowerMonitor, PowerMonitorMsg, PowerStats};
+
+/// Settings changes sent to a running pipeline, e.g. from the egui thread
+pub enum ListenerMsg {
+    Power(PowerMonitorMsg),
+    Fusion(FusionConfig),
+    Calibration(CalibrationMsg),
+    Encoder(EncoderMsg),
+    /// Log lines that fail to parse to the `parse_errors` TextLog
+    LogMalformed(bool),
+    /// Send every `(t, value)` sample of a channel or derived channel, see [`Subscription`].
+    /// The firmware's IMU attitude is available as `quaternion_w`, `_x`, `_y` and `_z`.
+    Subscribe(String, Subscription),
+    /// Drop all subscriptions to a channel
+    Unsubscribe(String),
+    /// Log a marker to the `script` TextLog, e.g. from a test sequence
+    Marker(String, rerun::TextLogLevel),
+    /// Also log to a recording saving to the given file from now on, alongside the live one,
+    /// or stop doing so with `None`
+    Record(Option<(rerun::RecordingStream, PathBuf)>),
+    /// Reply with the current [`PipelineStatus`]
+    Status(Sender<PipelineStatus>),
+    /// Flush the recording and stop listening
+    Shutdown,
+}
+
+/// Where the pipeline reports back to the command window, if there is one
+pub struct GuiLinks {
+    pub dbg_msgs_s: crossbeam_channel::Sender<String>,
+    pub power_stats_s: crossbeam_channel::Sender<PowerStats>,
+    pub imu_samples_s: crossbeam_channel::Sender<ImuSample>,
+    pub parse_stats_s: crossbeam_channel::Sender<ParseStats>,
+    pub queue_stats_s: crossbeam_channel::Sender<QueueStats>,
+    /// Commands once sent, whoever dispatched them
+    pub commands_s: crossbeam_channel::Sender<String>,
+    /// The file being recorded to, on changes
+    pub recording_s: crossbeam_channel::Sender<Option<PathBuf>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PipelineStatus {
+    pub parse_stats: ParseStats,
+    pub queue_stats: QueueStats,
+    pub recording: Option<PathBuf>,
+    /// Names of the configured derived channels
+    pub derived_channels: Vec<String>,
+}
+
+/// Samples of a subscribed channel. Samples are dropped while it's full, and it is
+/// unsubscribed once the receiver is dropped.
+pub type Subscription = Sender<(f64, f64)>;
+
+#[derive(Default)]
+struct Subscribers(HashMap<String, Vec<Subscription>>);
+
+impl Subscribers {
+    fn publish(&mut self, name: &str, t: f64, v: f64) {
+        if let Some(subscriptions) = self.0.get_mut(name) {
+            subscriptions.retain(|s| !matches!(s.try_send((t, v)), Err(TrySendError::Disconnected(_))));
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ListenerStopped;
+
+impl fmt::Display for ListenerStopped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "serial listener stopped")
+    }
+}
+
+impl std::error::Error for ListenerStopped {}
+
+struct Latest {
+    samples_r: Receiver<(f64, f64)>,
+    sample: Option<(f64, f64)>,
+}
+
+/// Latest `(t, value)` sample of each channel looked at so far, for pollers such as
+/// scripts. Call `update` regularly, samples are dropped while it isn't called.
+pub struct LatestValues {
+    listener_msgs_s: Sender<ListenerMsg>,
+    channels: HashMap<String, Latest>,
+}
+
+impl LatestValues {
+    // Samples buffered per channel between updates
+    const CAPACITY: usize = 1024;
+
+    pub fn new(listener_msgs_s: Sender<ListenerMsg>) -> Self {
+        LatestValues { listener_msgs_s, channels: HashMap::new() }
+    }
+
+    pub fn subscribe(&mut self, name: &str) -> Result<(), ListenerStopped> {
+        if !self.channels.contains_key(name) {
+            let (samples_s, samples_r) = crossbeam_channel::bounded(Self::CAPACITY);
+            self.listener_msgs_s
+                .send(ListenerMsg::Subscribe(name.to_string(), samples_s))
+                .map_err(|_| ListenerStopped)?;
+            self.channels.insert(name.to_string(), Latest { samples_r, sample: None });
+        }
+        Ok(())
+    }
+
+    pub fn update(&mut self) -> Result<(), ListenerStopped> {
+        for latest in self.channels.values_mut() {
+            loop {
+                match latest.samples_r.try_recv() {
+                    Ok(sample) => latest.sample = Some(sample),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return Err(ListenerStopped),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.channels.contains_key(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<(f64, f64)> {
+        self.channels.get(name).and_then(|latest| latest.sample)
+    }
+
+    /// Subscribed channels and their latest sample, if any
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Option<(f64, f64)>)> {
+        self.channels.iter().map(|(name, latest)| (name.as_str(), latest.sample))
+    }
+}
+
+// Seconds between parse statistics updates sent to the GUI
+const PARSE_STATS_PERIOD: f64 = 0.5;
+
+// Odometry path drawn in rerun: seconds between updates, and most recent points kept
+const ODOMETRY_PATH_PERIOD: f64 = 0.1;
+const ODOMETRY_PATH_POINTS: usize = 10_000;
+
+/// Builder for the recording the pipeline logs to.
+pub fn recording_builder() -> rerun::RecordingStreamBuilder {
+    rerun::RecordingStreamBuilder::new("sensor_stream_viewer")
+}
+
+// The recording passed to `Pipeline::new`, and the file recorded to alongside it, if any
+struct Recordings {
+    live: rerun::RecordingStream,
+    file: Option<rerun::RecordingStream>,
+}
+
+impl Recordings {
+    fn log(&self, entity_path: &str, arch: &impl rerun::AsComponents) {
+        for rec in self {
+            let _ = rec.log(entity_path, arch);
+        }
+    }
+
+    fn set_time_seconds(&self, timeline: &str, seconds: f32) {
+        for rec in self {
+            rec.set_time_seconds(timeline, seconds);
+        }
+    }
+
+    fn flush_blocking(&self) {
+        for rec in self {
+            rec.flush_blocking();
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a Recordings {
+    type Item = &'a rerun::RecordingStream;
+    type IntoIter = std::iter::Chain<std::iter::Once<&'a rerun::RecordingStream>, std::option::Iter<'a, rerun::RecordingStream>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        std::iter::once(&self.live).chain(self.file.iter())
+    }
+}
+
+/// Turns received lines into rerun logs, GUI updates and exported samples.
+pub struct Pipeline {
+    rec: Recordings,
+    scalars: ScalarLogger,
+    time: Option<f64>,
+    gui: Option<GuiLinks>,
+    quaternion: [f32; 4],
+    derived: DerivedChannels,
+    power: PowerMonitor,
+    was_under_voltage: bool,
+    imu_fusion: ImuFusion,
+    imu_calibration: ImuCalibration,
+    raw_imu: ImuAssembler,
+    capture_raw_imu: bool,
+    encoders: EncoderMonitor,
+    odometry_path: Vec<[f32; 2]>,
+    odometry_path_logged_t: f64,
+    exporter: Option<Exporter>,
+    subscribers: Subscribers,
+    recording: Option<PathBuf>,
+    // Saved to by `rec.file`
+    recording_file: Option<PathBuf>,
+    queue_stats: QueueStats,
+    parse_stats: ParseStats,
+    parse_stats_sent_t: f64,
+    log_malformed: bool,
+}
+
+impl Pipeline {
+    /// Log to `rec`, and export every sample to `exporter` if given.
+    pub fn new(rec: rerun::RecordingStream, exporter: Option<Exporter>) -> Self {
+        // User-defined channels computed from the incoming ones, see derived.rs
+        let derived_channels_path = "derived_channels.txt";
+        let derived = if Path::new(derived_channels_path).exists() {
+            match DerivedChannels::load(derived_channels_path) {
+                Ok(d) => {
+                    println!("Loaded {} derived channel(s) from {}", d.channels().len(), derived_channels_path);
+                    d
+                }
+                Err(e) => {
+                    println!("Could not load {}: {}", derived_channels_path, e);
+                    DerivedChannels::empty()
+                }
+            }
+        } else {
+            DerivedChannels::empty()
+        };
+
+        // IMU calibration applied to the raw acc/gyr channels, see calibration.rs
+        let imu_calibration_path = "imu_calibration.txt";
+        let imu_calibration = if Path::new(imu_calibration_path).exists() {
+            match ImuCalibration::load(imu_calibration_path) {
+                Ok(cal) => {
+                    println!("Loaded IMU calibration from {}", imu_calibration_path);
+                    cal
+                }
+                Err(e) => {
+                    println!("Could not load {}: {}", imu_calibration_path, e);
+                    ImuCalibration::default()
+                }
+            }
+        } else {
+            ImuCalibration::default()
+        };
+
+        Pipeline {
+            rec: Recordings { live: rec, file: None },
+            scalars: ScalarLogger::default(),
+            time: None,
+            gui: None,
+            quaternion: [0.0, 0.0, 0.0, 0.0],
+            derived,
+            power: PowerMonitor::new(BatteryModel::default()),
+            was_under_voltage: false,
+            // Host-side attitude estimate, compared against the firmware's
+            imu_fusion: ImuFusion::new(FusionConfig::default()),
+            imu_calibration,
+            // Uncalibrated samples are forwarded to the calibration wizard while it captures
+            raw_imu: ImuAssembler::default(),
+            capture_raw_imu: false,
+            // Encoder consistency checks and wheel odometry
+            encoders: EncoderMonitor::new(EncoderConfig::default()),
+            odometry_path: Vec::new(),
+            odometry_path_logged_t: f64::NEG_INFINITY,
+            exporter,
+            subscribers: Subscribers::default(),
+            recording: None,
+            recording_file: None,
+            queue_stats: QueueStats::default(),
+            parse_stats: ParseStats::default(),
+            parse_stats_sent_t: f64::NEG_INFINITY,
+            log_malformed: false,
+        }
+    }
+
+    pub fn set_gui(&mut self, gui: GuiLinks) {
+        let _ = gui.recording_s.try_send(self.recording_path());
+        self.gui = Some(gui);
+    }
+
+    /// The file the recording passed to `new` saves to, for status reports
+    pub fn set_recording_path(&mut self, path: Option<PathBuf>) {
+        self.recording = path;
+        if let Some(gui) = &self.gui {
+            let _ = gui.recording_s.try_send(self.recording_path());
+        }
+    }
+
+    // The file being recorded to, preferring one started with `ListenerMsg::Record`
+    fn recording_path(&self) -> Option<PathBuf> {
+        self.recording_file.clone().or_else(|| self.recording.clone())
+    }
+
+    pub fn handle(&mut self, msg: ListenerMsg) {
+        match msg {
+            ListenerMsg::Power(msg) => self.power.handle(msg),
+            ListenerMsg::Fusion(config) => self.imu_fusion.set_config(config),
+            ListenerMsg::Calibration(CalibrationMsg::Capture(enabled)) => {
+                self.capture_raw_imu = enabled;
+                self.raw_imu = ImuAssembler::default();
+            }
+            ListenerMsg::Calibration(CalibrationMsg::Apply(cal)) => self.imu_calibration = cal,
+            ListenerMsg::Encoder(msg) => {
+                if let EncoderMsg::ResetOdometry = msg {
+                    self.odometry_path.clear();
+                    self.rec.log("odometry/path", &rerun::Clear::flat());
+                }
+                self.encoders.handle(msg);
+            }
+            ListenerMsg::LogMalformed(enabled) => self.log_malformed = enabled,
+            ListenerMsg::Subscribe(name, subscription) => self.subscribers.0.entry(name).or_default().push(subscription),
+            ListenerMsg::Unsubscribe(name) => {
+                self.subscribers.0.remove(&name);
+            }
+            ListenerMsg::Marker(text, level) => {
+                self.rec.log("script", &rerun::TextLog::new(text).with_level(level));
+            }
+            ListenerMsg::Record(file) => {
+                if let Some(rec) = &self.rec.file {
+                    rec.flush_blocking();
+                }
+                (self.rec.file, self.recording_file) = file.unzip();
+                self.time = None;
+                self.set_recording_path(self.recording.clone());
+            }
+            ListenerMsg::Status(reply_s) => {
+                let _ = reply_s.try_send(PipelineStatus {
+                    parse_stats: self.parse_stats.clone(),
+                    queue_stats: self.queue_stats,
+                    recording: self.recording_path(),
+                    derived_channels: self.derived.channels().iter().map(|d| d.name.clone()).collect(),
+                });
+            }
+            ListenerMsg::Shutdown => {}
+        }
+    }
+
+    /// A command sent to the device at time `t` (seconds)
+    pub fn command(&mut self, t: f64, command: &str) {
+        if let Some(exporter) = &mut self.exporter {
+            exporter.command(t, command);
+        }
+        if let Some(gui) = &self.gui {
+            let _ = gui.commands_s.try_send(command.to_string());
+        }
+    }
+
+    /// A sample of a channel outside the registry, e.g. a field of a binary frame, logged
+    /// to `entity_path`. It is exported and published, but not fed to fusion, power etc.
+    pub fn sample(&mut self, t: f64, name: &str, entity_path: &str, label: &str, value: f64) {
+        self.set_time(t);
+        self.scalars.log(&self.rec, entity_path, label, value);
+        if let Some(exporter) = &mut self.exporter {
+            exporter.sample(t, name, value);
+        }
+        self.subscribers.publish(name, t, value);
+        self.parse_stats.ok(name);
+        self.send_parse_stats(t);
+    }
+
+    /// Process one line received at time `t` (seconds), without its delimiter
+    pub fn line(&mut self, t: f64, line: &[u8]) {
+        match Parser::split_line(line) {
+            Ok((header, data)) => self.message(t, header, data),
+            Err(e) => self.malformed(t, None, &e, line),
+        }
+    }
+
+    /// Start of a line discarded for exceeding `max_len` bytes
+    pub fn overflow(&mut self, t: f64, start: &[u8], max_len: usize) {
+        self.malformed(t, None, &ParseError::Oversized(max_len), start);
+    }
+
+    fn malformed(&mut self, t: f64, header: Option<&str>, err: &ParseError, line: &[u8]) {
+        self.parse_stats.error(header, err);
+        if self.log_malformed {
+            let line = Parser::parse_string(line);
+            let text = match header {
+                Some(header) => format!("{} in '{}:{}'", err, header, line.trim_end()),
+                None => format!("{} in '{}'", err, line.trim_end()),
+            };
+            self.set_time(t);
+            self.rec.log("parse_errors", &rerun::TextLog::new(text).with_level(rerun::TextLogLevel::WARN));
+        }
+        self.send_parse_stats(t);
+    }
+
+    fn send_parse_stats(&mut self, t: f64) {
+        if let Some(gui) = &self.gui {
+            if t - self.parse_stats_sent_t >= PARSE_STATS_PERIOD {
+                self.parse_stats_sent_t = t;
+                let _ = gui.parse_stats_s.try_send(self.parse_stats.clone());
+            }
+        }
+    }
+
+    /// Process one `header:value` message received at time `t` (seconds)
+    pub fn message(&mut self, t: f64, header: &str, data: &[u8]) {
+        match self.process(t, header, data) {
+            Ok(()) => {
+                self.parse_stats.ok(header);
+                self.send_parse_stats(t);
+            }
+            Err(e) => self.malformed(t, Some(header), &e, data),
+        }
+    }
+
+    // Lines from one serial read share a timestamp, only set it when it changes
+    fn set_time(&mut self, t: f64) {
+        if self.time != Some(t) {
+            self.rec.set_time_seconds("step", t as f32);
+            self.time = Some(t);
+        }
+    }
+
+    fn process(&mut self, t: f64, header: &str, data: &[u8]) -> Result<(), ParseError> {
+        self.set_time(t);
+        let rec = &self.rec;
+        match header {
+            "dbg_msg" => {
+                let s = Parser::parse_string(data);
+                println!("dbg_msg:{}", s);
+
+                // Hacky way to publish an array as a whole
+                if s.contains("quaternion,")
+                {
+                    // Split the input string by ','
+                    let parts: Vec<&str> = s.split(',').collect();
+
+                    // Extract the string and floats
+                    // let key = parts[0];
+                    let floats: Vec<f32> = parts[1..].iter().filter_map(|&s| s.parse().ok()).collect();
+                    if floats.len() < 4 {
+                        return Err(ParseError::BadNumber(s));
+                    }
+
+                    // println!("s: {}", s);
+                    // println!("parts: {:?}", parts);
+                    // println!("floats: {:?}", floats.len());
+                    let quaternion = &mut self.quaternion;
+                    quaternion[0] = floats[0];
+                    quaternion[1] = floats[1];
+                    quaternion[2] = floats[2];
+                    quaternion[3] = floats[3];
+
+                    // Publish to rerun
+                    rec.log(
+                        "IMU_3D",
+                        &rerun::Boxes3D::from_centers_and_half_sizes(
+                            [(0.0, 0.0, 0.0)],
+                            [(1.0, 2.0, 2.0)],
+                        ).with_rotations([rerun::Quaternion::from_xyzw([quaternion[1], quaternion[2], quaternion[3], quaternion[0]])]),
+                    );
+                    for (name, q) in ["quaternion_w", "quaternion_x", "quaternion_y", "quaternion_z"].iter().zip(quaternion) {
+                        self.subscribers.publish(name, t, *q as f64);
+                    }
+                }
+
+                if let Some(gui) = &self.gui {
+                    let _ = gui.dbg_msgs_s.try_send(s);
+                }
+            },
+            _ => {
+                let Some(channel) = channels::lookup(header) else {
+                    let s = Parser::parse_string(data);
+                    println!("No match on serial! {}", s);
+                    return Err(ParseError::UnknownHeader(header.to_string()));
+                };
+                let raw = Parser::parse_float(data)?;
+                if self.capture_raw_imu {
+                    if let (Some(sample), Some(gui)) = (self.raw_imu.update(channel.header, raw as f64), &self.gui) {
+                        let _ = gui.imu_samples_s.try_send(sample);
+                    }
+                }
+                let f = self.imu_calibration.apply(channel.header, raw as f64);
+
+                let scalars = &mut self.scalars;
+                scalars.log(rec, channel.entity_path, channel.label, f);
+
+                let exporter = &mut self.exporter;
+                if let Some(exporter) = exporter {
+                    exporter.sample(t, channel.header, f);
+                }
+                let subscribers = &mut self.subscribers;
+                subscribers.publish(channel.header, t, f);
+
+                self.derived.update(channel.header, f, t, |d, v| {
+                    scalars.log(rec, &d.entity_path, &d.name, v);
+                    if let Some(exporter) = exporter {
+                        exporter.sample(t, &d.name, v);
+                    }
+                    subscribers.publish(&d.name, t, v);
+                });
+
+                if self.power.update(channel.header, f, t) {
+                    let stats = *self.power.stats();
+                    scalars.log(rec, "power/P", "Power [W]", stats.power_w);
+                    scalars.log(rec, "power/energy", "Energy [Wh]", stats.energy_wh);
+                    scalars.log(rec, "power/charge", "Charge [mAh]", stats.charge_mah);
+                    if let Some(soc) = stats.soc {
+                        scalars.log(rec, "power/soc", "State of charge [%]", soc * 100.0);
+                    }
+                    if stats.under_voltage && !self.was_under_voltage {
+                        let msg = format!("Under-voltage: bus at {:.2} V", stats.voltage);
+                        println!("{}", msg);
+                        rec.log("power/warnings", &rerun::TextLog::new(msg).with_level(rerun::TextLogLevel::WARN));
+                    }
+                    self.was_under_voltage = stats.under_voltage;
+                    if let Some(gui) = &self.gui {
+                        let _ = gui.power_stats_s.try_send(stats);
+                    }
+                }
+
+                if let Some(est) = self.imu_fusion.update(channel.header, f, t) {
+                    scalars.log(rec, "imu_host/roll", "host_roll", est.roll);
+                    scalars.log(rec, "imu_host/pitch", "host_pitch", est.pitch);
+                    scalars.log(rec, "imu_host/yaw", "host_yaw", est.yaw);
+                    if let Some(r) = est.roll_residual {
+                        scalars.log(rec, "imu_residual/roll", "roll_residual", r);
+                    }
+                    if let Some(r) = est.pitch_residual {
+                        scalars.log(rec, "imu_residual/pitch", "pitch_residual", r);
+                    }
+
+                    // Drawn beside the firmware's IMU_3D box
+                    let [w, x, y, z] = est.quaternion.map(|c| c as f32);
+                    rec.log(
+                        "IMU_3D_host",
+                        &rerun::Boxes3D::from_centers_and_half_sizes(
+                            [(0.0, 5.0, 0.0)],
+                            [(1.0, 2.0, 2.0)],
+                        ).with_rotations([rerun::Quaternion::from_xyzw([x, y, z, w])]),
+                    );
+                }
+
+                if let Some(enc) = self.encoders.update(channel.header, f, t) {
+                    let axis = enc.axis;
+                    if let Some(v) = enc.host_velocity {
+                        scalars.log(rec, &format!("encoder_host_velocities/{}", axis), &format!("Axis {} host velocity", axis), v);
+                    }
+                    if let Some(r) = enc.velocity_residual {
+                        scalars.log(rec, &format!("encoder_velocity_residuals/{}", axis), &format!("Axis {} velocity residual", axis), r);
+                    }
+                    match enc.event {
+                        Some(EncoderEvent::Glitch { axis, jump }) => {
+                            let msg = format!("Encoder glitch on axis {}: position jumped {:.3} turns", axis, jump);
+                            println!("{}", msg);
+                            rec.log("encoder_events", &rerun::TextLog::new(msg).with_level(rerun::TextLogLevel::WARN));
+                        }
+                        Some(EncoderEvent::Wraparound { axis }) => {
+                            rec.log("encoder_events", &rerun::TextLog::new(format!("Encoder wraparound on axis {}", axis)).with_level(rerun::TextLogLevel::DEBUG));
+                        }
+                        None => {}
+                    }
+                    if let Some(pose) = enc.pose {
+                        scalars.log(rec, "odometry/distance", "Distance [m]", pose.distance);
+                        scalars.log(rec, "odometry/heading", "Heading [deg]", pose.heading.to_degrees());
+
+                        // Only extend the drawn path once the robot has moved a bit
+                        let point = [pose.x as f32, pose.y as f32];
+                        let moved = self.odometry_path.last().is_none_or(|last| {
+                            (last[0] - point[0]).hypot(last[1] - point[1]) > 0.01
+                        });
+                        if moved {
+                            if self.odometry_path.len() == ODOMETRY_PATH_POINTS {
+                                self.odometry_path.remove(0);
+                            }
+                            self.odometry_path.push(point);
+                        }
+                        // The whole path is re-logged each time, so limit how often
+                        if moved && t - self.odometry_path_logged_t >= ODOMETRY_PATH_PERIOD {
+                            self.odometry_path_logged_t = t;
+                            rec.log("odometry/path", &rerun::LineStrips2D::new([self.odometry_path.as_slice()]));
+                        }
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Queue depths of the serial link at time `t` (seconds)
+    pub fn queue_stats(&mut self, t: f64, stats: QueueStats) {
+        self.set_time(t);
+        let (rec, scalars) = (&self.rec, &mut self.scalars);
+        scalars.log(rec, "ingest/read_queue", "Read queue depth", stats.read_depth as f64);
+        scalars.log(rec, "ingest/command_queue", "Command queue depth", stats.command_depth as f64);
+        scalars.log(rec, "ingest/dropped", "Dropped bytes", stats.dropped_bytes as f64);
+        self.queue_stats = stats;
+        if let Some(gui) = &self.gui {
+            let _ = gui.queue_stats_s.try_send(stats);
+        }
+    }
+
+    /// Counts of parsed and rejected lines so far
+    pub fn parse_stats(&self) -> &ParseStats {
+        &self.parse_stats
+    }
+
+    /// Flush the recording and write out any buffered export
+    pub fn finish(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.rec.flush_blocking();
+        if let Some(exporter) = self.exporter.take() {
+            exporter.finish()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+//! Streaming of parsed samples to PlotJuggler's UDP server, with its JSON message parser.
+//! Every sample is one datagram, `{"timestamp": 1.25, "theta": 0.01}`, with the session
+//! time in seconds; tick "use field as timestamp if available" in PlotJuggler to plot
+//! against it rather than the arrival time.
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::{Receiver, Sender, TryRecvError};
+use serde_json::json;
+
+use crate::channels;
+use crate::pipeline::ListenerMsg;
+
+// Upper bound between datagrams sent
+const POLL_PERIOD: Duration = Duration::from_millis(20);
+// Samples buffered per channel between polls
+const SUBSCRIPTION_CAPACITY: usize = 1024;
+
+/// Send the samples of `channels`, or of every registered channel if empty, to the
+/// PlotJuggler UDP server at `target`. Returns once the serial listener stopped.
+pub fn stream(target: SocketAddr, channels: &[String], listener_msgs_s: Sender<ListenerMsg>) -> io::Result<()> {
+    let local: SocketAddr = if target.is_ipv4() { (Ipv4Addr::UNSPECIFIED, 0).into() } else { (Ipv6Addr::UNSPECIFIED, 0).into() };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(target)?;
+
+    let names: Vec<String> = if channels.is_empty() {
+        channels::CHANNELS.iter().map(|channel| channel.header.to_string()).collect()
+    } else {
+        channels.to_vec()
+    };
+    let mut subscriptions: Vec<(String, Receiver<(f64, f64)>)> = Vec::new();
+    for name in names {
+        let (samples_s, samples_r) = crossbeam_channel::bounded(SUBSCRIPTION_CAPACITY);
+        if listener_msgs_s.send(ListenerMsg::Subscribe(name.clone(), samples_s)).is_err() {
+            return Ok(());
+        }
+        subscriptions.push((name, samples_r));
+    }
+    println!("Streaming {} channels to PlotJuggler at udp://{}", subscriptions.len(), target);
+
+    loop {
+        let mut samples = Vec::new();
+        let mut stopped = false;
+        for (name, samples_r) in &subscriptions {
+            loop {
+                match samples_r.try_recv() {
+                    Ok((t, v)) => samples.push((t, name, v)),
+                    Err(TryRecvError::Empty) => break,
+                    // The pipeline is gone with the serial listener
+                    Err(TryRecvError::Disconnected) => {
+                        stopped = true;
+                        break;
+                    }
+                }
+            }
+        }
+        // In time order across channels, as PlotJuggler appends to each series
+        samples.sort_by(|a, b| a.0.total_cmp(&b.0));
+        for (t, name, v) in samples {
+            let msg = json!({ "timestamp": t, name.as_str(): v }).to_string();
+            // Nothing listening is not an error, PlotJuggler may be started later
+            match socket.send(msg.as_bytes()) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if stopped {
+            return Ok(());
+        }
+        thread::sleep(POLL_PERIOD);
+    }
+}
@@ -0,0 +1,183 @@
+//! Battery power and energy accounting from the `bus_voltage` and `bus_current` channels.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Chemistry {
+    LiPo,
+    LiFePO4,
+}
+
+impl Chemistry {
+    // Resting cell voltage -> state of charge, ascending in voltage
+    fn ocv_table(self) -> &'static [(f64, f64)] {
+        match self {
+            Chemistry::LiPo => &[
+                (3.27, 0.0), (3.61, 0.05), (3.69, 0.10), (3.71, 0.15), (3.73, 0.20),
+                (3.75, 0.25), (3.77, 0.30), (3.79, 0.35), (3.80, 0.40), (3.82, 0.45),
+                (3.84, 0.50), (3.85, 0.55), (3.87, 0.60), (3.91, 0.65), (3.95, 0.70),
+                (3.98, 0.75), (4.02, 0.80), (4.08, 0.85), (4.11, 0.90), (4.15, 0.95),
+                (4.20, 1.0),
+            ],
+            Chemistry::LiFePO4 => &[
+                (2.50, 0.0), (3.00, 0.09), (3.13, 0.14), (3.20, 0.17), (3.22, 0.20),
+                (3.25, 0.30), (3.26, 0.40), (3.27, 0.50), (3.28, 0.60), (3.30, 0.70),
+                (3.32, 0.80), (3.35, 0.90), (3.40, 0.99), (3.65, 1.0),
+            ],
+        }
+    }
+
+    fn soc_from_cell_voltage(self, v: f64) -> f64 {
+        let table = self.ocv_table();
+        if v <= table[0].0 {
+            return 0.0;
+        }
+        for w in table.windows(2) {
+            let ((v0, s0), (v1, s1)) = (w[0], w[1]);
+            if v <= v1 {
+                return s0 + (s1 - s0) * (v - v0) / (v1 - v0);
+            }
+        }
+        1.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatteryModel {
+    pub chemistry: Chemistry,
+    pub cells: u32,
+    pub capacity_mah: f64,
+    pub min_cell_voltage: f64,
+}
+
+impl Default for BatteryModel {
+    fn default() -> Self {
+        BatteryModel {
+            chemistry: Chemistry::LiPo,
+            cells: 6,
+            capacity_mah: 5000.0,
+            min_cell_voltage: 3.4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PowerStats {
+    pub voltage: f64,
+    pub current: f64,
+    pub power_w: f64,
+    pub energy_wh: f64,
+    pub charge_mah: f64,
+    pub soc: Option<f64>,
+    pub under_voltage: bool,
+}
+
+// Sent from the GUI to the listener thread
+pub enum PowerMonitorMsg {
+    SetModel(BatteryModel),
+    Reset,
+}
+
+pub struct PowerMonitor {
+    model: BatteryModel,
+    stats: PowerStats,
+    voltage: Option<f64>,
+    current: Option<f64>,
+    last_t: Option<f64>,
+    initial_soc: Option<f64>,
+}
+
+impl PowerMonitor {
+    pub fn new(model: BatteryModel) -> Self {
+        PowerMonitor {
+            model,
+            stats: PowerStats::default(),
+            voltage: None,
+            current: None,
+            last_t: None,
+            initial_soc: None,
+        }
+    }
+
+    pub fn handle(&mut self, msg: PowerMonitorMsg) {
+        match msg {
+            PowerMonitorMsg::SetModel(model) => {
+                // The coulomb count is relative to the initial SoC, so both restart together
+                self.model = model;
+                self.initial_soc = None;
+                self.stats.charge_mah = 0.0;
+            }
+            PowerMonitorMsg::Reset => *self = PowerMonitor::new(self.model),
+        }
+    }
+
+    pub fn stats(&self) -> &PowerStats {
+        &self.stats
+    }
+
+    /// Feed a channel sample at time `t` (seconds). Returns true if the stats were updated,
+    /// i.e. the sample was a bus voltage or current and both have been seen.
+    pub fn update(&mut self, header: &str, value: f64, t: f64) -> bool {
+        let prev_power = self.stats.power_w;
+        let prev_current = self.stats.current;
+
+        match header {
+            "bus_voltage" => self.voltage = Some(value),
+            "bus_current" => self.current = Some(value),
+            _ => return false,
+        }
+        let (Some(v), Some(i)) = (self.voltage, self.current) else {
+            return false;
+        };
+
+        // Trapezoidal integration between consecutive bus samples
+        if let Some(last_t) = self.last_t {
+            let dt_h = (t - last_t).max(0.0) / 3600.0;
+            self.stats.energy_wh += 0.5 * (prev_power + v * i) * dt_h;
+            self.stats.charge_mah += 0.5 * (prev_current + i) * dt_h * 1000.0;
+        }
+        self.last_t = Some(t);
+
+        let cell_voltage = v / self.model.cells.max(1) as f64;
+        let initial_soc = *self
+            .initial_soc
+            .get_or_insert_with(|| self.model.chemistry.soc_from_cell_voltage(cell_voltage));
+
+        self.stats.voltage = v;
+        self.stats.current = i;
+        self.stats.power_w = v * i;
+        self.stats.soc = if self.model.capacity_mah > 0.0 {
+            Some((initial_soc - self.stats.charge_mah / self.model.capacity_mah).clamp(0.0, 1.0))
+        } else {
+            None
+        };
+        self.stats.under_voltage = cell_voltage < self.model.min_cell_voltage;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_update_until_voltage_and_current_known() {
+        let mut monitor = PowerMonitor::new(BatteryModel::default());
+        assert!(!monitor.update("bus_voltage", 24.0, 0.0));
+        assert!(monitor.update("bus_current", 2.0, 0.0));
+        assert!(!monitor.update("enc_pos_0", 1.0, 0.0));
+    }
+
+    #[test]
+    fn set_model_restarts_charge_count() {
+        let mut monitor = PowerMonitor::new(BatteryModel::default());
+        monitor.update("bus_voltage", 24.0, 0.0);
+        monitor.update("bus_current", 10.0, 0.0);
+        monitor.update("bus_current", 10.0, 360.0);
+        assert!((monitor.stats().charge_mah - 1000.0).abs() < 1e-9);
+
+        monitor.handle(PowerMonitorMsg::SetModel(BatteryModel { capacity_mah: 10000.0, ..BatteryModel::default() }));
+        monitor.update("bus_voltage", 24.0, 360.0);
+        assert_eq!(monitor.stats().charge_mah, 0.0);
+        let fresh = Chemistry::LiPo.soc_from_cell_voltage(4.0);
+        assert!((monitor.stats().soc.unwrap() - fresh).abs() < 1e-9);
+    }
+}
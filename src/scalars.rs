@@ -0,0 +1,58 @@
+//! Fast path for the scalar time series, which make up most of what gets logged. Logging a
+//! `TimeSeriesScalar` serializes its label and archetype indicator on every call; here they
+//! are serialized once per entity and only the value is serialized per sample. Rows still
+//! go through the recording's batcher, which sends them in batches.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use rerun::components::Scalar;
+use rerun::log::{DataCell, DataRow, RowId};
+use rerun::{AsComponents, EntityPath, Loggable, RecordingStream, TimePoint};
+
+struct Entity {
+    path: EntityPath,
+    // Label and indicator
+    cells: Vec<DataCell>,
+}
+
+#[derive(Default)]
+pub struct ScalarLogger {
+    entities: HashMap<String, Entity>,
+}
+
+impl ScalarLogger {
+    /// Same as logging `TimeSeriesScalar::new(value).with_label(label)` to each of `recs`, at
+    /// its current time.
+    pub fn log<'a>(&mut self, recs: impl IntoIterator<Item = &'a RecordingStream>, entity_path: &str, label: &str, value: f64) {
+        if !self.entities.contains_key(entity_path) {
+            let cells = rerun::TimeSeriesScalar::new(value)
+                .with_label(label)
+                .as_component_batches()
+                .iter()
+                .filter(|batch| batch.name() != Scalar::name())
+                .filter_map(|batch| DataCell::from_component_batch(batch.as_ref()).ok())
+                .map(|mut cell| {
+                    // Cached with the cell, see below
+                    cell.compute_size_bytes();
+                    cell
+                })
+                .collect();
+            let entity = Entity { path: EntityPath::from(entity_path), cells };
+            self.entities.insert(entity_path.to_string(), entity);
+        }
+        let entity = &self.entities[entity_path];
+
+        for rec in recs {
+            // The batcher needs each row to own its cells to size them, so rows can't be shared
+            // between recordings; copies of the cached cells keep the arrow data shared and
+            // their size already computed
+            let mut cells: Vec<DataCell> = entity.cells.iter().map(|cell| DataCell { inner: Arc::new((*cell.inner).clone()) }).collect();
+            cells.push(DataCell::from_native([Scalar(value)]));
+            // The timepoint is filled in from the recording's clock
+            if let Ok(row) = DataRow::from_cells(RowId::new(), TimePoint::timeless(), entity.path.clone(), 1, cells) {
+                rec.record_row(row, true);
+            }
+        }
+    }
+}
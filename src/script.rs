@@ -0,0 +1,236 @@
+//! Automated test sequences, scripted in Rhai and run against a live session:
+//!
+//! ```text
+//! send("calib_rtn");
+//! sleep(5.0);
+//! send("velo_ctrl");
+//! marker("step");
+//! setpoint(2.0);
+//! wait_until("abs(enc_vel_0 - 2.0) < 0.1", 3.0);
+//! assert(abs(value("theta")) < 0.05, "robot fell over");
+//! send("idle_ctrl");
+//! ```
+//!
+//! Functions, besides Rhai's own:
+//!
+//! - `send(command)`: send a command as the command window would, e.g. `posn_ctrl`
+//! - `setpoint(value)`: setpoint of the active controller
+//! - `value(channel)`: latest value of a channel or derived channel
+//! - `wait_until(condition, timeout)`: wait until an expression over the channels is true,
+//!   failing after `timeout` seconds (10 s if omitted)
+//! - `sleep(seconds)`
+//! - `marker(text)`: log a marker to the `script` TextLog in rerun
+//! - `assert(condition, message)`: fail the script unless `condition` holds
+//!
+//! A script passes if it runs to the end, and fails on the first error, assertion or timeout.
+
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, Sender};
+use rhai::{Dynamic, Engine, EvalAltResult, NativeCallContext, Scope};
+
+use crate::channels;
+use crate::commands::{Command, CommandSender};
+use crate::pipeline::{LatestValues, ListenerMsg};
+
+const DEFAULT_TIMEOUT: f64 = 10.0;
+// How often waits re-check the channels and the stop flag
+const POLL_PERIOD: Duration = Duration::from_millis(10);
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+// Channels with a value so far, as variables for `wait_until`
+fn scope(latest: &LatestValues) -> Scope<'static> {
+    let mut scope = Scope::new();
+    for (name, sample) in latest.iter() {
+        if let Some((_, v)) = sample {
+            scope.push_constant(name, v);
+        }
+    }
+    scope
+}
+
+// Sleep for `seconds`, keeping the channels current
+fn wait(channels: &RefCell<LatestValues>, stop: &AtomicBool, seconds: f64, mut done: impl FnMut() -> ScriptResult<bool>) -> ScriptResult<bool> {
+    let start = Instant::now();
+    loop {
+        channels.borrow_mut().update().map_err(|e| e.to_string())?;
+        if done()? {
+            return Ok(true);
+        }
+        if stop.load(Ordering::Relaxed) {
+            return Err("stopped".into());
+        }
+        if start.elapsed().as_secs_f64() >= seconds {
+            return Ok(false);
+        }
+        thread::sleep(POLL_PERIOD);
+    }
+}
+
+fn engine(cmds_s: CommandSender, listener_msgs_s: Sender<ListenerMsg>, stop: Arc<AtomicBool>) -> Engine {
+    let mut engine = Engine::new();
+    let channels = Rc::new(RefCell::new(LatestValues::new(listener_msgs_s.clone())));
+
+    {
+        let stop = stop.clone();
+        engine.on_progress(move |_| stop.load(Ordering::Relaxed).then_some(Dynamic::UNIT));
+    }
+    engine.on_print(|s| println!("script: {}", s));
+
+    let dispatch = move |command: Command| -> ScriptResult<()> {
+        cmds_s.send(command.encode()).map_err(|_| "serial listener stopped".into())
+    };
+    {
+        let dispatch = dispatch.clone();
+        engine.register_fn("send", move |command: &str| -> ScriptResult<()> {
+            let command = Command::decode(command).ok_or_else(|| format!("unknown command '{}'", command))?;
+            dispatch(command)
+        });
+    }
+    {
+        let dispatch = dispatch.clone();
+        engine.register_fn("setpoint", move |value: f64| dispatch(Command::Setpoint(value as f32)));
+    }
+    // Rhai doesn't convert integers to floats by itself, so `setpoint(2)` needs its own overload
+    engine.register_fn("setpoint", move |value: i64| dispatch(Command::Setpoint(value as f32)));
+
+    {
+        let channels = channels.clone();
+        engine.register_fn("value", move |name: &str| -> ScriptResult<f64> {
+            let mut channels = channels.borrow_mut();
+            channels.subscribe(name).map_err(|e| e.to_string())?;
+            channels.update().map_err(|e| e.to_string())?;
+            channels.get(name).map(|(_, v)| v).ok_or_else(|| format!("no samples of '{}' yet", name).into())
+        });
+    }
+
+    {
+        let (channels, stop) = (channels.clone(), stop.clone());
+        let wait_until = move |ctx: NativeCallContext, condition: &str, timeout: f64| -> ScriptResult<()> {
+            let ast = ctx.engine().compile_expression(condition)?;
+            // Every registered channel is available; any other channel once `value` was called for it
+            for channel in channels::CHANNELS {
+                channels.borrow_mut().subscribe(channel.header).map_err(|e| e.to_string())?;
+            }
+            let met = wait(&channels, &stop, timeout, || {
+                let mut scope = scope(&channels.borrow());
+                match ctx.engine().eval_ast_with_scope::<bool>(&mut scope, &ast) {
+                    Ok(met) => Ok(met),
+                    // A channel without samples yet
+                    Err(e) if matches!(*e, EvalAltResult::ErrorVariableNotFound(..)) => Ok(false),
+                    Err(e) => Err(e),
+                }
+            })?;
+            if !met {
+                return Err(format!("timed out after {} s waiting for '{}'", timeout, condition).into());
+            }
+            Ok(())
+        };
+        let wait_until = Rc::new(wait_until);
+        let (default_timeout, int_timeout) = (wait_until.clone(), wait_until.clone());
+        engine.register_fn("wait_until", move |ctx: NativeCallContext, condition: &str| {
+            default_timeout(ctx, condition, DEFAULT_TIMEOUT)
+        });
+        engine.register_fn("wait_until", move |ctx: NativeCallContext, condition: &str, timeout: i64| {
+            int_timeout(ctx, condition, timeout as f64)
+        });
+        engine.register_fn("wait_until", move |ctx: NativeCallContext, condition: &str, timeout: f64| {
+            wait_until(ctx, condition, timeout)
+        });
+    }
+
+    {
+        let (channels, stop) = (channels.clone(), stop.clone());
+        let sleep = Rc::new(move |seconds: f64| -> ScriptResult<()> {
+            wait(&channels, &stop, seconds, || Ok(false)).map(|_| ())
+        });
+        let int_seconds = sleep.clone();
+        engine.register_fn("sleep", move |seconds: i64| int_seconds(seconds as f64));
+        engine.register_fn("sleep", move |seconds: f64| sleep(seconds));
+    }
+
+    {
+        let listener_msgs_s = listener_msgs_s.clone();
+        engine.register_fn("marker", move |text: &str| {
+            let _ = listener_msgs_s.send(ListenerMsg::Marker(text.to_string(), rerun::TextLogLevel::INFO.into()));
+        });
+    }
+
+    engine.register_fn("assert", |condition: bool, message: &str| -> ScriptResult<()> {
+        if condition {
+            Ok(())
+        } else {
+            Err(format!("assertion failed: {}", message).into())
+        }
+    });
+
+    engine
+}
+
+/// Run the script at `path` until it ends, fails or `stop` is set. Returns why it failed.
+pub fn run(path: &Path, cmds_s: CommandSender, listener_msgs_s: Sender<ListenerMsg>, stop: Arc<AtomicBool>) -> Result<(), String> {
+    let source = std::fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    let engine = engine(cmds_s, listener_msgs_s.clone(), stop);
+
+    let marker = |text: String, level: &str| {
+        println!("{}", text);
+        let _ = listener_msgs_s.send(ListenerMsg::Marker(text, level.into()));
+    };
+    marker(format!("Script {} started", path.display()), rerun::TextLogLevel::INFO);
+    match engine.run(&source) {
+        Ok(()) => {
+            marker(format!("Script {} passed", path.display()), rerun::TextLogLevel::INFO);
+            Ok(())
+        }
+        Err(e) => {
+            let reason = match *e {
+                EvalAltResult::ErrorTerminated(..) => "stopped".to_string(),
+                e => e.to_string(),
+            };
+            marker(format!("Script {} failed: {}", path.display(), reason), rerun::TextLogLevel::ERROR);
+            Err(reason)
+        }
+    }
+}
+
+/// A script running on its own thread. Clones share the stop flag and the result.
+#[derive(Clone)]
+pub struct ScriptRun {
+    stop: Arc<AtomicBool>,
+    result_r: Receiver<Result<(), String>>,
+}
+
+impl ScriptRun {
+    pub fn start(path: PathBuf, cmds_s: CommandSender, listener_msgs_s: Sender<ListenerMsg>) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (result_s, result_r) = crossbeam_channel::bounded(1);
+        {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let _ = result_s.send(run(&path, cmds_s, listener_msgs_s, stop));
+            });
+        }
+        ScriptRun { stop, result_r }
+    }
+
+    /// Abort the script at its next statement or wait.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    /// The outcome, once the script has ended.
+    pub fn result(&self) -> Option<Result<(), String>> {
+        self.result_r.try_recv().ok()
+    }
+
+    pub fn wait(self) -> Result<(), String> {
+        self.result_r.recv().unwrap_or_else(|_| Err("script thread panicked".to_string()))
+    }
+}
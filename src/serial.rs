@@ -0,0 +1,342 @@
+//! Serial port I/O on dedicated threads, so that a slow rerun log can't stall reading and
+//! commands go out as soon as they're dispatched, whatever the telemetry volume.
+//!
+//! The reader hands timestamped chunks to the processing thread over a bounded queue. If
+//! processing falls behind and the queue fills up, the overflow policy decides which data
+//! is lost. Chunks are numbered so the processing thread can tell when some went missing
+//! in between, and resync its framer rather than join the lines on either side.
+//!
+//! A port that fails, e.g. when the USB cable is pulled, is reopened by name until it comes
+//! back, so a long-running session survives the device resetting.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+
+pub const READ_QUEUE_CAPACITY: usize = 1024;
+const READ_SIZE: usize = 256;
+const REOPEN_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued data to make room, keeping the view current
+    DropOldest,
+    /// Drop newly read data until the queue drains
+    DropNewest,
+    /// Stop reading until there's room; the OS buffer and then the device may drop data instead
+    Block,
+}
+
+impl OverflowPolicy {
+    /// Queue `item` on the bounded queue `s`, whose receiving end `r` is used to drop the
+    /// oldest item; `dropped` is called with every item lost. Returns false once the queue
+    /// is disconnected, or `stop` is set while blocking.
+    pub fn enqueue<T>(self, mut item: T, s: &Sender<T>, r: &Receiver<T>, stop: &AtomicBool, dropped: impl Fn(&T)) -> bool {
+        match self {
+            OverflowPolicy::Block => {
+                // Wake up now and then to notice a stop
+                while let Err(e) = s.send_timeout(item, Duration::from_millis(100)) {
+                    if stop.load(Ordering::Relaxed) || e.is_disconnected() {
+                        return false;
+                    }
+                    item = e.into_inner();
+                }
+            }
+            OverflowPolicy::DropNewest => match s.try_send(item) {
+                Ok(()) => {}
+                Err(TrySendError::Full(item)) => dropped(&item),
+                Err(TrySendError::Disconnected(_)) => return false,
+            },
+            OverflowPolicy::DropOldest => loop {
+                match s.try_send(item) {
+                    Ok(()) => break,
+                    Err(TrySendError::Full(rejected)) => {
+                        if let Ok(oldest) = r.try_recv() {
+                            dropped(&oldest);
+                        }
+                        item = rejected;
+                    }
+                    Err(TrySendError::Disconnected(_)) => return false,
+                }
+            },
+        }
+        true
+    }
+}
+
+/// Bytes from one read of the port
+pub struct Chunk {
+    /// Seconds since the link was started
+    pub t: f64,
+    /// Counts up by one per read, so a jump means chunks were dropped in between
+    pub seq: u64,
+    pub bytes: Vec<u8>,
+}
+
+impl Chunk {
+    /// Whether chunks were dropped before this one; `next_seq` holds the sequence number
+    /// expected next, starting at 0.
+    pub fn after_gap(&self, next_seq: &mut u64) -> bool {
+        let gap = self.seq != *next_seq;
+        *next_seq = self.seq + 1;
+        gap
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QueueStats {
+    pub read_depth: usize,
+    pub read_peak: usize,
+    pub read_capacity: usize,
+    pub dropped_chunks: u64,
+    pub dropped_bytes: u64,
+    pub command_depth: usize,
+    /// Bytes read from the port, including dropped ones
+    pub read_bytes: u64,
+    /// Commands that failed to be written to the port
+    pub dropped_commands: u64,
+    /// Times the port was reopened after failing
+    pub reconnects: u64,
+}
+
+// Shared with the port threads
+#[derive(Default)]
+struct Counters {
+    read_bytes: AtomicU64,
+    dropped_chunks: AtomicU64,
+    dropped_bytes: AtomicU64,
+    dropped_commands: AtomicU64,
+    reconnects: AtomicU64,
+}
+
+pub struct SerialLink {
+    pub chunks_r: Receiver<Chunk>,
+    /// Commands once written to the port, with the time they went out
+    pub sent_r: Receiver<(f64, String)>,
+    cmds_r: Receiver<String>,
+    start_time: Instant,
+    counters: Arc<Counters>,
+    read_peak: usize,
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl SerialLink {
+    /// Start reading from `port` and writing the commands received on `cmds_r` to it.
+    pub fn start(port: Box<dyn serialport::SerialPort>, policy: OverflowPolicy, cmds_r: Receiver<String>) -> io::Result<Self> {
+        let writer_port = port.try_clone()?;
+        let start_time = Instant::now();
+        let stop = Arc::new(AtomicBool::new(false));
+        let counters = Arc::new(Counters::default());
+        let (chunks_s, chunks_r) = crossbeam_channel::bounded(READ_QUEUE_CAPACITY);
+        let (sent_s, sent_r) = crossbeam_channel::unbounded();
+        // The reader hands the writer its half of a reopened port
+        let (reopened_s, reopened_r) = crossbeam_channel::unbounded();
+
+        let reader = {
+            let (chunks_r, stop, counters) = (chunks_r.clone(), stop.clone(), counters.clone());
+            thread::spawn(move || read_port(port, start_time, policy, chunks_s, chunks_r, reopened_s, counters, stop))
+        };
+        let writer = {
+            let (cmds_r, stop, counters) = (cmds_r.clone(), stop.clone(), counters.clone());
+            thread::spawn(move || write_port(writer_port, start_time, cmds_r, reopened_r, sent_s, counters, stop))
+        };
+
+        Ok(SerialLink {
+            chunks_r,
+            sent_r,
+            cmds_r,
+            start_time,
+            counters,
+            read_peak: 0,
+            stop,
+            threads: vec![reader, writer],
+        })
+    }
+
+    pub fn elapsed(&self) -> f64 {
+        self.start_time.elapsed().as_secs_f64()
+    }
+
+    pub fn stats(&mut self) -> QueueStats {
+        let read_depth = self.chunks_r.len();
+        self.read_peak = self.read_peak.max(read_depth);
+        QueueStats {
+            read_depth,
+            read_peak: self.read_peak,
+            read_capacity: READ_QUEUE_CAPACITY,
+            dropped_chunks: self.counters.dropped_chunks.load(Ordering::Relaxed),
+            dropped_bytes: self.counters.dropped_bytes.load(Ordering::Relaxed),
+            command_depth: self.cmds_r.len(),
+            read_bytes: self.counters.read_bytes.load(Ordering::Relaxed),
+            dropped_commands: self.counters.dropped_commands.load(Ordering::Relaxed),
+            reconnects: self.counters.reconnects.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Open the port described by `builder` again, retrying until it succeeds or `stop` is set.
+// Returns the reader's and the writer's halves.
+fn reopen(builder: &serialport::SerialPortBuilder, stop: &AtomicBool) -> Option<(Box<dyn serialport::SerialPort>, Box<dyn serialport::SerialPort>)> {
+    while !stop.load(Ordering::Relaxed) {
+        thread::sleep(REOPEN_DELAY);
+        let Ok(port) = builder.clone().open() else {
+            continue;
+        };
+        if let Ok(writer_port) = port.try_clone() {
+            return Some((port, writer_port));
+        }
+    }
+    None
+}
+
+#[allow(clippy::too_many_arguments)]
+fn read_port(
+    mut port: Box<dyn serialport::SerialPort>,
+    start_time: Instant,
+    policy: OverflowPolicy,
+    chunks_s: Sender<Chunk>,
+    chunks_r: Receiver<Chunk>,
+    reopened_s: Sender<Box<dyn serialport::SerialPort>>,
+    counters: Arc<Counters>,
+    stop: Arc<AtomicBool>,
+) {
+    // Ports without a name can't be reopened
+    let builder = match (port.name(), port.baud_rate()) {
+        (Some(name), Ok(baud)) => Some(serialport::new(name, baud).timeout(port.timeout())),
+        _ => None,
+    };
+    let mut read_buf = [0u8; READ_SIZE];
+    let mut seq = 0;
+    while !stop.load(Ordering::Relaxed) {
+        let n = match port.read(&mut read_buf) {
+            Ok(0) => continue,
+            Ok(n) => n,
+            Err(e) if matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::Interrupted) => continue,
+            Err(e) => {
+                println!("Serial read failed: {}", e);
+                let Some((reopened, writer_port)) = builder.as_ref().and_then(|builder| reopen(builder, &stop)) else {
+                    return;
+                };
+                println!("Serial port reopened");
+                counters.reconnects.fetch_add(1, Ordering::Relaxed);
+                (port, seq) = (reopened, seq + 1);
+                let _ = reopened_s.send(writer_port);
+                continue;
+            }
+        };
+        counters.read_bytes.fetch_add(n as u64, Ordering::Relaxed);
+        let chunk = Chunk { t: start_time.elapsed().as_secs_f64(), seq, bytes: read_buf[..n].to_vec() };
+        seq += 1;
+
+        let count_drop = |chunk: &Chunk| {
+            counters.dropped_chunks.fetch_add(1, Ordering::Relaxed);
+            counters.dropped_bytes.fetch_add(chunk.bytes.len() as u64, Ordering::Relaxed);
+        };
+        if !policy.enqueue(chunk, &chunks_s, &chunks_r, &stop, count_drop) {
+            return;
+        }
+    }
+}
+
+fn write_port(
+    mut port: Box<dyn serialport::SerialPort>,
+    start_time: Instant,
+    cmds_r: Receiver<String>,
+    reopened_r: Receiver<Box<dyn serialport::SerialPort>>,
+    sent_s: Sender<(f64, String)>,
+    counters: Arc<Counters>,
+    stop: Arc<AtomicBool>,
+) {
+    while !stop.load(Ordering::Relaxed) {
+        let command = match cmds_r.recv_timeout(Duration::from_millis(100)) {
+            Ok(command) => command,
+            Err(e) if e.is_timeout() => continue,
+            Err(_) => return,
+        };
+        if let Some(reopened) = reopened_r.try_iter().last() {
+            port = reopened;
+        }
+        if let Err(e) = port.write_all(command.as_bytes()) {
+            println!("Failed to write to serial port: {}", e);
+            counters.dropped_commands.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        let _ = sent_s.send((start_time.elapsed().as_secs_f64(), command));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    // Queue 0..5 on a queue of 2, returning the results, the dropped and the queued items
+    fn overflow(policy: OverflowPolicy, stop: &AtomicBool) -> (Vec<bool>, Vec<u32>, Vec<u32>) {
+        let (s, r) = crossbeam_channel::bounded(2);
+        let dropped = RefCell::new(Vec::new());
+        let results = (0..5).map(|i| policy.enqueue(i, &s, &r, stop, |&item| dropped.borrow_mut().push(item))).collect();
+        (results, dropped.into_inner(), r.try_iter().collect())
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_latest() {
+        let (results, dropped, queued) = overflow(OverflowPolicy::DropOldest, &AtomicBool::new(false));
+        assert_eq!(results, [true; 5]);
+        assert_eq!(dropped, [0, 1, 2]);
+        assert_eq!(queued, [3, 4]);
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_earliest() {
+        let (results, dropped, queued) = overflow(OverflowPolicy::DropNewest, &AtomicBool::new(false));
+        assert_eq!(results, [true; 5]);
+        assert_eq!(dropped, [2, 3, 4]);
+        assert_eq!(queued, [0, 1]);
+    }
+
+    #[test]
+    fn block_gives_up_once_stopped() {
+        let (results, dropped, queued) = overflow(OverflowPolicy::Block, &AtomicBool::new(true));
+        // Nothing is dropped, the reader just stops once the queue is full
+        assert_eq!(results, [true, true, false, false, false]);
+        assert!(dropped.is_empty());
+        assert_eq!(queued, [0, 1]);
+    }
+
+    #[test]
+    fn disconnected_queue_stops_every_policy() {
+        for policy in [OverflowPolicy::DropOldest, OverflowPolicy::DropNewest, OverflowPolicy::Block] {
+            let (s, r) = crossbeam_channel::bounded(2);
+            let (_, closed) = crossbeam_channel::bounded(2);
+            drop(r);
+            assert!(!policy.enqueue(0, &s, &closed, &AtomicBool::new(false), |_| {}));
+        }
+    }
+
+    #[test]
+    fn gaps_follow_sequence_jumps() {
+        let chunk = |seq| Chunk { t: 0.0, seq, bytes: Vec::new() };
+        let mut next_seq = 0;
+        assert!(!chunk(0).after_gap(&mut next_seq));
+        assert!(!chunk(1).after_gap(&mut next_seq));
+        // Chunks 2 and 3 were dropped
+        assert!(chunk(4).after_gap(&mut next_seq));
+        assert!(!chunk(5).after_gap(&mut next_seq));
+        // A reconnect skips a sequence number, as the line in progress was cut off
+        assert!(chunk(7).after_gap(&mut next_seq));
+        assert!(!chunk(8).after_gap(&mut next_seq));
+    }
+}
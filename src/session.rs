@@ -0,0 +1,131 @@
+//! A live session: reads the serial port, frames the incoming bytes into lines and feeds
+//! them through a [`Pipeline`], and writes dispatched commands to the port, until a
+//! [`ListenerMsg::Shutdown`] arrives.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crossbeam_channel::Receiver;
+
+use crate::capture::CaptureWriter;
+use crate::framing::{Frame, Framer, DEFAULT_MAX_LINE_LEN};
+use crate::pipeline::{ListenerMsg, Pipeline};
+use crate::serial::{OverflowPolicy, SerialLink};
+
+// Seconds between queue depth updates
+const QUEUE_STATS_PERIOD: f64 = 0.5;
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    pub delimiter: Vec<u8>,
+    /// Longer lines are discarded up to the next delimiter
+    pub max_line_len: usize,
+    pub overflow: OverflowPolicy,
+    /// Record every received line and sent command to this file, see [`crate::capture`]
+    pub capture: Option<PathBuf>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            delimiter: b"\n".to_vec(),
+            max_line_len: DEFAULT_MAX_LINE_LEN,
+            overflow: OverflowPolicy::DropOldest,
+            capture: None,
+        }
+    }
+}
+
+/// Open the serial port `port_name`, or the first one found if `None`.
+pub fn open_port(port_name: Option<&str>, baud: u32) -> Result<Box<dyn serialport::SerialPort>, Box<dyn std::error::Error>> {
+    let port_name = match port_name {
+        Some(port_name) => port_name.to_string(),
+        None => {
+            println!("Serial port:");
+            let ports = serialport::available_ports()?;
+            for p in &ports {
+                println!("{}", p.port_name);
+            }
+            ports.first().ok_or("No ports found!")?.port_name.clone()
+        }
+    };
+    Ok(serialport::new(port_name, baud).timeout(Duration::from_millis(10)).open()?)
+}
+
+/// Run a session on `port` until a shutdown is received on `listener_msgs_r`, or the port
+/// fails for good, see serial.rs. Commands received on `cmds_r` are written to the port as they arrive.
+pub fn run(
+    port: Box<dyn serialport::SerialPort>,
+    config: &SessionConfig,
+    mut pipeline: Pipeline,
+    cmds_r: Receiver<String>,
+    listener_msgs_r: Receiver<ListenerMsg>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Chunks may contain less or more than one whole msg, see framing.rs
+    let mut framer = Framer::new(config.delimiter.clone(), config.max_line_len);
+
+    let mut capture = match &config.capture {
+        Some(path) => Some(CaptureWriter::create(path)?),
+        None => None,
+    };
+
+    // Reading and writing the port happen on their own threads, see serial.rs
+    let mut link = SerialLink::start(port, config.overflow, cmds_r)?;
+    let mut stats_t = 0.0;
+    let mut next_seq = 0;
+
+    loop {
+        crossbeam_channel::select! {
+            recv(link.chunks_r) -> chunk => {
+                let Ok(chunk) = chunk else {
+                    break;
+                };
+                // Process all complete messages, partial ones stay buffered until the next chunk.
+                // Messages from the same chunk share a timestamp.
+                let t = chunk.t;
+                if chunk.after_gap(&mut next_seq) {
+                    framer.resync();
+                }
+                framer.push(&chunk.bytes);
+                while let Some(frame) = framer.next_frame() {
+                    match frame {
+                        Frame::Line(message) => {
+                            if let Some(capture) = &mut capture {
+                                let _ = capture.line(t, &String::from_utf8_lossy(message));
+                            }
+                            pipeline.line(t, message);
+                        }
+                        Frame::Overflow(start) => pipeline.overflow(t, start, config.max_line_len),
+                    }
+                }
+            },
+            // Dispatched commands, once they were sent
+            recv(link.sent_r) -> sent => {
+                if let Ok((t, command)) = sent {
+                    if let Some(capture) = &mut capture {
+                        let _ = capture.command(t, &command);
+                    }
+                    pipeline.command(t, &command);
+                }
+            },
+            // Settings changes, e.g. from the egui thread
+            recv(listener_msgs_r) -> msg => match msg {
+                Ok(ListenerMsg::Shutdown) | Err(_) => break,
+                Ok(msg) => pipeline.handle(msg),
+            },
+            default(Duration::from_millis(100)) => {},
+        }
+
+        let t = link.elapsed();
+        if t - stats_t >= QUEUE_STATS_PERIOD {
+            stats_t = t;
+            pipeline.queue_stats(t, link.stats());
+        }
+    }
+
+    link.stop();
+    if let Some(capture) = &mut capture {
+        capture.flush()?;
+    }
+    pipeline.finish()
+}
@@ -0,0 +1,252 @@
+//! WebSocket streaming of parsed channel samples, e.g. for a browser dashboard. Clients
+//! subscribe to channels and may send commands, with JSON text messages:
+//!
+//! ```text
+//! {"subscribe": ["theta", "bus_voltage"], "rate": 20}   at most 20 samples/s per channel, all if omitted
+//! {"unsubscribe": ["theta"]}
+//! {"format": "msgpack"}                                 samples as binary MessagePack, or "json"
+//! {"command": "velo_ctrl"}
+//! ```
+//!
+//! Samples are sent in batches, `{"samples": [{"channel": "theta", "t": 1.25, "value": 0.01}]}`.
+//! Replies to commands are `{"sent": "velo_ctrl"}`, and `{"error": "..."}` for anything rejected.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::{Receiver, Sender};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tungstenite::{Message, WebSocket};
+
+use crate::commands::{Command, CommandSender};
+use crate::pipeline::ListenerMsg;
+
+// Upper bound between batches of samples
+const POLL_PERIOD: Duration = Duration::from_millis(20);
+// Samples buffered per channel between batches
+const SUBSCRIPTION_CAPACITY: usize = 1024;
+
+// The error is large, box it rather than copying it up the stack
+type WsResult<T> = Result<T, Box<tungstenite::Error>>;
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    Json,
+    Msgpack,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClientMsg {
+    subscribe: Option<Vec<String>>,
+    rate: Option<f64>,
+    unsubscribe: Option<Vec<String>>,
+    format: Option<Format>,
+    command: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Sample<'a> {
+    channel: &'a str,
+    t: f64,
+    value: f64,
+}
+
+#[derive(Serialize)]
+struct Batch<'a> {
+    samples: Vec<Sample<'a>>,
+}
+
+struct Subscription {
+    samples_r: Receiver<(f64, f64)>,
+    // Seconds between samples sent, for decimation
+    period: f64,
+    sent_t: f64,
+}
+
+struct Client {
+    socket: WebSocket<TcpStream>,
+    cmds_s: CommandSender,
+    listener_msgs_s: Sender<ListenerMsg>,
+    subscriptions: HashMap<String, Subscription>,
+    format: Format,
+}
+
+impl Client {
+    fn handle(&mut self, text: &str) -> WsResult<()> {
+        let msg: ClientMsg = match serde_json::from_str(text) {
+            Ok(msg) => msg,
+            Err(e) => return self.reply(json!({ "error": e.to_string() })),
+        };
+        if let Some(format) = msg.format {
+            self.format = format;
+        }
+        for channel in msg.subscribe.unwrap_or_default() {
+            let (samples_s, samples_r) = crossbeam_channel::bounded(SUBSCRIPTION_CAPACITY);
+            if self.listener_msgs_s.send(ListenerMsg::Subscribe(channel.clone(), samples_s)).is_err() {
+                return self.reply(json!({ "error": "serial listener stopped" }));
+            }
+            let period = msg.rate.filter(|rate| *rate > 0.0).map_or(0.0, |rate| 1.0 / rate);
+            self.subscriptions.insert(channel, Subscription { samples_r, period, sent_t: f64::NEG_INFINITY });
+        }
+        // The pipeline drops a subscription once its receiver is gone
+        for channel in msg.unsubscribe.unwrap_or_default() {
+            self.subscriptions.remove(&channel);
+        }
+        if let Some(command) = msg.command {
+            let reply = match Command::decode(&command) {
+                Some(command) => match self.cmds_s.send(command.encode()) {
+                    Ok(()) => json!({ "sent": command.encode() }),
+                    Err(_) => json!({ "error": "serial listener stopped" }),
+                },
+                None => json!({ "error": format!("unknown command '{}'", command) }),
+            };
+            self.reply(reply)?;
+        }
+        Ok(())
+    }
+
+    fn reply(&mut self, reply: serde_json::Value) -> WsResult<()> {
+        Ok(self.socket.send(Message::text(reply.to_string()))?)
+    }
+
+    fn send_samples(&mut self) -> WsResult<()> {
+        let mut samples = Vec::new();
+        for (channel, subscription) in &mut self.subscriptions {
+            for (t, value) in subscription.samples_r.try_iter() {
+                if t - subscription.sent_t >= subscription.period {
+                    subscription.sent_t = t;
+                    samples.push(Sample { channel, t, value });
+                }
+            }
+        }
+        if samples.is_empty() {
+            return Ok(());
+        }
+        let batch = Batch { samples };
+        let msg = match self.format {
+            Format::Json => Message::text(serde_json::to_string(&batch).unwrap_or_default()),
+            Format::Msgpack => Message::binary(rmp_serde::to_vec_named(&batch).unwrap_or_default()),
+        };
+        Ok(self.socket.send(msg)?)
+    }
+
+    fn run(&mut self) -> WsResult<()> {
+        loop {
+            match self.socket.read() {
+                Ok(Message::Text(text)) => self.handle(&text)?,
+                Ok(_) => {}
+                // No message within the read timeout
+                Err(tungstenite::Error::Io(e)) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+                Err(e) => return Err(e.into()),
+            }
+            self.send_samples()?;
+        }
+    }
+}
+
+fn serve_client(stream: TcpStream, cmds_s: CommandSender, listener_msgs_s: Sender<ListenerMsg>) {
+    let peer = stream.peer_addr().map_or("?".to_string(), |addr| addr.to_string());
+    let socket = match tungstenite::accept(stream) {
+        Ok(socket) => socket,
+        Err(e) => {
+            println!("WebSocket handshake with {} failed: {}", peer, e);
+            return;
+        }
+    };
+    // Reads time out so samples keep flowing while the client is quiet
+    if let Err(e) = socket.get_ref().set_read_timeout(Some(POLL_PERIOD)) {
+        println!("WebSocket client {}: {}", peer, e);
+        return;
+    }
+    println!("WebSocket client {} connected", peer);
+
+    let mut client = Client { socket, cmds_s, listener_msgs_s, subscriptions: HashMap::new(), format: Format::Json };
+    if let Err(e) = client.run() {
+        match *e {
+            tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => println!("WebSocket client {} disconnected", peer),
+            e => println!("WebSocket client {} dropped: {}", peer, e),
+        }
+    }
+}
+
+/// Accept WebSocket clients on `addr`, each served on its own thread.
+pub fn serve(addr: SocketAddr, cmds_s: CommandSender, listener_msgs_s: Sender<ListenerMsg>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    println!("WebSocket server listening on ws://{}", addr);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let (cmds_s, listener_msgs_s) = (cmds_s.clone(), listener_msgs_s.clone());
+        thread::spawn(move || serve_client(stream, cmds_s, listener_msgs_s));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    fn read_json(socket: &mut WebSocket<TcpStream>) -> Value {
+        match socket.read().unwrap() {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            msg => panic!("expected a text message, got {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn subscribed_channel_is_decimated_and_switches_format() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (commands_s, commands_r) = crossbeam_channel::unbounded();
+        let (listener_msgs_s, listener_msgs_r) = crossbeam_channel::unbounded();
+        thread::spawn(move || serve_client(listener.accept().unwrap().0, CommandSender::new(commands_s), listener_msgs_s));
+
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (mut socket, _) = tungstenite::client(format!("ws://{}", addr), stream).unwrap();
+
+        socket.send(Message::text(r#"{"command": "velo_ctrl"}"#)).unwrap();
+        assert_eq!(read_json(&mut socket), json!({ "sent": "velo_ctrl" }));
+        assert_eq!(commands_r.recv_timeout(Duration::from_secs(5)).unwrap(), "velo_ctrl");
+        socket.send(Message::text(r#"{"command": "self_destruct"}"#)).unwrap();
+        assert_eq!(read_json(&mut socket), json!({ "error": "unknown command 'self_destruct'" }));
+        socket.send(Message::text(r#"{"subscribe": ["theta"], "frequency": 2}"#)).unwrap();
+        assert!(read_json(&mut socket)["error"].as_str().unwrap().contains("unknown field"));
+
+        socket.send(Message::text(r#"{"subscribe": ["theta"], "rate": 2}"#)).unwrap();
+        // Stand in for the pipeline
+        let Ok(ListenerMsg::Subscribe(name, samples_s)) = listener_msgs_r.recv_timeout(Duration::from_secs(5)) else {
+            panic!("expected a subscription");
+        };
+        assert_eq!(name, "theta");
+        for i in 0..5 {
+            samples_s.send((i as f64 * 0.25, i as f64)).unwrap();
+        }
+        // At most 2 samples/s, however they were batched
+        let mut sent = Vec::new();
+        while sent.len() < 3 {
+            for sample in read_json(&mut socket)["samples"].as_array().unwrap() {
+                assert_eq!(sample["channel"], "theta");
+                sent.push((sample["t"].as_f64().unwrap(), sample["value"].as_f64().unwrap()));
+            }
+        }
+        assert_eq!(sent, [(0.0, 0.0), (0.5, 2.0), (1.0, 4.0)]);
+
+        // The reply to the command comes once the format has switched
+        socket.send(Message::text(r#"{"format": "msgpack", "command": "idle_ctrl"}"#)).unwrap();
+        assert_eq!(read_json(&mut socket), json!({ "sent": "idle_ctrl" }));
+        samples_s.send((1.5, 6.0)).unwrap();
+        let Message::Binary(data) = socket.read().unwrap() else {
+            panic!("expected a binary message");
+        };
+        let batch: Value = rmp_serde::from_slice(&data).unwrap();
+        assert_eq!(batch, json!({ "samples": [{ "channel": "theta", "t": 1.5, "value": 6.0 }] }));
+    }
+}